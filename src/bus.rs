@@ -0,0 +1,535 @@
+use std::fs::File;
+use std::future::Future;
+use std::io::{LineWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+
+use can_socket::tokio::CanSocket;
+use can_socket::{CanFrame, CanId};
+
+use crate::config::BusConfig;
+use crate::rng::SplitMix64;
+
+/// Future returned by the methods of a [`CanTransport`].
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// Sends and receives the frames of a CAN bus: a SocketCAN socket, or a [`MockBus`] in tests.
+pub trait CanTransport: Send + Sync {
+    /// Send a frame on the bus.
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()>;
+
+    /// Wait for the next frame on the bus.
+    fn recv(&self) -> TransportFuture<'_, CanFrame>;
+
+    /// Open the transport again after its interface went down, transports that can not reconnect return an error.
+    fn reconnect(&self) -> TransportFuture<'_, ()> {
+        Box::pin(std::future::ready(Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "transport can not reconnect"))))
+    }
+}
+
+/// Linux error codes of a socket on a CAN interface that was removed or set down.
+const ENODEV: i32 = 19;
+const ENETDOWN: i32 = 100;
+
+/// First delay before binding the socket again after the interface went down, doubled after every failed attempt.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Longest delay between two attempts to bind the socket again.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Number of attempts to bind the socket again before the bus is given up.
+const RECONNECT_ATTEMPTS: u32 = 10;
+
+/// Whether a receive error means the interface is gone, other errors are transient.
+pub fn is_interface_down(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(ENODEV | ENETDOWN))
+}
+
+impl CanTransport for CanSocket {
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+        Box::pin(CanSocket::send(self, frame))
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        Box::pin(CanSocket::recv(self))
+    }
+}
+
+/// SocketCAN socket that binds to its interface again on a reconnect.
+struct BoundSocket {
+    interface: String,
+    socket: RwLock<Arc<CanSocket>>,
+}
+
+impl BoundSocket {
+    fn bind(interface: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            interface: interface.to_string(),
+            socket: RwLock::new(Arc::new(CanSocket::bind(interface)?)),
+        })
+    }
+
+    fn socket(&self) -> Arc<CanSocket> {
+        Arc::clone(&self.socket.read().unwrap())
+    }
+}
+
+impl CanTransport for BoundSocket {
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+        let socket = self.socket();
+        Box::pin(async move { socket.send(frame).await })
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        let socket = self.socket();
+        Box::pin(async move { socket.recv().await })
+    }
+
+    fn reconnect(&self) -> TransportFuture<'_, ()> {
+        Box::pin(async move {
+            let socket = CanSocket::bind(&self.interface)?;
+            *self.socket.write().unwrap() = Arc::new(socket);
+            Ok(())
+        })
+    }
+}
+
+/// Transport that is not connected to a bus, it never receives and drops the sent frames.
+struct Detached;
+
+impl CanTransport for Detached {
+    fn send<'a>(&'a self, _frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        Box::pin(std::future::pending())
+    }
+}
+
+/// In-memory CAN bus: frames pushed into the bus are received by the nodes and the frames sent by the nodes can be read out.
+/// Clones share the same bus, keep one to drive the nodes and give the other to [`DigitalTwinBuilder::transport`](crate::DigitalTwinBuilder::transport).
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+/// use can_socket::{CanFrame, CanId};
+/// use fake_motorcontroller::{DigitalTwin, MockBus};
+///
+/// let bus = MockBus::new();
+/// let mut twin = DigitalTwin::builder()
+///     .add_node(1, "CPB3-1-2.eds")
+///     .transport("vcan0", bus.clone())
+///     .build()
+///     .expect("invalid configuration");
+/// twin.start().await.expect("failed to start the nodes");
+///
+/// // SDO upload of the device type (0x1000)
+/// let request = CanFrame::new(CanId::new_base(0x601).unwrap(), &[0x40, 0x00, 0x10, 0x00, 0, 0, 0, 0], None).unwrap();
+/// bus.push(request);
+/// let response = loop {
+///     let frame = bus.next_sent().await;
+///     if frame.id().as_u32() == 0x581 {
+///         break frame;
+///     }
+/// };
+/// assert_eq!(&response.data()[..4], &[0x43, 0x00, 0x10, 0x00]);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MockBus {
+    inner: Arc<MockBusInner>,
+}
+
+struct MockBusInner {
+    to_nodes: mpsc::UnboundedSender<CanFrame>,
+    received: AsyncMutex<mpsc::UnboundedReceiver<CanFrame>>,
+    from_nodes: mpsc::UnboundedSender<CanFrame>,
+    sent: AsyncMutex<mpsc::UnboundedReceiver<CanFrame>>,
+}
+
+impl MockBus {
+    pub fn new() -> Self {
+        let (to_nodes, received) = mpsc::unbounded_channel();
+        let (from_nodes, sent) = mpsc::unbounded_channel();
+        Self {
+            inner: Arc::new(MockBusInner {
+                to_nodes,
+                received: AsyncMutex::new(received),
+                from_nodes,
+                sent: AsyncMutex::new(sent),
+            }),
+        }
+    }
+
+    /// Put a frame on the bus for the nodes to receive.
+    pub fn push(&self, frame: CanFrame) {
+        self.inner.to_nodes.send(frame).ok();
+    }
+
+    /// Wait for the next frame sent by a node.
+    pub async fn next_sent(&self) -> CanFrame {
+        let mut sent = self.inner.sent.lock().await;
+        match sent.recv().await {
+            Some(frame) => frame,
+            // The bus holds a sender itself, the channel is never closed
+            None => std::future::pending().await,
+        }
+    }
+
+    /// The next frame sent by a node, if any was sent and not read yet.
+    pub fn try_next_sent(&self) -> Option<CanFrame> {
+        self.inner.sent.try_lock().ok()?.try_recv().ok()
+    }
+}
+
+impl Default for MockBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CanTransport for MockBus {
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+        self.inner.from_nodes.send(*frame).ok();
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        Box::pin(async move {
+            let mut received = self.inner.received.lock().await;
+            match received.recv().await {
+                Some(frame) => Ok(frame),
+                None => std::future::pending().await,
+            }
+        })
+    }
+}
+
+/// Probability that a sent frame is held back and sent after the next frame when reordering is enabled.
+const REORDER_PROBABILITY: f64 = 0.05;
+
+/// Longest time a held back frame waits for the next frame.
+const REORDER_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Transport that delays, drops and reorders the frames sent on another transport, to test the timeouts of a master.
+pub struct FaultyTransport {
+    inner: Arc<dyn CanTransport>,
+    queue: mpsc::UnboundedSender<(CanFrame, Instant, bool)>,
+    rng: Mutex<SplitMix64>,
+    tx_latency: Duration,
+    drop_probability: f64,
+    reorder: bool,
+}
+
+impl FaultyTransport {
+    /// Wrap the transport with the faults of the bus configuration, the frames are sent by a spawned task.
+    pub fn new(inner: Arc<dyn CanTransport>, config: &BusConfig) -> Self {
+
+        let (queue, frames) = mpsc::unbounded_channel();
+        tokio::spawn(forward_frames(Arc::clone(&inner), frames));
+
+        Self {
+            inner,
+            queue,
+            rng: Mutex::new(SplitMix64(config.seed)),
+            tx_latency: Duration::from_millis(config.tx_latency_ms),
+            drop_probability: config.drop_probability,
+            reorder: config.reorder,
+        }
+    }
+}
+
+impl CanTransport for FaultyTransport {
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+
+        if let Ok(mut rng) = self.rng.lock() {
+            if rng.next_f64() < self.drop_probability {
+                log::debug!("Frame {} dropped", format_frame(frame));
+            } else {
+                let due = Instant::now() + self.tx_latency.mul_f64(rng.next_f64());
+                let hold = self.reorder && rng.next_f64() < REORDER_PROBABILITY;
+                self.queue.send((*frame, due, hold)).ok();
+            }
+        }
+
+        Box::pin(std::future::ready(Ok(())))
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        self.inner.recv()
+    }
+
+    fn reconnect(&self) -> TransportFuture<'_, ()> {
+        self.inner.reconnect()
+    }
+}
+
+/// Send the queued frames at their due time, a held back frame is sent after the next frame.
+async fn forward_frames(transport: Arc<dyn CanTransport>, mut frames: mpsc::UnboundedReceiver<(CanFrame, Instant, bool)>) {
+
+    let mut held: Option<CanFrame> = None;
+
+    loop {
+        let next = match held {
+            Some(_) => tokio::time::timeout(REORDER_TIMEOUT, frames.recv()).await.unwrap_or(None),
+            None => frames.recv().await,
+        };
+
+        match next {
+            Some((frame, due, hold)) if hold && held.is_none() => {
+                tokio::time::sleep_until(due.into()).await;
+                held = Some(frame);
+                continue;
+            }
+            Some((frame, due, _)) => {
+                tokio::time::sleep_until(due.into()).await;
+                if let Err(e) = transport.send(&frame).await {
+                    log::error!("Failed to send frame {}: {e}", format_frame(&frame));
+                }
+            }
+            // The transport was dropped and no frame is held back
+            None if held.is_none() => return,
+            None => {}
+        }
+
+        if let Some(frame) = held.take() {
+            if let Err(e) = transport.send(&frame).await {
+                log::error!("Failed to send frame {}: {e}", format_frame(&frame));
+            }
+        }
+    }
+}
+
+/// Transport of a CAN interface, the sent and received frames are written to the trace when configured.
+pub struct BusSocket {
+    transport: Arc<dyn CanTransport>,
+    interface: String,
+}
+
+impl BusSocket {
+    /// Bind a socket on the interface.
+    pub fn bind(interface: &str, trace: Option<Arc<Trace>>) -> std::io::Result<Self> {
+        Ok(Self::new(interface, Arc::new(BoundSocket::bind(interface)?), trace))
+    }
+
+    /// Create a socket for the interface without binding it, used to replay a trace without a bus.
+    pub fn detached(interface: &str, trace: Option<Arc<Trace>>) -> Self {
+        Self::new(interface, Arc::new(Detached), trace)
+    }
+
+    /// Use the transport for the interface.
+    pub fn new(interface: &str, transport: Arc<dyn CanTransport>, trace: Option<Arc<Trace>>) -> Self {
+        let transport = match trace {
+            Some(trace) => Arc::new(TracedTransport { inner: transport, interface: interface.to_string(), trace }),
+            None => transport,
+        };
+        Self {
+            transport,
+            interface: interface.to_string(),
+        }
+    }
+
+    /// Delay, drop and reorder the sent frames as configured for the bus. The faults are added above the trace, so it
+    /// holds the frames that are really sent, at the time they are sent.
+    pub fn with_faults(mut self, config: &BusConfig) -> Self {
+        self.transport = Arc::new(FaultyTransport::new(self.transport, config));
+        self
+    }
+
+    pub async fn send(&self, frame: &CanFrame) -> std::io::Result<()> {
+        self.transport.send(frame).await
+    }
+
+    pub async fn recv(&self) -> std::io::Result<CanFrame> {
+        self.transport.recv().await
+    }
+
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Reconnect the transport after the interface went down, with an increasing delay between the attempts.
+    /// Returns the last error when the attempts run out.
+    pub async fn reconnect(&self) -> std::io::Result<()> {
+
+        let mut delay = RECONNECT_DELAY;
+        let mut attempt = 1;
+        loop {
+
+            tokio::time::sleep(delay).await;
+            match self.transport.reconnect().await {
+                Ok(()) => return Ok(()),
+                Err(e) if e.kind() == std::io::ErrorKind::Unsupported || attempt == RECONNECT_ATTEMPTS => return Err(e),
+                Err(e) => log::warn!("Reconnecting to CAN interface {} failed, attempt {} of {}: {e}", self.interface, attempt, RECONNECT_ATTEMPTS),
+            }
+
+            attempt += 1;
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+    }
+}
+
+/// Transport that writes the frames sent and received on another transport to the trace.
+struct TracedTransport {
+    inner: Arc<dyn CanTransport>,
+    interface: String,
+    trace: Arc<Trace>,
+}
+
+impl CanTransport for TracedTransport {
+    fn send<'a>(&'a self, frame: &'a CanFrame) -> TransportFuture<'a, ()> {
+        Box::pin(async move {
+            self.inner.send(frame).await?;
+            self.trace.log(&self.interface, frame);
+            Ok(())
+        })
+    }
+
+    fn recv(&self) -> TransportFuture<'_, CanFrame> {
+        Box::pin(async move {
+            let frame = self.inner.recv().await?;
+            self.trace.log(&self.interface, &frame);
+            Ok(frame)
+        })
+    }
+
+    fn reconnect(&self) -> TransportFuture<'_, ()> {
+        self.inner.reconnect()
+    }
+}
+
+/// Frame trace in the candump log format (`candump -L`), which can be replayed with canplayer.
+pub struct Trace {
+    file: Mutex<LineWriter<File>>,
+    start: Instant,
+}
+
+impl Trace {
+    /// Create the trace file, an existing file is appended to.
+    pub fn create(path: &Path) -> Result<Self, ()> {
+
+        let file = File::options().create(true).append(true).open(path)
+            .map_err(|e| log::error!("Failed to open trace file {}: {e}", path.display()))?;
+        log::info!("Tracing frames to {}", path.display());
+
+        Ok(Self {
+            file: Mutex::new(LineWriter::new(file)),
+            start: Instant::now(),
+        })
+    }
+
+    /// Write the buffered frames to the file.
+    pub fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = file.flush() {
+                log::error!("Failed to flush trace: {e}");
+            }
+        }
+    }
+
+    /// Write a frame with the time since the start of the trace in seconds.
+    fn log(&self, interface: &str, frame: &CanFrame) {
+
+        let timestamp = self.start.elapsed();
+        let line = format!("({:010}.{:06}) {} {}\n", timestamp.as_secs(), timestamp.subsec_micros(), interface, format_frame(frame));
+
+        if let Ok(mut file) = self.file.lock() {
+            if let Err(e) = file.write_all(line.as_bytes()) {
+                log::error!("Failed to write trace: {e}");
+            }
+        }
+    }
+}
+
+/// Format a frame as `ID#DATA`, with 3 hex digits for a base id and 8 for an extended id and `R` for a remote frame.
+fn format_frame(frame: &CanFrame) -> String {
+
+    let id = match frame.id() {
+        CanId::Base(id) => format!("{:03X}", id.as_u16()),
+        CanId::Extended(id) => format!("{:08X}", id.as_u32()),
+    };
+
+    let data = if frame.is_rtr() {
+        "R".to_string()
+    } else {
+        frame.data().iter().map(|byte| format!("{:02X}", byte)).collect()
+    };
+
+    format!("{}#{}", id, data)
+}
+
+/// Parse a line of a candump log into the timestamp, interface and frame. CAN FD frames are not supported.
+pub fn parse_trace_line(line: &str) -> Option<(Duration, String, CanFrame)> {
+
+    let mut fields = line.split_whitespace();
+    let timestamp = fields.next()?.strip_prefix('(')?.strip_suffix(')')?;
+    let interface = fields.next()?;
+    let (id, data) = fields.next()?.split_once('#')?;
+
+    let timestamp = Duration::try_from_secs_f64(timestamp.parse().ok()?).ok()?;
+
+    // Base ids have 3 hex digits, extended ids 8
+    let id = if id.len() == 3 {
+        CanId::new_base(u16::from_str_radix(id, 16).ok()?).ok()?
+    } else {
+        CanId::new_extended(u32::from_str_radix(id, 16).ok()?).ok()?
+    };
+
+    let frame = if let Some(length) = data.strip_prefix('R') {
+        CanFrame::new_rtr(id, length.parse().unwrap_or(0)).ok()?
+    } else {
+        let data = (0..data.len() / 2)
+            .map(|i| u8::from_str_radix(data.get(2 * i..2 * i + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()?;
+        CanFrame::new(id, &data, None).ok()?
+    };
+
+    Some((timestamp, interface.to_string(), frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send the frames on a socket with the faults of the bus and return the lines of the trace, once the frames
+    /// that are not dropped arrived on the bus.
+    async fn trace_of_sent_frames(bus_config: &BusConfig, count: u8, arriving: usize) -> Vec<String> {
+
+        let path = std::env::temp_dir().join(format!("trace-{}-{}.log", std::process::id(), bus_config.drop_probability));
+        std::fs::remove_file(&path).ok();
+        let trace = Arc::new(Trace::create(&path).unwrap());
+        let bus = MockBus::new();
+        let socket = BusSocket::new("vcan0", Arc::new(bus.clone()), Some(trace)).with_faults(bus_config);
+
+        for i in 0..count {
+            socket.send(&CanFrame::new(CanId::new_base(0x181).unwrap(), &[i], None).unwrap()).await.unwrap();
+        }
+        for _ in 0..arriving {
+            bus.next_sent().await;
+        }
+
+        let lines = std::fs::read_to_string(&path).unwrap().lines().map(str::to_string).collect();
+        std::fs::remove_file(&path).ok();
+        lines
+    }
+
+    #[tokio::test]
+    async fn dropped_frames_are_not_traced() {
+        let mut bus_config = BusConfig::new("vcan0");
+        bus_config.drop_probability = 1.;
+        assert!(trace_of_sent_frames(&bus_config, 10, 0).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn delayed_frames_are_traced_when_sent() {
+        let mut bus_config = BusConfig::new("vcan0");
+        bus_config.tx_latency_ms = 1;
+        let lines = trace_of_sent_frames(&bus_config, 10, 10).await;
+        assert_eq!(lines.len(), 10);
+        assert!(lines[0].ends_with("vcan0 181#00"));
+    }
+}
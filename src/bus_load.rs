@@ -0,0 +1,80 @@
+use std::ops::AddAssign;
+
+use crate::cia301::Node;
+use crate::eds::DataValue;
+
+/// Part of the bit rate the periodic frames can use before frames are expected to be delayed or lost on a real bus
+pub const MAX_BUS_LOAD: f64 = 0.7;
+
+/// Bits per second of the periodic frames on a bus, estimated from the communication parameters of the nodes
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BusLoad {
+    /// Synchronous TPDOs of the nodes
+    pub nodes: f64,
+    /// SYNC frames of the fastest communication cycle period (0x1006), the SYNC producer is shared by the nodes
+    pub sync: f64,
+}
+
+impl BusLoad {
+    /// Total bits per second.
+    pub fn bits_per_second(&self) -> f64 {
+        self.nodes + self.sync
+    }
+}
+
+impl AddAssign for BusLoad {
+    fn add_assign(&mut self, other: Self) {
+        self.nodes += other.nodes;
+        self.sync = self.sync.max(other.sync);
+    }
+}
+
+/// Bits of a frame with a base identifier and the data length, including the worst case stuff bits and the interframe space.
+pub fn frame_bits(data_length: usize) -> f64 {
+    let bits = 47 + 8 * data_length;
+    // A stuff bit after every four equal bits of the 34 bits from the start of frame up to the CRC and the data
+    let stuff_bits = (34 + 8 * data_length - 1) / 4;
+    (bits + stuff_bits) as f64
+}
+
+impl Node {
+
+    /// Estimate the bus load of the periodic frames of the node: the synchronous TPDOs of TPDO 1 to 8 (0x1800 to 0x1807)
+    /// at the communication cycle period (0x1006) of the SYNC they answer. Event driven TPDOs are only sent on a change
+    /// and are not counted.
+    pub(crate) fn bus_load(&self) -> BusLoad {
+
+        let mut load = BusLoad::default();
+
+        // The SYNC carries the counter when the synchronous counter overflow value (0x1019) is above 1
+        let sync_period = self.eds_data.get_f64(0x1006, 0).unwrap_or(0.);
+        let sync_rate = if sync_period > 0. { 1_000_000. / sync_period } else { 0. };
+        let sync_length = match self.eds_data.get_var(0x1019, 0).map(|var| &var.value) {
+            Some(DataValue::Unsigned8(overflow)) if *overflow > 1 => 1,
+            _ => 0,
+        };
+        load.sync = frame_bits(sync_length) * sync_rate;
+
+        for tpdo in self.pdo_mappings.tpdos.iter() {
+
+            let Some(tpdo) = tpdo else {
+                continue;
+            };
+            if !tpdo.enabled || tpdo.objects.is_empty() {
+                continue;
+            }
+
+            // Acyclic synchronous TPDOs are sent on a SYNC when their data changed, at most on every SYNC
+            let rate = match tpdo.transmission_type {
+                0 => sync_rate,
+                transmission_type @ 1..=240 => sync_rate / transmission_type as f64,
+                _ => 0.,
+            };
+
+            load.nodes += frame_bits(self.tpdo_data(tpdo).len()) * rate;
+        }
+
+        load
+    }
+
+}
@@ -1,20 +1,239 @@
 use std::collections::BTreeMap;
-use std::time::Instant;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 
-use can_socket::{tokio::CanSocket, CanId};
+use can_socket::CanId;
 use can_socket::CanFrame;
 use canopen_tokio::nmt::{NmtCommand, NmtState};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::bus::{self, BusSocket};
+use crate::clock::Clock;
+use crate::conformance::{Conformance, WriteSource};
+use crate::coupling::AxisMotions;
+use crate::config::{Node as NodeConfig, UNCONFIGURED_NODE_ID};
+use crate::eds::{DataValue, EDSData, Var};
+use crate::lss::{LssSlave, LSS_REQUEST_COB_ID};
+use crate::pdo::{PdoMappings, Tpdo};
+use crate::rng::SplitMix64;
+use crate::sdo::SdoTransfer;
+use crate::cia402_runner::{Command, HomeStatus, ModeOfOperation, Motion, PositionMove, ProfilePositionStatus, ProfileVelocityStatus, Setpoint, State};
+
+/// Device type (0x1000) when the EDS does not define it: CiA 402 servo drive
+const DEVICE_TYPE: u32 = 0x0002_0192;
+
+/// COB-ID of the NMT commands of the master
+const NMT_COB_ID: u32 = 0x000;
+
+/// COB-ID TIME (0x1012) when the EDS does not define it: TIME consumer on COB-ID 0x100
+const DEFAULT_TIME_COB_ID: u32 = 0x8000_0100;
+
+/// Bit of NMT startup (0x1F80) to enter operational without an NMT start
+const NMT_STARTUP_SELF_STARTING: u32 = 1 << 2;
+
+/// Period of the TIME messages when the node is the TIME producer
+const TIME_PRODUCER_PERIOD: Duration = Duration::from_secs(1);
+
+/// Days from the Unix epoch to 1984-01-01, the epoch of TIME_OF_DAY
+const TIME_OF_DAY_EPOCH_DAYS: u64 = 5113;
+
+/// Number of received frames that can wait for a node
+pub const FRAME_QUEUE_SIZE: usize = 64;
+
+/// Message from the receive task to the node
+#[derive(Debug, Clone)]
+pub enum SocketMessage {
+    /// A frame received on the bus, with the time of the clock of the twin it was received.
+    Frame(CanFrame, Duration),
+    /// Receiving from the socket failed.
+    Error(String),
+    /// The interface of the bus is gone and could not be reconnected, no more frames are received.
+    Closed,
+}
+
+/// Number of requests that can wait for a node
+pub const REQUEST_QUEUE_SIZE: usize = 16;
+
+/// Request to a node from outside of its task, answered on the reply channel
+#[derive(Debug)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub enum NodeRequest {
+    /// Read the objects of the object dictionary, a single object when an index and sub index are given.
+    ReadObjects {
+        object: Option<(u16, u8)>,
+        reply: oneshot::Sender<Vec<(u16, u8, Var)>>,
+    },
+    /// Write the little endian data to an object as an SDO download does, replies the abort code when rejected.
+    WriteObject {
+        index: u16,
+        sub_index: u8,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<(), u32>>,
+    },
+    /// Apply an NMT command as if it was received on the bus.
+    Nmt {
+        command: NmtCommand,
+        reply: oneshot::Sender<()>,
+    },
+    /// Read the metrics of the node.
+    Metrics {
+        reply: oneshot::Sender<NodeMetrics>,
+    },
+    /// Read a copy of the EDS data with the current values of the object dictionary.
+    EdsData {
+        reply: oneshot::Sender<EDSData>,
+    },
+    /// Read the statistics of the node.
+    Stats {
+        reply: oneshot::Sender<NodeStats>,
+    },
+}
+
+/// Frame counters of a node
+#[derive(Debug, Default)]
+pub struct NodeCounters {
+    pub frames_received: AtomicU64,
+    pub frames_sent: AtomicU64,
+    pub sdo_aborts: AtomicU64,
+    /// Synchronous TPDOs not sent because the synchronous window (0x1007) had passed
+    pub sync_window_drops: AtomicU64,
+    pub sdo_uploads: AtomicU64,
+    pub sdo_downloads: AtomicU64,
+    pub rpdos_applied: AtomicU64,
+    pub tpdos_sent: AtomicU64,
+    pub emcy_sent: AtomicU64,
+    pub nmt_changes: AtomicU64,
+}
+
+impl NodeCounters {
+    /// The current values of the counters.
+    pub fn stats(&self) -> NodeStats {
+        NodeStats {
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            sdo_uploads: self.sdo_uploads.load(Ordering::Relaxed),
+            sdo_downloads: self.sdo_downloads.load(Ordering::Relaxed),
+            rpdos_applied: self.rpdos_applied.load(Ordering::Relaxed),
+            tpdos_sent: self.tpdos_sent.load(Ordering::Relaxed),
+            emcy_sent: self.emcy_sent.load(Ordering::Relaxed),
+            nmt_changes: self.nmt_changes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Statistics of a node since it started, see [`DigitalTwin::stats`](crate::DigitalTwin::stats)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NodeStats {
+    /// Frames received on the bus of the node, also the frames addressed to other nodes
+    pub frames_received: u64,
+    /// Frames sent by the node
+    pub frames_sent: u64,
+    /// SDO uploads (reads) started by a client and accepted by the node
+    pub sdo_uploads: u64,
+    /// SDO downloads (writes) started by a client and accepted by the node
+    pub sdo_downloads: u64,
+    /// RPDOs with a valid length written to the object dictionary
+    pub rpdos_applied: u64,
+    /// TPDOs sent, on SYNC or on a change of the mapped objects
+    pub tpdos_sent: u64,
+    /// EMCY frames sent, including the error reset EMCY
+    pub emcy_sent: u64,
+    /// Changes of the NMT state
+    pub nmt_changes: u64,
+}
+
+/// State and counters of a node, published as metrics
+#[derive(Debug)]
+#[cfg_attr(not(feature = "http"), allow(dead_code))]
+pub struct NodeMetrics {
+    pub statusword: u16,
+    /// Position actual value (0x6064) in user units
+    pub position: f64,
+    /// Velocity actual value (0x606C) in user units
+    pub velocity: f64,
+    /// NMT state as reported in the NMT state messages
+    pub nmt_state: u8,
+    pub frames_received: u64,
+    pub frames_sent: u64,
+    pub sdo_aborts: u64,
+    pub sync_window_drops: u64,
+}
+
+/// Request senders of the running nodes by interface and node id
+pub type NodeRegistry = Arc<RwLock<BTreeMap<(String, u8), mpsc::Sender<NodeRequest>>>>;
+
+/// Called with the new value when an object is written over SDO or by an RPDO.
+pub type WriteCallback = Box<dyn FnMut(&DataValue) + Send>;
+
+/// Write callbacks by node id, index and sub index, shared by all nodes and kept when a node restarts
+#[derive(Default)]
+pub struct WriteCallbacks(BTreeMap<(u8, u16, u8), Vec<Mutex<WriteCallback>>>);
 
-use crate::eds::{DataValue, EDSData};
-use crate::cia402_runner::{Command, HomeStatus, ModeOfOperation, ProfilePositionStatus, ProfileVelocityStatus, State};
+impl WriteCallbacks {
+    pub fn insert(&mut self, node_id: u8, index: u16, sub_index: u8, callback: WriteCallback) {
+        self.0.entry((node_id, index, sub_index)).or_default().push(Mutex::new(callback));
+    }
+}
 
 pub struct Node {
     pub node_id: u8,
+    /// Log target of the node, `twin::node::<node id>`, to filter the logs of one node with `RUST_LOG=twin::node::3=debug`
+    pub log_target: String,
     pub eds_data: EDSData,
     pub nmt_state: NmtState,
-    pub socket: CanSocket,
+    pub socket: Arc<BusSocket>,
     pub motor_controller: MotorController,
+    pub emcy_on_pdo_length_error: bool,
+    pub quick_stop_on_pre_operational: bool,
+    pub auto_start: bool,
+    /// Manufacturer object with the duration and end position of the last planned move
+    pub move_diagnostics_index: u16,
+    /// Manufacturer object with the node id, if any
+    pub node_id_index: Option<u16>,
+    /// Standard deviation in user units of the noise on the position actual value (0x6064)
+    pub position_noise_stddev: f64,
+    pub position_noise: SplitMix64,
+    /// Node id of the axis on the same bus this axis follows, if any
+    pub follow: Option<u8>,
+    /// Motion of the axes on the bus of the node, to follow an axis
+    pub axis_motions: AxisMotions,
+    pub sdo_transfer: Option<SdoTransfer>,
+    pub pdo_mappings: PdoMappings,
+    pub sdo_segment_gap: Duration,
+    /// Clock time of the last segment response sent
+    pub last_sdo_segment: Option<Duration>,
+    /// Segment responses waiting for the gap since the previous segment response, sent by the update cycle
+    pub sdo_segment_queue: VecDeque<[u8; 8]>,
+    /// Clock time of the last SDO request, a transfer in progress times out without requests
+    pub last_sdo_request: Option<Duration>,
+    /// Period of the motor controller update
+    pub cycle_time: Duration,
+    /// Time after the start of the node before it is powered up and sends its boot-up
+    pub startup_delay: Duration,
+    /// Clock of the update cycles
+    pub clock: Arc<dyn Clock>,
+    pub write_callbacks: Arc<WriteCallbacks>,
+    /// Conformance rules the writes of the master are checked against, shared by all nodes
+    pub conformance: Arc<Conformance>,
+    /// Error codes of the errors that are currently active, reflected in the error register (0x1001)
+    pub active_errors: Vec<u16>,
+    pub counters: NodeCounters,
+    /// File the parameters are stored to (0x1010)
+    pub parameter_file: PathBuf,
+    /// Object dictionary as parsed from the EDS file, restored with 0x1011
+    pub default_eds_data: EDSData,
+    /// EDS file the object dictionary is parsed from
+    pub eds_file: PathBuf,
+    pub lss: LssSlave,
+    /// Time of day since 1984-01-01 and the clock time it was set: the system time at the start of the node, or the time
+    /// received from the TIME producer
+    pub time_of_day: (Duration, Duration),
+    /// Clock time the TIME producer sends the next time of day
+    pub next_time_production: Duration,
 }
 
 #[derive(Default)]
@@ -32,140 +251,270 @@ pub struct MotorController {
     pub target_reached: bool,
     pub status_oms1: bool,
     pub status_oms2: bool,
-    pub timer: Option<Instant>,
-}
-
-#[derive(Debug)]
-enum ServerCommand {
-    
-	/// The server is uploading a segment.
-	_UploadSegmentResponse = 0,
-
-	/// The server has downloaded the segment.
-	_DownloadSegmentResponse = 1,
-
-	/// The server accepts the upload request.
-	InitiateUploadResponse = 2,
-
-	/// The server accepts the download request.
-	InitiateDownloadResponse = 3,
-
-	/// The server is aborting the transfer.
-	_AbortTransfer = 4,
-
-    /// Unknown server command.
-    Unknown = 5,
-}
-
-#[derive(Debug)]
-enum ClientCommand {
-
-	/// Download a segment to the server.
-	SegmentDownload = 0,
-
-	/// Initiate a download to the server.
-	InitiateDownload = 1,
-
-	/// Initiate an upload from the server.
-	InitiateUpload = 2,
-
-	/// Request the server to upload a segment.
-	SegmentUpload = 3,
-
-	/// Tell the server we are aborting the transfer.
-	AbortTransfer = 4,
-
-    /// Unknown client command.
-    Unknown = 5,
+    /// Main supply applied, statusword bit 4
+    pub voltage_enabled: bool,
+    /// Warning active, statusword bit 7
+    pub warning: bool,
+    /// Fault reset bit of the controlword (bit 7) at the previous update
+    pub fault_reset: bool,
+    /// Controlword processed from the bus, statusword bit 9
+    pub remote: bool,
+    /// Target or velocity saturated by a limit, statusword bit 11
+    pub internal_limit_active: bool,
+    /// Timing of the motion of the mode of operation in progress
+    pub motion: Motion,
+    /// Simulation time, advanced by the cycle time on every update
+    pub time: Duration,
+    /// Position in increments in the motor direction, before applying polarity
+    pub actual_position: f64,
+    /// Velocity in rpm in the motor direction, before applying polarity
+    pub actual_velocity: f64,
+    pub target_velocity: f64,
+    /// Actual velocity at the start of the velocity ramp
+    pub ramp_start_velocity: f64,
+    /// Simulation time the actual velocity entered the velocity window (0x606D) around the target velocity
+    pub velocity_window_entered: Option<Duration>,
+    /// Torque in per thousand of the rated torque
+    pub actual_torque: f64,
+    pub touch_probe_input: bool,
+    pub digital_outputs: u32,
+    /// Profile position move in progress, or the last one
+    pub position_move: Option<PositionMove>,
+    /// Setpoints buffered while a profile position move is in progress
+    pub setpoint_queue: VecDeque<Setpoint>,
 }
 
-impl ClientCommand {
-    fn client_command(value: u8) -> ClientCommand {
-        match value {
-            0 => ClientCommand::SegmentDownload,
-            1 => ClientCommand::InitiateDownload,
-            2 => ClientCommand::InitiateUpload,
-            3 => ClientCommand::SegmentUpload,
-            4 => ClientCommand::AbortTransfer,
-            _ => ClientCommand::Unknown,
-        }
-    }
-}
-
-
 impl Node {
     /// Initialize the motor controller.
     pub async fn initialize(
-        socket: CanSocket,
-        node_id: u8,
+        socket: Arc<BusSocket>,
+        node_config: &NodeConfig,
         eds_data: EDSData,
-    ) -> Result<Self, ()> {
+        cycle_time: Duration,
+        clock: Arc<dyn Clock>,
+        write_callbacks: Arc<WriteCallbacks>,
+    ) -> Self {
+        let clock_time = clock.now();
         let mut node = Self {
-            node_id,
-            eds_data,
+            node_id: node_config.node_id,
+            log_target: log_target(node_config.node_id),
+            eds_data: eds_data.clone(),
             nmt_state: NmtState::Initializing,
             socket,
-            motor_controller: {Default::default()}
+            motor_controller: {Default::default()},
+            emcy_on_pdo_length_error: node_config.emcy_on_pdo_length_error,
+            quick_stop_on_pre_operational: node_config.quick_stop_on_pre_operational,
+            auto_start: node_config.auto_start,
+            move_diagnostics_index: node_config.move_diagnostics_index,
+            node_id_index: node_config.node_id_index,
+            position_noise_stddev: node_config.position_noise_stddev,
+            position_noise: SplitMix64(node_config.position_noise_seed ^ node_config.node_id as u64),
+            follow: node_config.follow,
+            axis_motions: AxisMotions::default(),
+            sdo_transfer: None,
+            pdo_mappings: PdoMappings::default(),
+            sdo_segment_gap: Duration::from_micros(node_config.sdo_segment_gap_us),
+            last_sdo_segment: None,
+            sdo_segment_queue: VecDeque::new(),
+            last_sdo_request: None,
+            cycle_time,
+            startup_delay: Duration::ZERO,
+            clock,
+            write_callbacks,
+            conformance: Arc::default(),
+            active_errors: Vec::new(),
+            counters: NodeCounters::default(),
+            parameter_file: node_config.parameter_file(),
+            default_eds_data: eds_data,
+            eds_file: node_config.eds_file.clone(),
+            lss: LssSlave::default(),
+            time_of_day: (system_time_of_day(), clock_time),
+            next_time_production: clock_time,
         };
         node.motor_controller.control_oms1 = VecDeque::from(vec![false; 2]);
-        Ok(node)
+        node.motor_controller.voltage_enabled = true;
+        node.motor_controller.remote = true;
+        node.add_communication_objects();
+        node.add_supported_drive_modes();
+        node.add_touch_probe_objects();
+        node.add_digital_io_objects(node_config.digital_inputs);
+        node.add_fault_injection_objects();
+        node.add_set_position_object();
+        node.add_move_diagnostics_objects();
+        node.add_node_id_object();
+        if let Some(serial_number) = node_config.serial_number {
+            node.eds_data.set_dataval(0x1018, 4, DataValue::Unsigned32(serial_number));
+        }
+        node.default_eds_data = node.eds_data.clone();
+        node.load_parameters();
+        node.update_node_id_object();
+        node.update_pdo_mappings();
+        node
     }
 
-    pub async fn start_socket(&mut self) {
-
-        // Start receiving frames over socket
-        loop {
+    /// Add the device type and the manufacturer status register when the EDS does not define them.
+    fn add_communication_objects(&mut self) {
+        self.eds_data.insert_var_if_missing(0x1000, 0, "Device type", "ro", DataValue::Unsigned32(DEVICE_TYPE));
+        self.eds_data.insert_var_if_missing(0x1002, 0, "Manufacturer status register", "ro", DataValue::Unsigned32(0));
+        self.eds_data.insert_var_if_missing(0x1012, 0, "COB-ID TIME", "rw", DataValue::Unsigned32(DEFAULT_TIME_COB_ID));
+    }
 
-            // Check if a frame is received
-            if let Some(frame) = self.socket.recv().await.ok() {
+    /// Handle the frames received by the receive task of the bus, see [`receive_frames`], and the requests to the node.
+    pub async fn start_socket(&mut self, mut receiver: broadcast::Receiver<SocketMessage>, mut requests: mpsc::Receiver<NodeRequest>) {
 
-                // Extract id and cob_id
-                let cob_id = frame.id().as_u32();
-                let node_id = (cob_id & 0x7F) as u8;
-                let function_code = frame.id().as_u32() & (0x0F << 7);
+        let mut ticker = self.clock.ticker(self.cycle_time);
 
-                // Parse frame
-                if node_id == 0 {
+        // A node that is not powered up yet misses the frames on the bus
+        if !self.startup_delay.is_zero() {
+            let cycles = self.startup_delay.as_micros().div_ceil(self.cycle_time.as_micros());
+            for _ in 0..cycles {
+                ticker.tick().await;
+            }
+            receiver = receiver.resubscribe();
+        }
 
-                    match function_code {
-                        0x000 => self.parse_nmt_command(&frame.data()).await,
-                        0x080 => self.parse_sync().await,
-                        _ => {},
-                    }
+        // A self-starting node reports its boot-up and starts itself, other nodes wait for the master
+        if self.self_starting() {
+            self.send_new_nmt_state().await;
+            self.self_start().await;
+        }
 
-                } else if node_id == self.node_id {
+        // Handle received frames and update the motor controller on a fixed period
+        loop {
 
-                    match function_code {
-                        0x080 => self.parse_emcy().await,
-                        0x200 => self.parse_rpdo(&1, &frame.data()).await,
-                        0x300 => self.parse_rpdo(&2, &frame.data()).await,
-                        0x400 => self.parse_rpdo(&3, &frame.data()).await,
-                        0x500 => self.parse_rpdo(&4, &frame.data()).await,
-                        0x600 => self.parse_sdo_client_request(&frame.data()).await,
-                        _ => {},
+            tokio::select! {
+                result = receiver.recv() => match result {
+                    Ok(SocketMessage::Frame(frame, received)) => {
+                        self.counters.frames_received.fetch_add(1, Ordering::Relaxed);
+                        self.parse_frame(&frame, received).await;
                     }
-                    
-                }
-
-                if cob_id == 0x080 {
+                    Ok(SocketMessage::Error(e)) => log::error!(target: &self.log_target, "Error receiving frame node {}: {}", self.node_id, e),
+                    Ok(SocketMessage::Closed) => {
+                        log::error!(target: &self.log_target, "Node {} stopped, its CAN interface is not available", self.node_id);
+                        return;
+                    }
+                    Err(RecvError::Lagged(count)) => log::warn!(target: &self.log_target, "Node {} missed {} frames", self.node_id, count),
+                    Err(RecvError::Closed) => return,
+                },
+                Some(request) = requests.recv() => self.handle_request(request).await,
+                _ = ticker.tick() => {
+                    self.send_due_sdo_segments().await;
+                    self.produce_time().await;
                     self.update_controller().await;
                 }
+            }
 
+        }
+
+    }
+
+    async fn handle_request(&mut self, request: NodeRequest) {
+
+        match request {
+            NodeRequest::ReadObjects { object, reply } => {
+                let objects = self.eds_data.od.iter()
+                    .flat_map(|(&index, vars)| vars.iter().map(move |(&sub_index, var)| (index, sub_index, var)))
+                    .filter(|&(index, sub_index, _)| object.is_none_or(|object| object == (index, sub_index)))
+                    .map(|(index, sub_index, var)| (index, sub_index, var.clone()))
+                    .collect();
+                reply.send(objects).ok();
+            }
+            NodeRequest::WriteObject { index, sub_index, data, reply } => {
+                reply.send(self.write_object(index, sub_index, &data)).ok();
+            }
+            NodeRequest::Nmt { command, reply } => {
+                self.apply_nmt_command(command).await;
+                reply.send(()).ok();
+            }
+            NodeRequest::EdsData { reply } => {
+                reply.send(self.eds_data.clone()).ok();
+            }
+            NodeRequest::Stats { reply } => {
+                reply.send(self.counters.stats()).ok();
+            }
+            NodeRequest::Metrics { reply } => {
+                reply.send(NodeMetrics {
+                    statusword: self.motor_controller.statusword,
+                    position: self.eds_data.get_f64(0x6064, 0).unwrap_or(0.),
+                    velocity: self.eds_data.get_f64(0x606C, 0).unwrap_or(0.),
+                    nmt_state: nmt_state_code(&self.nmt_state),
+                    frames_received: self.counters.frames_received.load(Ordering::Relaxed),
+                    frames_sent: self.counters.frames_sent.load(Ordering::Relaxed),
+                    sdo_aborts: self.counters.sdo_aborts.load(Ordering::Relaxed),
+                    sync_window_drops: self.counters.sync_window_drops.load(Ordering::Relaxed),
+                }).ok();
             }
         }
 
     }
 
-    async fn parse_nmt_command(&mut self, data: &[u8]) {
+    async fn parse_frame(&mut self, frame: &CanFrame, received: Duration) {
 
-        // Check if the data the correct size
-        if !(data.len() == 2) {
-            log::error!("Received incorrect frame data length for NMT state change");
+        let id = frame.id();
+
+        // An unconfigured node only responds to LSS
+        if cob_id_matches(LSS_REQUEST_COB_ID, id) {
+            self.parse_lss_request(frame.data()).await;
+            return;
+        } else if self.node_id == UNCONFIGURED_NODE_ID {
+            return;
+        }
+
+        // TIME is consumed in pre-operational and operational when enabled in 0x1012
+        let time_cob_id = self.eds_data.get_f64(0x1012, 0).unwrap_or(0.) as u32;
+        if time_cob_id & (1 << 31) != 0 && cob_id_matches(time_cob_id, id) {
+            if matches!(self.nmt_state, NmtState::PreOperational | NmtState::Operational) {
+                self.parse_time(frame.data());
+            }
+            return;
+        }
+
+        // NMT has a fixed COB-ID, SYNC, SDO and the RPDOs the COB-IDs in the object dictionary
+        let rpdo = self.pdo_mappings.rpdo_cob_ids.iter()
+            .position(|cob_id| cob_id.is_some_and(|cob_id| cob_id_matches(cob_id, id)));
+
+        if cob_id_matches(NMT_COB_ID, id) {
+            self.parse_nmt_command(frame.data()).await;
+        } else if cob_id_matches(self.sync_cob_id(), id) {
+            if self.nmt_state == NmtState::Operational {
+                self.parse_sync(frame.data(), received).await;
+            }
+        } else if cob_id_matches(self.sdo_request_cob_id(), id) {
+            self.parse_sdo_client_request(frame.data()).await;
+        } else if let Some(rpdo) = rpdo {
+            if self.nmt_state == NmtState::Operational {
+                self.parse_rpdo(&(rpdo as u16 + 1), frame.data()).await;
+            }
+        } else if cob_id_matches(0x080 + self.node_id as u32, id) {
+            self.parse_emcy(frame.data());
+        }
+
+    }
+
+    /// COB-ID of the SYNC (0x1005), the predefined 0x080 when the object is missing.
+    fn sync_cob_id(&self) -> u32 {
+        match self.eds_data.get_var(0x1005, 0).map(|var| &var.value) {
+            Some(DataValue::Unsigned32(cob_id)) => *cob_id,
+            _ => 0x080,
         }
+    }
+
+    /// COB-ID of the SDO requests (sub 1 of the SDO server parameter 0x1200), the predefined 0x600 + node id when the
+    /// object is missing.
+    fn sdo_request_cob_id(&self) -> u32 {
+        match self.eds_data.get_var(0x1200, 1).map(|var| &var.value) {
+            Some(DataValue::Unsigned32(cob_id)) => *cob_id,
+            _ => 0x600 + self.node_id as u32,
+        }
+    }
+
+    async fn parse_nmt_command(&mut self, data: &[u8]) {
 
-        // Extract data
-        let requested_state = data[0];
-        let addressed_node = data[1];
+        // Check if the data the correct size
+        let [requested_state, addressed_node] = *data else {
+            log::error!(target: &self.log_target, "Received incorrect frame data length for NMT state change: {} bytes", data.len());
+            return;
+        };
 
         // Label NMT command based on requested state
         let nmt_command = match requested_state {
@@ -174,23 +523,123 @@ impl Node {
             0x80 => NmtCommand::GoToPreOperational,
             0x81 => NmtCommand::Reset,
             0x82 => NmtCommand::ResetCommunication,
-            _ => panic!("Unexpected requested state: {:#X}", requested_state),
+            _ => {
+                log::error!(target: &self.log_target, "Unexpected requested state: {:#X}", requested_state);
+                return;
+            }
         };
 
-        // Change NMT state
-        if addressed_node == self.node_id {
+        // Change NMT state, node id 0 addresses all nodes
+        if addressed_node == self.node_id || addressed_node == 0 {
+            self.apply_nmt_command(nmt_command).await;
+        }
+
+    }
 
-            self.nmt_state = match nmt_command {
-                NmtCommand::Start => NmtState::Operational,
-                NmtCommand::Stop => NmtState::Stopped,
-			    NmtCommand::GoToPreOperational => NmtState::PreOperational,
-			    NmtCommand::Reset => NmtState::Initializing,
-			    NmtCommand::ResetCommunication => NmtState::Initializing,
-            };
+    /// Set the time of day from a TIME_OF_DAY message: milliseconds after midnight and days since 1984-01-01.
+    fn parse_time(&mut self, data: &[u8]) {
+
+        let Some(&[ms0, ms1, ms2, ms3, days0, days1]) = data.get(..6) else {
+            log::warn!(target: &self.log_target, "TIME node {} ignored: received {} bytes, expected 6", self.node_id, data.len());
+            return;
+        };
+
+        let milliseconds = u32::from_le_bytes([ms0, ms1, ms2, ms3]) & 0x0FFF_FFFF;
+        let days = u16::from_le_bytes([days0, days1]);
+        let time_of_day = Duration::from_secs(days as u64 * 86_400) + Duration::from_millis(milliseconds as u64);
+
+        log::debug!(target: &self.log_target, "TIME node {}: day {} at {} ms", self.node_id, days, milliseconds);
+        self.time_of_day = (time_of_day, self.clock.now());
+
+    }
+
+    /// Time since 1984-01-01: the time of day advanced by the time of the clock since it was set.
+    pub fn time_of_day(&self) -> Duration {
+        let (time_of_day, set) = self.time_of_day;
+        time_of_day + self.clock.now().saturating_sub(set)
+    }
+
+    /// Send the time of day every period of the clock when the node is the TIME producer (0x1012 bit 30), in
+    /// pre-operational and operational.
+    async fn produce_time(&mut self) {
+
+        let now = self.clock.now();
+        if now < self.next_time_production {
+            return;
+        }
+        self.next_time_production = now + TIME_PRODUCER_PERIOD;
+
+        let time_cob_id = self.eds_data.get_f64(0x1012, 0).unwrap_or(0.) as u32;
+        if time_cob_id & (1 << 30) == 0 || !matches!(self.nmt_state, NmtState::PreOperational | NmtState::Operational) {
+            return;
+        }
+
+        let time_of_day = self.time_of_day();
+        let days = (time_of_day.as_secs() / 86_400) as u16;
+        let milliseconds = (time_of_day.as_millis() % 86_400_000) as u32;
+
+        let mut data: [u8; 6] = [0; 6];
+        data[..4].copy_from_slice(&milliseconds.to_le_bytes());
+        data[4..].copy_from_slice(&days.to_le_bytes());
+
+        let cob_id = CanId::new_base((time_cob_id & 0x7FF) as u16).unwrap();
+        let frame = &CanFrame::new(cob_id, &data, None).unwrap();
+
+        self.send_frame(frame).await;
+
+    }
+
+    /// Change the NMT state and report the new state.
+    pub async fn apply_nmt_command(&mut self, nmt_command: NmtCommand) {
+
+        let new_nmt_state = match nmt_command {
+            NmtCommand::Start => NmtState::Operational,
+            NmtCommand::Stop => NmtState::Stopped,
+            NmtCommand::GoToPreOperational => NmtState::PreOperational,
+            NmtCommand::Reset => NmtState::Initializing,
+            NmtCommand::ResetCommunication => NmtState::Initializing,
+        };
+
+        // PDOs stop when leaving operational, the drive keeps its state unless configured to quick stop
+        if self.nmt_state == NmtState::Operational && new_nmt_state == NmtState::PreOperational
+            && self.quick_stop_on_pre_operational && self.motor_controller.state == State::OperationEnabled {
+            log::info!(target: &self.log_target, "Quick stop node {} on entering pre-operational", self.node_id);
+            self.motor_controller.state = State::QuickStopActive;
+        }
+
+        // A node id configured over LSS becomes active on a reset
+        if matches!(nmt_command, NmtCommand::Reset | NmtCommand::ResetCommunication) {
+            self.activate_pending_node_id();
+        }
+
+        self.set_nmt_state(new_nmt_state);
+        if self.node_id != UNCONFIGURED_NODE_ID {
             self.send_new_nmt_state().await;
+        }
+        self.self_start().await;
+
+    }
+
+    /// The node enters operational after the boot-up, by the configuration or the self-starting bit of NMT startup (0x1F80).
+    fn self_starting(&self) -> bool {
+        let nmt_startup = self.eds_data.get_f64(0x1F80, 0).unwrap_or(0.) as u32;
+        self.node_id != UNCONFIGURED_NODE_ID && (self.auto_start || nmt_startup & NMT_STARTUP_SELF_STARTING != 0)
+    }
 
+    /// Enter operational after the boot-up of a self-starting node.
+    pub(crate) async fn self_start(&mut self) {
+        if self.nmt_state == NmtState::Initializing && self.self_starting() {
+            self.set_nmt_state(NmtState::Operational);
+            self.send_new_nmt_state().await;
         }
+    }
 
+    /// Enter an NMT state, counted in the statistics when it differs from the current state.
+    pub(crate) fn set_nmt_state(&mut self, nmt_state: NmtState) {
+        if self.nmt_state != nmt_state {
+            self.counters.nmt_changes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nmt_state = nmt_state;
     }
 
     pub async fn send_new_nmt_state(&mut self) {
@@ -198,12 +647,7 @@ impl Node {
         let cob = u16::from_str_radix("700", 16).unwrap();
         let cob_id = CanId::new_base(cob | self.node_id as u16).unwrap();
 
-        let data: [u8; 1] = [match self.nmt_state {
-            NmtState::Initializing => 0x00,
-            NmtState::Stopped => 0x04,
-            NmtState::Operational => 0x05,
-            NmtState::PreOperational => 0x7f,
-        }];
+        let data: [u8; 1] = [nmt_state_code(&self.nmt_state)];
 
         let frame = &CanFrame::new(
             cob_id,
@@ -212,361 +656,654 @@ impl Node {
         )
         .unwrap();
 
-        if let Err(_) = self.socket.send(frame).await {
-            log::error!("Error sending frame");
-        }
+        self.send_frame(frame).await;
 
-        log::info!("New NMT State node {}: {}", self.node_id, self.nmt_state);
+        log::info!(target: &self.log_target, "New NMT State node {}: {}", self.node_id, self.nmt_state);
 
     }
 
-    async fn parse_sdo_client_request(&mut self, data: &[u8]) {
+    async fn parse_rpdo(&mut self, rpdo_number: &u16, input_data: &[u8]) {
 
-        if data.len() > 8 {
-            log::error!("Data length too long")
-        };
+        let rpdo = (*rpdo_number - 1) as usize;
 
-        let ccs = (data[0] >> 5) & 0b111;
-        let command = ClientCommand::client_command(ccs);
-        self.sdo_response(&command,data).await;
-
-    }
-
-    async fn sdo_response(&mut self, command: &ClientCommand, input_data: &[u8]) {
-
-        let input_index = u16::from_le_bytes([input_data[1], input_data[2]]);
-        let input_sub_index = input_data[3];
-
-        if let Some(var) = self.eds_data.od.get_mut(&input_index)
-            .and_then(|vars| vars.get_mut(&input_sub_index)) {
-
-                let mut data: [u8; 8] = [0; 8];
-                let mut scs = ServerCommand::Unknown;
-                let mut n = 0;
-                let mut s = 0;
-                let mut e = 0;
-
-                match command {
-                    ClientCommand::InitiateUpload => {
-
-                        s = 1;
-                        e = 1;
-                        scs = ServerCommand::InitiateUploadResponse;
-
-                        match var.value {
-                            DataValue::Integer8(value) => {
-                                n = 3;
-                                data[4] = value as u8;
-                            }
-                            DataValue::Integer16(value) => {
-                                n = 2;
-                                data[4..6].copy_from_slice(&value.to_le_bytes());
-                            }
-                            DataValue::Integer32(value) => data[4..].copy_from_slice(&value.to_le_bytes()),
-                            DataValue::Unsigned8(value) => {
-                                n = 3;
-                                data[4] = value;
-                            }
-                            DataValue::Unsigned16(value) => {
-                                n = 2;
-                                data[4..6].copy_from_slice(&value.to_le_bytes());
-                            }
-                            DataValue::Unsigned32(value) => data[4..].copy_from_slice(&value.to_le_bytes()),
-                            _ => log::error!("Data type not implemented for initiate upload"),
-                        };
-                    }
-                    ClientCommand::InitiateDownload => {
-
-                        // Update value with incoming data
-                        match var.value {
-                            DataValue::Integer8(_) => var.value = DataValue::Integer8(input_data[4] as i8),
-                            DataValue::Integer16(_) => var.value = DataValue::Integer16(i16::from_le_bytes([input_data[4], input_data[5]])),
-                            DataValue::Integer32(_) => var.value = DataValue::Integer32(i32::from_le_bytes([input_data[4], input_data[5], input_data[6], input_data[7]])),
-                            DataValue::Unsigned8(_) => var.value = DataValue::Unsigned8(input_data[4]),
-                            DataValue::Unsigned16(_) => var.value = DataValue::Unsigned16(u16::from_le_bytes([input_data[4], input_data[5]])),
-                            DataValue::Unsigned32(_) => var.value = DataValue::Unsigned32(u32::from_le_bytes([input_data[4], input_data[5], input_data[6], input_data[7]])),
-                            _ => log::error!("Data type not implemented for initiate download"),
-                        }
-
-                        s = 0;
-                        e = 0;
-                        scs = ServerCommand::InitiateDownloadResponse;
-                        
-                    }
-                    _ => log::error!("Client command not implemented"),
-                }
+        // Validate the frame length against the length of the mapped objects
+        let mapped_bits: u32 = self.pdo_mappings.rpdos[rpdo].iter()
+            .map(|object| object.bits as u32)
+            .sum();
+        let mapped_length = mapped_bits.div_ceil(8) as usize;
 
-                data[0] = data[0] | (scs as u8 & 0b111) << 5;
-                data[0] = data[0] | (n & 0b11) << 2;
-                data[0] = data[0] | e << 1;
-                data[0] = data[0] | s << 0;
+        if input_data.len() < mapped_length {
+            log::warn!(target: &self.log_target, "RPDO{} node {} not processed due to length error: received {} bytes, expected {}", rpdo_number, self.node_id, input_data.len(), mapped_length);
+            if self.emcy_on_pdo_length_error {
+                self.activate_error(0x8210);
+                self.send_emcy(0x8210, [0; 5]).await;
+            }
+            return;
+        } else if input_data.len() > mapped_length {
+            log::warn!(target: &self.log_target, "RPDO{} node {} length exceeded: received {} bytes, expected {}", rpdo_number, self.node_id, input_data.len(), mapped_length);
+            if self.emcy_on_pdo_length_error {
+                self.activate_error(0x8220);
+                self.send_emcy(0x8220, [0; 5]).await;
+            }
+        } else {
+            // A correctly sized RPDO ends a previous length error
+            let cleared = self.clear_error(0x8210) | self.clear_error(0x8220);
+            if cleared && self.active_errors.is_empty() {
+                self.send_emcy(0x0000, [0; 5]).await;
+            }
+        }
+        self.counters.rpdos_applied.fetch_add(1, Ordering::Relaxed);
+
+        let mut data = input_data;
 
-                data[1..3].copy_from_slice(&input_index.to_le_bytes());
+        for object in &self.pdo_mappings.rpdos[rpdo] {
 
-                data[3] = input_sub_index;
+            let index_to_set = object.index;
+            let sub_index_to_set = object.sub_index;
+            let data_type = object.bits;
 
-                let cob = u16::from_str_radix("580", 16).unwrap();
-                let cob_id = CanId::new_base(cob | self.node_id as u16).unwrap();
+            // Every mapped object takes its own length from the frame, also when it can not be written
+            let length = (data_type as usize).div_ceil(8);
+            let field = &data[..length.min(data.len())];
+            data = drop_front(data, length);
 
-                let frame = &CanFrame::new(
-                    cob_id,
-                    &data,
-                    None,
-                )
-                .unwrap();
+            // Stop when there is no data left
+            if field.len() < length {
+                break;
+            }
+
+            if object.is_padding() {
+                continue;
+            }
 
-                if let Err(_) = self.socket.send(frame).await {
-                    log::error!("Error sending frame");
+            self.check_write_rules(index_to_set, sub_index_to_set, WriteSource::Rpdo(*rpdo_number));
+
+            let Some(var) = self.eds_data.od.get_mut(&index_to_set).and_then(|vars| vars.get_mut(&sub_index_to_set)) else {
+                log::warn!(target: &self.log_target, "RPDO{} node {} maps missing object 0x{:04X} sub {}", rpdo_number, self.node_id, index_to_set, sub_index_to_set);
+                continue;
+            };
+
+            match (data_type, &var.value) {
+                (0x08, DataValue::Unsigned8(_)) => {
+                    var.value = DataValue::Unsigned8(field[0]);
+                }
+                (0x08, DataValue::Integer8(_)) => {
+                    var.value = DataValue::Integer8(field[0] as i8);
+                }
+                (0x10, DataValue::Unsigned16(_)) => {
+                    var.value = DataValue::Unsigned16(u16::from_le_bytes([field[0], field[1]]));
+                }
+                (0x10, DataValue::Integer16(_)) => {
+                    var.value = DataValue::Integer16(i16::from_le_bytes([field[0], field[1]]));
+                }
+                (0x18, DataValue::Unsigned24(_) | DataValue::Integer24(_)) => {
+                    var.value = var.value.with_le_bytes(field).unwrap();
+                }
+                (0x20, DataValue::Unsigned32(_)) => {
+                    var.value = DataValue::Unsigned32(u32::from_le_bytes([field[0], field[1], field[2], field[3]]));
+                }
+                (0x20, DataValue::Integer32(_)) => {
+                    var.value = DataValue::Integer32(i32::from_le_bytes([field[0], field[1], field[2], field[3]]));
+                }
+                (0x20, DataValue::Real32(_)) => {
+                    var.value = DataValue::Real32(f32::from_le_bytes([field[0], field[1], field[2], field[3]]));
+                }
+                _ => {
+                    log::error!(target: &self.log_target, "Data type not implemented. Data type: 0x{:X}, data value: {:?}", data_type, var.value);
+                    continue;
                 }
+            };
 
+            self.notify_write(index_to_set, sub_index_to_set);
         }
+    }
+
+    /// Call the write callbacks of the object with its new value.
+    pub(crate) fn notify_write(&self, index: u16, sub_index: u8) {
+
+        let (Some(callbacks), Some(var)) = (self.write_callbacks.0.get(&(self.node_id, index, sub_index)), self.eds_data.get_var(index, sub_index)) else {
+            return;
+        };
 
+        for callback in callbacks {
+            if let Ok(mut callback) = callback.lock() {
+                callback(&var.value);
+            }
+        }
     }
 
-    async fn parse_rpdo(&mut self, rpdo_number: &u16, input_data: &[u8]) {
+    /// Send the TPDOs due on this SYNC. With a synchronous counter overflow value (0x1019) above 1 the SYNC carries a
+    /// counter and synchronous TPDOs (transmission type 1 to 240) are sent when the counter is a multiple of their type.
+    /// TPDOs that would be sent after the synchronous window length (0x1007) in µs since the SYNC was received are dropped.
+    async fn parse_sync(&mut self, data: &[u8], received: Duration) {
 
-        let mut enabled_sub_indices: u8 = 0;
-        let mut rpdo_indices: BTreeMap<u8, u32> = BTreeMap::new();
+        let window = Duration::from_micros(self.eds_data.get_f64(0x1007, 0).unwrap_or(0.) as u64);
+        let mut dropped = 0;
 
-        if let Some(var) = self.eds_data.od.get(&(0x1600 | (*rpdo_number - 1)))
-            .and_then(|vars| vars.get(&0)) {
-                match var.value {
-                    DataValue::Unsigned8(value) => {
-                        enabled_sub_indices = value;
-                    }
-                    _ => {},
-                }
+        // A SYNC without data has no counter, also when a counter is configured
+        let counter = match self.eds_data.get_var(0x1019, 0).map(|var| &var.value) {
+            Some(DataValue::Unsigned8(overflow)) if *overflow > 1 => data.first().copied(),
+            _ => None,
+        };
+
+        for tpdo_number in 0..self.pdo_mappings.tpdos.len() {
+
+            let Some(tpdo) = &self.pdo_mappings.tpdos[tpdo_number] else {
+                continue;
+            };
+            if !tpdo.enabled || tpdo.transmission_type > 240 || tpdo.objects.is_empty() {
+                continue;
             }
 
-        if let Some(vars) = self.eds_data.od.get(&(0x1600 | (*rpdo_number - 1))) {
-            for (sub_index, var) in vars.iter() {
-                if *sub_index != 0 as u8 {
-                    match var.value {
-                        DataValue::Unsigned32(value) => {
-                            rpdo_indices.insert(*sub_index, value);
-                        }
-                        _ => {},
-                    }
-                }
+            let data = self.tpdo_data(tpdo);
+            let Some(tpdo) = &mut self.pdo_mappings.tpdos[tpdo_number] else {
+                continue;
+            };
+            if !sync_due(tpdo, counter, &data) {
+                continue;
+            }
+
+            // A window length of zero disables the window
+            if !window.is_zero() && self.clock.now().saturating_sub(received) > window {
+                dropped += 1;
+                continue;
+            }
+
+            self.send_tpdo(tpdo_number, &data).await;
+            if let Some(tpdo) = &mut self.pdo_mappings.tpdos[tpdo_number] {
+                tpdo.last_sync_data = Some(data);
             }
         }
 
-        let mut data = input_data;
+        if dropped > 0 {
+            self.counters.sync_window_drops.fetch_add(dropped, Ordering::Relaxed);
+            log::warn!(target: &self.log_target, "Synchronous window of {} µs passed node {}, {} TPDOs not sent", window.as_micros(), self.node_id, dropped);
+        }
+    }
 
-        for i in 0..enabled_sub_indices {
-
-            if let Some(rpdo_index_value) = rpdo_indices.get(&(i + 1)) {
-
-                let index_to_set = (rpdo_index_value >> 16) as u16;
-                let sub_index_to_set = ((rpdo_index_value >> 8) & 0xFF) as u8;
-                let data_type = (rpdo_index_value & 0xFF) as u8;
-
-                if let Some(vars) = self.eds_data.od.get_mut(&index_to_set) {
-
-                    for (sub_index, var) in vars.iter_mut() {
-
-                        if sub_index == &sub_index_to_set {
-
-                            // Break loop when there is no data left
-                            if data == &[] {
-                                break;
-                            }
-
-                            match (data_type, &var.value) {
-                                (0x08, DataValue::Unsigned8(_)) => {
-                                    var.value = DataValue::Unsigned8(data[0]);
-                                    data = drop_front(data, 1);
-                                }
-                                (0x08, DataValue::Integer8(_)) => {
-                                    var.value = DataValue::Integer8(data[0] as i8);
-                                    data = drop_front(data, 1);
-                                }
-                                (0x10, DataValue::Unsigned16(_)) => {
-                                    var.value = DataValue::Unsigned16(u16::from_le_bytes([data[0], data[1]]));
-                                    data = drop_front(data, 2);
-                                }
-                                (0x10, DataValue::Integer16(_)) => {
-                                    var.value = DataValue::Integer16(i16::from_le_bytes([input_data[0], input_data[1]]));
-                                    data = drop_front(data, 2);
-                                }
-                                (0x20, DataValue::Unsigned32(_)) => {
-                                    var.value = DataValue::Unsigned32(u32::from_le_bytes([input_data[0], input_data[1], input_data[2], input_data[3]]));
-                                    data = drop_front(data, 4);
-                                }
-                                (0x20, DataValue::Integer32(_)) => {
-                                    var.value = DataValue::Integer32(i32::from_le_bytes([input_data[0], input_data[1], input_data[2], input_data[3]]));
-                                    data = drop_front(data, 4);
-                                }
-                                _ => log::error!("Data type not implemented. Data type: 0x{:X}, data value: {:?}", data_type, var.value)
-                            };
-                        }
-                    }
-                }
+    /// Send an EMCY frame with the given error code, the error register (0x1001) and manufacturer specific bytes.
+    pub async fn send_emcy(&self, error_code: u16, manufacturer: [u8; 5]) {
+
+        let mut error_register = 0;
+        if let Some(var) = self.eds_data.get_var(0x1001, 0) {
+            if let DataValue::Unsigned8(value) = var.value {
+                error_register = value;
             }
         }
+
+        let mut data: [u8; 8] = [0; 8];
+        data[0..2].copy_from_slice(&error_code.to_le_bytes());
+        data[2] = error_register;
+        data[3..].copy_from_slice(&manufacturer);
+
+        let cob_id = CanId::new_base(0x080 | self.node_id as u16).unwrap();
+
+        let frame = &CanFrame::new(
+            cob_id,
+            &data,
+            None,
+        )
+        .unwrap();
+
+        self.send_frame(frame).await;
+        self.counters.emcy_sent.fetch_add(1, Ordering::Relaxed);
+
+        log::info!(target: &self.log_target, "EMCY node {}: error code 0x{:04X}, error register 0x{:02X}", self.node_id, error_code, error_register);
+
     }
 
-    async fn parse_sync(&self) {
+    /// Activate an error, which is added to the error history (0x1003) and reflected in the error register (0x1001).
+    pub fn activate_error(&mut self, error_code: u16) {
 
-        let mut tpdos_enabled: BTreeMap<u16, bool> = BTreeMap::new();
-        let mut tpdos_sync_type: BTreeMap<u16, u8> = BTreeMap::new();
-        let mut number_of_entries: BTreeMap<u16, u8> = BTreeMap::new();
-        let mut tpdo_objects: BTreeMap<&u16, BTreeMap<u8, u32>> = BTreeMap::new();
+        if !self.active_errors.contains(&error_code) {
+            self.active_errors.push(error_code);
+        }
+        self.push_error_history(error_code);
+        self.update_error_register();
 
+    }
 
-        for i in 0..8 {
-            if let Some(vars) = self.eds_data.od.get(&(0x1800 + i)) {
-                for (sub_index, var) in vars.iter() {
+    /// Clear an active error, returns true when the error was active.
+    pub fn clear_error(&mut self, error_code: u16) -> bool {
 
-                    if *sub_index == 1 as u8 {
-                        match var.value {
-                            DataValue::Unsigned32(value) => {
-                                let tpdo_enabled = !((value & (1 << 31)) != 0);
-                                tpdos_enabled.insert(i, tpdo_enabled);
-                            }
-                            _ => {},
-                        }
-                    }
+        let active = self.active_errors.contains(&error_code);
+        self.active_errors.retain(|&code| code != error_code);
+        self.update_error_register();
 
-                    if *sub_index == 2 as u8 {
-                        match var.value {
-                            DataValue::Unsigned8(value) => {
-                                tpdos_sync_type.insert(i, value);
-                            }
-                            _ => {},
-                        }
-                    }
-                }
-            } 
-            
-            if let Some(vars) = self.eds_data.od.get(&(0x1A00 + i)) {
-                for (sub_index, var) in vars.iter() {
-                    if *sub_index == 0 as u8 {
-                        match var.value {
-                            DataValue::Unsigned8(value) => {
-                                number_of_entries.insert(i, value);
-                            }
-                            _ => {},
-                        }
-                    }
-                }
+        active
+    }
+
+    /// Clear all active errors.
+    pub fn clear_errors(&mut self) {
+
+        self.active_errors.clear();
+        self.update_error_register();
+
+    }
+
+    /// Send a frame on the bus of the node.
+    pub(crate) async fn send_frame(&self, frame: &CanFrame) {
+
+        if let Err(e) = self.socket.send(frame).await {
+            log::error!(target: &self.log_target, "Error sending frame node {}: {}", self.node_id, e);
+        } else {
+            self.counters.frames_sent.fetch_add(1, Ordering::Relaxed);
+        }
+
+    }
+
+    /// Set the error register (0x1001) from the classes of the active errors. The generic error bit is set whenever an error is active.
+    fn update_error_register(&mut self) {
+
+        let error_register = self.active_errors.iter()
+            .fold(0u8, |error_register, &error_code| error_register | 1 << error_register_bit(error_code) | 0x01);
+
+        self.eds_data.set_dataval(0x1001, 0, DataValue::Unsigned8(error_register));
+
+    }
+
+    /// Add an error code to the pre-defined error field (0x1003), the newest error at sub index 1.
+    pub fn push_error_history(&mut self, error_code: u16) {
+
+        let Some(vars) = self.eds_data.od.get(&0x1003) else {
+            return;
+        };
+
+        let size = vars.keys().filter(|&&sub_index| sub_index != 0).count() as u8;
+        if size == 0 {
+            return;
+        }
+
+        for sub_index in (2..=size).rev() {
+            if let Some(DataValue::Unsigned32(previous)) = self.eds_data.get_var(0x1003, sub_index - 1).map(|var| var.value.clone()) {
+                self.eds_data.set_dataval(0x1003, sub_index, DataValue::Unsigned32(previous));
             }
         }
+        self.eds_data.set_dataval(0x1003, 1, DataValue::Unsigned32(error_code as u32));
 
-        for tpdo_number in tpdos_enabled.keys() {
-            if let (Some(tpdo_enabled), Some(sync_type), Some(number_of_entries)) = (tpdos_enabled.get(tpdo_number), tpdos_sync_type.get(tpdo_number), number_of_entries.get(tpdo_number)) {
-                if *tpdo_enabled && *sync_type == 255 {
-
-                    if let Some(vars) = self.eds_data.od.get(&(0x1A00 + tpdo_number)) {
-                    
-                        for i in 1..(*number_of_entries + 1) {
-
-                            for (sub_index, var) in vars.iter() {
-                                if *sub_index == i as u8 {
-                                    match var.value {
-                                        DataValue::Unsigned32(value) => {
-                                            tpdo_objects.entry(tpdo_number)
-                                                .or_insert_with(BTreeMap::new)
-                                                .insert(i, value);  
-                                        }
-                                        _ => {},
-                                    }
-                                }
-                            }  
-                        }
-                    }
-                }
+        let number_of_errors = self.eds_data.get_f64(0x1003, 0).unwrap_or(0.) as u8;
+        self.eds_data.set_dataval(0x1003, 0, DataValue::Unsigned8((number_of_errors + 1).min(size)));
+
+    }
+
+    /// Clear the pre-defined error field (0x1003).
+    pub fn clear_error_history(&mut self) {
+
+        let sub_indices: Vec<u8> = match self.eds_data.od.get(&0x1003) {
+            Some(vars) => vars.keys().copied().filter(|&sub_index| sub_index != 0).collect(),
+            None => return,
+        };
+
+        for sub_index in sub_indices {
+            self.eds_data.set_dataval(0x1003, sub_index, DataValue::Unsigned32(0));
+        }
+        self.eds_data.set_dataval(0x1003, 0, DataValue::Unsigned8(0));
+
+        log::info!(target: &self.log_target, "Error history node {} cleared", self.node_id);
+
+    }
+
+    /// Load the stored parameters when the parameter file exists, the EDS defaults are kept when it can not be loaded.
+    fn load_parameters(&mut self) {
+
+        if !self.parameter_file.exists() {
+            return;
+        }
+
+        match self.eds_data.load_parameters(self.node_id, &self.parameter_file) {
+            Ok(()) => log::info!(target: &self.log_target, "Parameters node {} loaded from {}", self.node_id, self.parameter_file.display()),
+            Err(e) => {
+                log::error!(target: &self.log_target, "Failed to load parameters node {} from {}, using the defaults: {e}", self.node_id, self.parameter_file.display());
+                self.eds_data = self.default_eds_data.clone();
             }
         }
 
+    }
+
+    /// Store the parameters to the parameter file (0x1010).
+    pub fn store_parameters(&self) -> Result<(), ()> {
 
-        for tpdo_object_nr in tpdo_objects.keys() {
-
-            let mut data_to_send: Vec<u8> = Vec::new();
-
-            if let Some(tpdo_sub_indices) = tpdo_objects.get(tpdo_object_nr) {
-
-                let tpdo_number = tpdo_object_nr;
-
-                for sub_index in tpdo_sub_indices.keys() {
-
-                    if let Some(tpdo_content) = tpdo_sub_indices.get(sub_index) {
-
-                        let index_to_find = (tpdo_content >> 16) as u16;
-                        let sub_index_to_find = ((tpdo_content >> 8) & 0xFF) as u8;
-                        let data_type = (tpdo_content & 0xFF) as u8;
-
-                        if let Some(vars) = self.eds_data.od.get(&index_to_find) {
-                            for (sub_index, var) in vars.iter() {
-
-                                if sub_index == &sub_index_to_find {
-
-                                    match data_type {
-                                        0x08 => match var.value {
-                                            DataValue::Unsigned8(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            DataValue::Integer8(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            _ => {},
-                                        }
-                                        0x10 => match var.value {
-                                            DataValue::Unsigned16(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            DataValue::Integer16(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            _ => {},
-                                        }
-                                        0x20 => match var.value {
-                                            DataValue::Unsigned32(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            DataValue::Integer32(value) => {
-                                                data_to_send.extend(&value.to_le_bytes())
-                                            }
-                                            _ => {},
-                                        }
-                                        _ => {},
-                                        
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
-    
-                let functions_code = u16::from_str_radix(format!("{}80", *tpdo_number + 1).as_str(), 16).unwrap();
-                let cob_id = CanId::new_base(functions_code | self.node_id as u16).unwrap();
-    
-                let frame = &CanFrame::new(
-                    cob_id,
-                    &data_to_send.as_slice(),
-                    None,
-                )
-                .unwrap();
-
-                if let Err(_) = self.socket.send(frame).await {
-                    log::error!("Error sending frame");
-                }
+        self.eds_data.write_parameters(&self.parameter_file, &[0x1010, 0x1011])
+            .map_err(|e| log::error!(target: &self.log_target, "Failed to store parameters node {} to {}: {e}", self.node_id, self.parameter_file.display()))?;
+        log::info!(target: &self.log_target, "Parameters node {} stored to {}", self.node_id, self.parameter_file.display());
+
+        Ok(())
+    }
+
+    /// Restore the parameters to the EDS defaults and remove the stored parameters (0x1011).
+    pub fn restore_default_parameters(&mut self) -> Result<(), ()> {
+
+        match std::fs::remove_file(&self.parameter_file) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                log::error!(target: &self.log_target, "Failed to remove parameters node {} from {}: {e}", self.node_id, self.parameter_file.display());
+                return Err(());
             }
         }
+
+        self.eds_data = self.default_eds_data.clone();
+        self.update_pdo_mappings();
+        self.update_error_register();
+        log::info!(target: &self.log_target, "Parameters node {} restored to the defaults", self.node_id);
+
+        Ok(())
+    }
+
+    /// An EMCY on the own COB-ID is sent by another device with the same node id.
+    fn parse_emcy(&self, data: &[u8]) {
+
+        let error_code = if data.len() >= 2 { u16::from_le_bytes([data[0], data[1]]) } else { 0 };
+        log::warn!(target: &self.log_target, "EMCY with COB-ID of node {} received from another device: error code 0x{:04X}", self.node_id, error_code);
+
+    }
+
+}
+
+/// Whether a frame has the CAN-ID of a COB-ID: with bit 29 set the extended CAN-ID in bits 0 to 28, otherwise the base
+/// CAN-ID in bits 0 to 10. Extended frames are ignored unless a COB-ID selects them.
+pub(crate) fn cob_id_matches(cob_id: u32, id: CanId) -> bool {
+    match id {
+        CanId::Base(id) => cob_id & (1 << 29) == 0 && id.as_u16() as u32 == cob_id & 0x7FF,
+        CanId::Extended(id) => cob_id & (1 << 29) != 0 && id.as_u32() == cob_id & 0x1FFF_FFFF,
+    }
+}
+
+/// Time since 1984-01-01 of the system time.
+fn system_time_of_day() -> Duration {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default()
+        .saturating_sub(Duration::from_secs(TIME_OF_DAY_EPOCH_DAYS * 86_400))
+}
+
+/// Log target of a node. Filters match the target as a prefix, `twin::node::1` also selects nodes 10 to 19.
+pub fn log_target(node_id: u8) -> String {
+    format!("twin::node::{}", node_id)
+}
+
+/// Bit of the error register (0x1001) for the class of an error code.
+fn error_register_bit(error_code: u16) -> u8 {
+    match error_code >> 12 {
+        0x2 => 1,
+        0x3 => 2,
+        0x4 => 3,
+        0x8 => 4,
+        0xF => 7,
+        _ => 0,
     }
+}
 
-    async fn parse_emcy(&mut self) {
+/// State of a node as reported in the NMT state messages.
+pub fn nmt_state_code(nmt_state: &NmtState) -> u8 {
+    match nmt_state {
+        NmtState::Initializing => 0x00,
+        NmtState::Stopped => 0x04,
+        NmtState::Operational => 0x05,
+        NmtState::PreOperational => 0x7f,
+    }
+}
+
+/// Forward the frames received on a bus to its nodes, which subscribe to the sender.
+/// Frames are received on a separate task, so reception never waits for an update of a node, and timestamped when they
+/// arrive on the clock of the twin.
+pub async fn receive_frames(socket: Arc<BusSocket>, sender: broadcast::Sender<SocketMessage>, clock: Arc<dyn Clock>) {
+
+    loop {
+
+        let message = match socket.recv().await {
+            Ok(frame) => SocketMessage::Frame(frame, clock.now()),
+            // The nodes keep running while the socket is bound again, they stop when the interface does not return
+            Err(e) if bus::is_interface_down(&e) => {
+                log::error!("CAN interface {} is down: {e}, reconnecting", socket.interface());
+                match socket.reconnect().await {
+                    Ok(()) => {
+                        log::info!("CAN interface {} reconnected", socket.interface());
+                        continue;
+                    }
+                    Err(e) => {
+                        log::error!("CAN interface {} not available: {e}, its nodes are stopped", socket.interface());
+                        sender.send(SocketMessage::Closed).ok();
+                        return;
+                    }
+                }
+            }
+            Err(e) => SocketMessage::Error(e.to_string()),
+        };
 
-        println!("Emcy");
+        // Without subscribed nodes the frame is dropped
+        sender.send(message).ok();
 
     }
 
 }
 
+/// Whether a synchronous TPDO is sent on a SYNC with the counter. An acyclic TPDO (type 0) is sent when its data changed
+/// since it was last sent, a cyclic TPDO (type 1 to 240) on every n-th SYNC. Without a counter in the SYNC the TPDO
+/// counts the SYNCs itself.
+fn sync_due(tpdo: &mut Tpdo, counter: Option<u8>, data: &[u8]) -> bool {
+    match (tpdo.transmission_type, counter) {
+        (0, _) => tpdo.last_sync_data.as_deref() != Some(data),
+        (sync_type @ 1..=240, Some(counter)) => counter % sync_type == 0,
+        (sync_type @ 1..=240, None) => {
+            tpdo.sync_count += 1;
+            if tpdo.sync_count >= sync_type {
+                tpdo.sync_count = 0;
+                true
+            } else {
+                false
+            }
+        }
+        _ => false,
+    }
+}
+
 fn drop_front(slice: &[u8], count: usize) -> &[u8] {
     if count > slice.len() {
         &[]
     } else {
         &slice[count..]
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use can_socket::{CanFrame, CanId};
+    use tokio::sync::broadcast;
+
+    use super::{receive_frames, SocketMessage};
+    use crate::bus::{BusSocket, MockBus};
+    use crate::clock::ManualClock;
+    use crate::config;
+    use crate::testing::{TestTwin, EDS_FILE};
+
+    /// Start node 1 with TPDO 2 (position actual value, 0x281) at the transmission type.
+    async fn start_with_tpdo2(transmission_type: u8) -> TestTwin {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1801, 2, &[transmission_type]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+        twin
+    }
+
+    #[tokio::test]
+    async fn cyclic_tpdo_counts_syncs_without_counter() {
+        let twin = start_with_tpdo2(3).await;
+
+        for _ in 0..7 {
+            twin.push(0x080, &[]);
+        }
+        assert_eq!(twin.sent_with_id(1, 0x281).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn cyclic_tpdo_follows_sync_counter() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1019, 0, &[8]).await.unwrap();
+        twin.write(1, 0x1801, 2, &[2]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        // Sent on the even counters only
+        for counter in [1, 2, 3, 5, 6] {
+            twin.push(0x080, &[counter]);
+        }
+        assert_eq!(twin.sent_with_id(1, 0x281).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn acyclic_tpdo_is_sent_when_its_data_changed() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1800, 2, &[0]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        twin.push(0x080, &[]);
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x181).await.len(), 1);
+
+        // The statusword changes with the state of the drive
+        twin.write(1, 0x6040, 0, &0x06u16.to_le_bytes()).await.unwrap();
+        twin.advance(2).await;
+        twin.push(0x080, &[]);
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x181).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn event_driven_tpdo_is_not_sent_on_sync() {
+        let twin = start_with_tpdo2(255).await;
+
+        twin.push(0x080, &[]);
+        twin.push(0x080, &[]);
+        assert!(twin.sent_with_id(1, 0x281).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn short_rpdo_raises_length_error_emcy() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        // RPDO 1 maps the controlword and the mode of operation, 3 bytes
+        twin.push(0x201, &[0x06]);
+        let emcys = twin.sent_with_id(1, 0x081).await;
+        assert_eq!(emcys.len(), 1);
+        assert_eq!(emcys[0][0..2], 0x8210u16.to_le_bytes());
+        assert_ne!(emcys[0][2] & (1 << 4), 0);
+        assert_ne!(twin.read_i32(1, 0x1001, 0).await & (1 << 4), 0);
+    }
+
+    #[tokio::test]
+    async fn pre_operational_stops_the_tpdos_and_keeps_the_drive_state() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1801, 2, &[1]).await.unwrap();
+        twin.enable(1).await;
+        twin.start_move(1, 4000, false).await;
+        twin.advance(100).await;
+        twin.push(0x080, &[]);
+        assert!(!twin.sent_with_id(1, 0x281).await.is_empty());
+
+        twin.nmt(0x80, 1);
+        twin.sent(1).await;
+        twin.advance(100).await;
+        twin.push(0x080, &[]);
+        let sent = twin.sent(1).await;
+        assert!(sent.iter().all(|frame| !(0x181..=0x4FF).contains(&frame.id().as_u32())));
+
+        // Operation enabled
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x27);
+    }
+
+    #[tokio::test]
+    async fn pre_operational_quick_stops_when_configured() {
+        let mut node = config::Node::new(1, EDS_FILE);
+        node.quick_stop_on_pre_operational = true;
+        let twin = TestTwin::start_nodes(vec![node]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        twin.nmt(0x80, 1);
+        twin.sent(1).await;
+
+        // Quick stop active, the axis slows down while it moves on
+        let mut ramp = Vec::new();
+        for _ in 0..10 {
+            twin.advance(1).await;
+            assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x07);
+            ramp.push((twin.read_i32(1, 0x606C, 0).await, twin.read_i32(1, 0x6064, 0).await));
+        }
+        assert!(ramp.windows(2).all(|pair| pair[1].0 < pair[0].0 && pair[1].1 > pair[0].1), "{ramp:?}");
+
+        // Switch on disabled once stopped with the quick stop option code 2 of the EDS, the controlword does not enable again
+        twin.advance(500).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x40);
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+    }
+
+    #[tokio::test]
+    async fn received_frames_are_timestamped_on_the_clock() {
+        let bus = MockBus::new();
+        let clock = ManualClock::new();
+        let socket = Arc::new(BusSocket::new("vcan0", Arc::new(bus.clone()), None));
+        let (sender, mut receiver) = broadcast::channel(8);
+        tokio::spawn(receive_frames(socket, sender, Arc::new(clock.clone())));
+
+        let sync = CanFrame::new(CanId::new_base(0x080).unwrap(), &[], None).unwrap();
+        clock.advance(Duration::from_millis(5)).await;
+        bus.push(sync);
+        let Ok(SocketMessage::Frame(_, received)) = receiver.recv().await else {
+            panic!("no frame received");
+        };
+        assert_eq!(received, Duration::from_millis(5));
+    }
+
+    #[tokio::test]
+    async fn sync_window_is_measured_on_the_clock() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1007, 0, &1u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1801, 2, &[1]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        // The clock does not advance while the node handles the SYNC, so the TPDO is within the window of 1 µs
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x281).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn broadcast_start_starts_all_nodes() {
+        let twin = TestTwin::start(&[1, 2]).await;
+        twin.sent(1).await;
+        twin.sent(2).await;
+
+        twin.nmt(0x01, 0);
+        let mut sent = twin.sent(1).await;
+        sent.extend(twin.sent(2).await);
+
+        // Both nodes report their new NMT state operational
+        for node_id in [1, 2] {
+            let state = sent.iter().rev().find(|frame| frame.id() == CanId::new_base(0x700 + node_id).unwrap());
+            assert_eq!(state.map(|frame| frame.data().to_vec()), Some(vec![0x05]), "node {node_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn time_producer_runs_on_the_clock() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.nmt(0x80, 1);
+        // Consumer and producer of the TIME on 0x100
+        twin.write(1, 0x1012, 0, &0xC000_0100u32.to_le_bytes()).await.unwrap();
+
+        // Day 10 at 1000 ms
+        let time = |milliseconds: u32| [milliseconds.to_le_bytes().as_slice(), &10u16.to_le_bytes()].concat();
+        twin.push(0x100, &time(1000));
+        twin.sent(1).await;
+
+        // Sent on the first update and every second after, advanced by the time of the clock
+        twin.advance(2500).await;
+        assert_eq!(twin.sent_with_id(1, 0x100).await, vec![time(1001), time(2001), time(3001)]);
+    }
+}
@@ -1,8 +1,52 @@
-use std::time::{Instant, Duration};
+use std::time::Duration;
 use std::collections::HashMap;
 
+
 use crate::cia301::Node;
 use crate::eds::DataValue;
+use crate::profile::MotionProfile;
+
+/// Encoder increments per motor rotation, when the position encoder resolution (0x608F) is not set
+const INC_PER_ROT: f64 = 3600.0;
+
+/// Manufacturer object to simulate the touch probe input, a positive edge on a write of a non-zero value
+const TOUCH_PROBE_INPUT: u16 = 0x2FF0;
+
+/// Manufacturer object to simulate the digital inputs, mirrored to the digital inputs (0x60FD)
+const DIGITAL_INPUTS_INPUT: u16 = 0x2FF1;
+
+/// Manufacturer object to inject a fault, a write of a non-zero error code drives the state machine to Fault
+const FAULT_INPUT: u16 = 0x2FF2;
+
+/// Manufacturer object to simulate the main supply, the voltage enabled bit of the statusword is set while non-zero
+const MAIN_SUPPLY_INPUT: u16 = 0x2FF3;
+
+/// Manufacturer object to inject a warning, the warning bit of the statusword is set while non-zero
+const WARNING_INPUT: u16 = 0x2FF4;
+
+/// Manufacturer object to place the axis at a position in user units without a move, rejected while the axis moves
+pub const SET_POSITION_INPUT: u16 = 0x2FF6;
+
+/// Bit of the manufacturer status register (0x1002) set in the fault reaction and fault states
+const STATUS_REGISTER_FAULT: u32 = 1 << 0;
+
+/// Bit of the manufacturer status register (0x1002) set while a warning is active
+const STATUS_REGISTER_WARNING: u32 = 1 << 1;
+
+/// Bit of the manufacturer status register (0x1002) set while the main supply is off
+const STATUS_REGISTER_NO_MAIN_SUPPLY: u32 = 1 << 2;
+
+/// Jerk limit of a profile position move in rotations per second cubed, when the profile jerk (0x60A4) is not set
+const DEFAULT_JERK: f64 = 10.0;
+
+/// Number of profile position setpoints that can be buffered while moving
+const SETPOINT_QUEUE_SIZE: usize = 4;
+
+/// Duration of a simulated homing run
+const HOMING_DURATION: Duration = Duration::from_millis(100);
+
+/// EMCY error code of a profile position move that can not be planned from the profile parameters: parameter error
+const MOVE_PARAMETER_ERROR: u16 = 0x6320;
 
 /// Operation mode
 #[derive(Default, Debug, PartialEq, Clone)]
@@ -11,7 +55,9 @@ pub enum ModeOfOperation {
 	NoMode = 0,
 	ProfilePosition = 1,
 	ProfileVelocity = 3,
+	ProfileTorque = 4,
 	Homing = 6,
+	CyclicSynchronousTorque = 10,
 }
 
 /// Controlword
@@ -51,6 +97,99 @@ pub enum ProfilePositionStatus {
     Moving,
 }
 
+/// Profile position setpoint, captured on the rising edge of the new setpoint bit
+#[derive(Debug)]
+pub struct Setpoint {
+    /// Target in increments in the motor direction
+    pub target_position: f64,
+    /// Target is relative to the position at the start of the move
+    pub relative: bool,
+}
+
+/// Profile position move planned from the actual position to the end position, evaluated at the time since its start
+#[derive(Clone, Copy, Debug)]
+pub struct PositionMove {
+    /// Positions in increments in the motor direction
+    start_position: f64,
+    end_position: f64,
+    increments_per_rotation: f64,
+    /// Profile in rotations, None for a move without travel
+    profile: Option<MotionProfile>,
+}
+
+impl PositionMove {
+    /// Position at the time in seconds since the start of the move, the end position once the move is done.
+    pub fn position(&self, time: f64) -> f64 {
+        match self.profile {
+            Some(profile) if time < profile.duration() => {
+                let direction = (self.end_position - self.start_position).signum();
+                self.start_position + direction * profile.position(time) * self.increments_per_rotation
+            }
+            _ => self.end_position,
+        }
+    }
+}
+
+/// Timing of a motion in simulation time: a profile position move, a velocity ramp or a homing run
+#[derive(Default, Debug)]
+pub struct Motion {
+    /// Simulation time at the start of the motion
+    start: Duration,
+    /// Planned duration of the motion
+    duration: Duration,
+    /// Simulation time the motion was halted by the halt bit (controlword bit 8)
+    halted: Option<Duration>,
+}
+
+impl Motion {
+    /// Start a motion of the planned duration at the simulation time.
+    pub fn start(&mut self, time: Duration, duration: Duration) {
+        self.start = time;
+        self.duration = duration;
+        self.halted = None;
+    }
+
+    /// Halt or release the motion at the simulation time. A halted motion does not advance, once released it continues
+    /// where it was halted. Returns true when a halt is released.
+    pub fn halt(&mut self, halt: bool, time: Duration) -> bool {
+        match (halt, self.halted) {
+            (true, None) => {
+                self.halted = Some(time);
+                false
+            }
+            (false, Some(halted)) => {
+                self.start += time.saturating_sub(halted);
+                self.halted = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted.is_some()
+    }
+
+    /// Simulation time since the start of the motion, without the time it was halted.
+    pub fn elapsed(&self, time: Duration) -> Duration {
+        self.halted.unwrap_or(time).saturating_sub(self.start)
+    }
+
+    /// Whether the planned duration has passed at the simulation time.
+    pub fn finished(&self, time: Duration) -> bool {
+        self.elapsed(time) >= self.duration
+    }
+
+    /// Fraction of the planned duration that has passed at the simulation time, 1 once the motion is finished.
+    pub fn progress(&self, time: Duration) -> f64 {
+        if self.finished(time) {
+            1.
+        } else {
+            self.elapsed(time).as_secs_f64() / self.duration.as_secs_f64()
+        }
+    }
+}
+
 /// Homing status
 #[derive(Default, Debug)]
 pub enum ProfileVelocityStatus {
@@ -68,15 +207,25 @@ pub enum HomeStatus {
 }
 
 impl ModeOfOperation {
-    fn mode_of_operation(value: i8) -> ModeOfOperation {
+    pub fn from_i8(value: i8) -> Option<ModeOfOperation> {
         match value {
-            0 => ModeOfOperation::NoMode,
-            1 => ModeOfOperation::ProfilePosition,
-            3 => ModeOfOperation::ProfileVelocity,
-            6 => ModeOfOperation::Homing,
-            _ => panic!("Mode of operation not implemented")
+            0 => Some(ModeOfOperation::NoMode),
+            1 => Some(ModeOfOperation::ProfilePosition),
+            3 => Some(ModeOfOperation::ProfileVelocity),
+            4 => Some(ModeOfOperation::ProfileTorque),
+            6 => Some(ModeOfOperation::Homing),
+            10 => Some(ModeOfOperation::CyclicSynchronousTorque),
+            _ => None,
         }
     }
+
+    /// Supported drive modes (0x6502) of the implemented modes, bit 0 for mode 1 up to bit 9 for mode 10.
+    fn supported_drive_modes() -> u32 {
+        (1..=10)
+            .filter(|&value| ModeOfOperation::from_i8(value).is_some())
+            .map(|value| 1 << (value - 1))
+            .sum()
+    }
 }
 
 impl Node {
@@ -85,56 +234,143 @@ impl Node {
 
         if let Some(var) = self.eds_data.od.get(&0x6060)
             .and_then(|vars| vars.get(&0)) {
-                match var.value {
-                    DataValue::Integer8(value) => {
-                        self.motor_controller.mode_of_operation = ModeOfOperation::mode_of_operation(value);
+                if let DataValue::Integer8(value) = var.value {
+                    match ModeOfOperation::from_i8(value) {
+                        Some(mode) => self.motor_controller.mode_of_operation = mode,
+                        None => {
+                            // Without a supported mode the drive does not move
+                            if self.motor_controller.mode_of_operation != ModeOfOperation::NoMode {
+                                log::warn!(target: &self.log_target, "Mode of operation {} not implemented on node {}", value, self.node_id);
+                            }
+                            self.motor_controller.mode_of_operation = ModeOfOperation::NoMode;
+                        }
                     }
-                    _ => {},
                 }
             }
 
         if let Some(var) = self.eds_data.od.get(&0x6040)
             .and_then(|vars| vars.get(&0)) {
-                match var.value {
-                    DataValue::Unsigned16(value) => {
-                        self.motor_controller.controlword = value;
-                    }
-                    _ => {},
+                if let DataValue::Unsigned16(value) = var.value {
+                    self.motor_controller.controlword = value;
                 }
             }
 
+        // Time since the previous update
+        let cycle_time = self.cycle_time;
+        self.motor_controller.time += cycle_time;
+
+        // Fault written to the manufacturer object
+        let error_code = self.eds_data.get_f64(FAULT_INPUT, 0).unwrap_or(0.) as u16;
+        if error_code != 0 {
+            self.eds_data.set_dataval(FAULT_INPUT, 0, DataValue::Unsigned16(0));
+            self.inject_fault(error_code).await;
+        }
+
+        // Main supply and warning written to the manufacturer objects
+        self.motor_controller.voltage_enabled = self.eds_data.get_f64(MAIN_SUPPLY_INPUT, 0).unwrap_or(1.) != 0.;
+        self.motor_controller.warning = self.eds_data.get_f64(WARNING_INPUT, 0).unwrap_or(0.) != 0.
+            || self.active_errors.contains(&MOVE_PARAMETER_ERROR);
+
         // Do logic based on input
         self.parse_controlword();
+        let was_fault = self.motor_controller.state == State::Fault;
         self.update_state();
 
+        // Fault reset clears the error register and is reported with an error reset EMCY
+        if was_fault && self.motor_controller.state != State::Fault {
+            log::info!(target: &self.log_target, "Fault reset node {}", self.node_id);
+            self.clear_errors();
+            self.send_emcy(0x0000, [0; 5]).await;
+        }
+
+        // Motion only continues while operation is enabled, after enabling again a move needs a new setpoint
+        if self.motor_controller.state != State::OperationEnabled {
+            self.abort_motion();
+        }
+
         match (&self.motor_controller.mode_of_operation, &self.motor_controller.state) {
 
             (ModeOfOperation::ProfilePosition, State::OperationEnabled) => {
 
+                // A halted move continues to its end position once released, from where the axis came to a stop
+                if self.halt_motion() && matches!(self.motor_controller.profile_position_status, ProfilePositionStatus::Moving) {
+                    if let Some(position_move) = self.motor_controller.position_move {
+                        self.start_move(&Setpoint { target_position: position_move.end_position, relative: false }).await;
+                    }
+                }
+
                 match &self.motor_controller.profile_position_status {
 
                     ProfilePositionStatus::SetpointAcknownlegde => {
 
                         self.motor_controller.status_oms1 = true;
                         self.motor_controller.target_reached = true;
+                        self.motor_controller.actual_velocity = 0.;
+                        self.motor_controller.target_velocity = 0.;
 
                         if self.motor_controller.control_oms1[0] && !self.motor_controller.control_oms1[1] {
+                            match self.new_setpoint() {
+                                Ok(setpoint) => self.start_move(&setpoint).await,
+                                Err(e) => log::error!(target: &self.log_target, "Failed to read setpoint node {}: {}", self.node_id, e),
+                            }
+                        }
+                    }
 
-                            self.motor_controller.timer = Some(Instant::now());
-                            self.motor_controller.profile_position_status = ProfilePositionStatus::Moving
+                    ProfilePositionStatus::Moving if self.motor_controller.motion.is_halted() => {
+
+                        // The axis slows down with the profile deceleration and reports target reached at standstill
+                        self.motor_controller.target_velocity = 0.;
+                        self.motor_controller.target_reached = self.decelerate(self.deceleration(0x6084), cycle_time);
 
-                        }
                     }
 
                     ProfilePositionStatus::Moving => {
 
-                        self.motor_controller.status_oms1 = false;
                         self.motor_controller.target_reached = false;
 
-                        if self.motor_controller.timer.unwrap().elapsed() > Duration::from_millis(100) {
-                            self.motor_controller.profile_position_status = ProfilePositionStatus::SetpointAcknownlegde
+                        let previous_position = self.motor_controller.actual_position;
+                        let finished = self.motion_finished();
+
+                        if let Some(position_move) = &self.motor_controller.position_move {
+                            self.motor_controller.actual_position = position_move.position(self.elapsed().as_secs_f64());
+                        }
+
+                        if self.motor_controller.control_oms1[0] && !self.motor_controller.control_oms1[1] {
+
+                            // A new setpoint interrupts the move with change set immediately, otherwise it is queued
+                            match self.new_setpoint() {
+                                Ok(setpoint) if get_bit_16(&self.motor_controller.controlword, 5) => {
+                                    self.motor_controller.setpoint_queue.clear();
+                                    self.start_move(&setpoint).await;
+                                }
+                                Ok(setpoint) if self.motor_controller.setpoint_queue.len() < SETPOINT_QUEUE_SIZE => {
+                                    self.motor_controller.setpoint_queue.push_back(setpoint);
+                                }
+                                Ok(_) => log::warn!(target: &self.log_target, "Setpoint queue node {} full, new setpoint ignored", self.node_id),
+                                Err(e) => log::error!(target: &self.log_target, "Failed to read setpoint node {}: {}", self.node_id, e),
+                            }
+
+                        } else if finished {
+
+                            match self.motor_controller.setpoint_queue.pop_front() {
+                                Some(setpoint) => self.start_move(&setpoint).await,
+                                None => self.motor_controller.profile_position_status = ProfilePositionStatus::SetpointAcknownlegde,
+                            }
+
                         }
 
+                        // Setpoint acknowledge while a queued setpoint is waiting
+                        self.motor_controller.status_oms1 = !self.motor_controller.setpoint_queue.is_empty();
+
+                        // Velocity in rpm from the position change since the previous update
+                        if !cycle_time.is_zero() {
+                            let rotations = (self.motor_controller.actual_position - previous_position) / self.increments_per_rotation();
+                            self.motor_controller.actual_velocity = rotations / cycle_time.as_secs_f64() * 60.;
+                            self.motor_controller.target_velocity = self.motor_controller.actual_velocity;
+                        }
+
+                        log::debug!(target: &self.log_target, "Actual position node {}: {}", self.node_id, self.position_polarity() * self.motor_controller.actual_position / self.factor(0x6093));
+
                     }
 
                 }
@@ -143,28 +379,40 @@ impl Node {
 
             (ModeOfOperation::ProfileVelocity, State::OperationEnabled) => {
 
+                let requested_velocity = self.unless_halted(self.factor(0x6094) * self.eds_data.get_f64(0x60FF, 0).unwrap_or(0.));
+                let limited_velocity = self.limit_velocity(requested_velocity);
+                self.motor_controller.internal_limit_active = limited_velocity != requested_velocity;
+                let target_velocity = self.velocity_polarity() * limited_velocity;
+
                 match &self.motor_controller.profile_velocity_status {
 
                     ProfileVelocityStatus::WaitingForStart => {
 
                         self.motor_controller.target_reached = false;
+                        self.motor_controller.velocity_window_entered = None;
+                        self.start_velocity_ramp(target_velocity);
+                        self.motor_controller.profile_velocity_status = ProfileVelocityStatus::Moving
 
-                        if !&self.motor_controller.halt {
-
-                            self.motor_controller.timer = Some(Instant::now());
-                            self.motor_controller.profile_velocity_status = ProfileVelocityStatus::Moving
-
-                        }
                     }
 
                     ProfileVelocityStatus::Moving => {
 
-                        self.motor_controller.target_reached = true;
-
-                        if self.motor_controller.timer.unwrap().elapsed() > Duration::from_millis(100) {
-                            self.motor_controller.profile_velocity_status = ProfileVelocityStatus::WaitingForStart
+                        // Restart the ramp when the target velocity changes
+                        if target_velocity != self.motor_controller.target_velocity {
+                            self.start_velocity_ramp(target_velocity);
                         }
 
+                        // Linear ramp from the velocity at the start of the ramp to the target velocity
+                        self.motor_controller.actual_velocity = if self.motion_finished() {
+                            self.motor_controller.target_velocity
+                        } else {
+                            let start_velocity = self.motor_controller.ramp_start_velocity;
+                            let progress = self.motor_controller.motion.progress(self.motor_controller.time);
+                            start_velocity + (self.motor_controller.target_velocity - start_velocity) * progress
+                        };
+
+                        self.motor_controller.target_reached = self.velocity_reached();
+
                     }
 
                 }
@@ -173,6 +421,9 @@ impl Node {
 
             (ModeOfOperation::Homing, State::OperationEnabled) => {
 
+                // A halted homing run pauses
+                self.halt_motion();
+
                 match &self.motor_controller.home_status {
 
                     HomeStatus::WaitingForStart => {
@@ -181,7 +432,8 @@ impl Node {
                         self.motor_controller.status_oms2 = false;
 
                         if self.motor_controller.control_oms1[0] && !self.motor_controller.control_oms1[1] {
-                            self.motor_controller.timer = Some(Instant::now());
+                            log::debug!(target: &self.log_target, "Homing node {} started", self.node_id);
+                            self.start_motion(HOMING_DURATION);
                             self.motor_controller.home_status = HomeStatus::Homing
                         }
                     }
@@ -191,8 +443,9 @@ impl Node {
                         self.motor_controller.status_oms1 = false;
                         self.motor_controller.status_oms2 = false;
 
-                        if self.motor_controller.timer.unwrap().elapsed() > Duration::from_millis(100) {
+                        if self.motion_finished() {
 
+                            log::debug!(target: &self.log_target, "Homing node {} completed", self.node_id);
                             self.motor_controller.target_reached = true;
                             self.motor_controller.status_oms1 = true;
                             self.motor_controller.status_oms2 = false;
@@ -204,43 +457,631 @@ impl Node {
                 }
             }
 
+            (ModeOfOperation::ProfileTorque | ModeOfOperation::CyclicSynchronousTorque, State::OperationEnabled) => {
+
+                let requested_torque = self.unless_halted(self.eds_data.get_f64(0x6071, 0).unwrap_or(0.));
+                let target_torque = self.limit_torque(requested_torque);
+                self.motor_controller.internal_limit_active = target_torque != requested_torque;
+
+                self.torque_ramp(target_torque, cycle_time);
+                self.motor_controller.target_reached = self.motor_controller.actual_torque == target_torque;
+
+            }
+
+            (_, State::QuickStopActive) => {
+
+                // The motion in progress is aborted, the axis decelerates with the quick stop deceleration (0x6085)
+                let deceleration = self.deceleration(0x6085);
+                self.motor_controller.target_velocity = 0.;
+                self.motor_controller.target_reached = self.decelerate(deceleration, cycle_time);
+
+            }
+
+            (_, State::FaultReactionActive) => {
+
+                // The motion in progress is aborted, the axis only decelerates
+                self.motor_controller.target_reached = false;
+
+                if self.fault_reaction(cycle_time) {
+                    log::info!(target: &self.log_target, "Fault reaction node {} completed", self.node_id);
+                    self.motor_controller.state = State::Fault;
+                }
+
+            }
+
             _ => {},
         }
+
+        // Limits only apply to position, velocity and torque motion
+        if !matches!(self.motor_controller.mode_of_operation, ModeOfOperation::ProfilePosition | ModeOfOperation::ProfileVelocity
+                | ModeOfOperation::ProfileTorque | ModeOfOperation::CyclicSynchronousTorque)
+            || self.motor_controller.state != State::OperationEnabled {
+            self.motor_controller.internal_limit_active = false;
+        }
+
+        // Without torque control the motor produces no torque
+        if !matches!(self.motor_controller.mode_of_operation, ModeOfOperation::ProfileTorque | ModeOfOperation::CyclicSynchronousTorque)
+            || self.motor_controller.state != State::OperationEnabled {
+            self.motor_controller.actual_torque = 0.;
+        }
         
+        // A coupled axis takes the motion of the axis it follows
+        self.couple_axis();
+
+        self.update_touch_probe();
+        self.update_digital_io();
         self.set_statusword();
 
         // Adjust eds according to motor controller status
         if let Some(var) = self.eds_data.od.get_mut(&0x6061)
             .and_then(|vars| vars.get_mut(&0)) {
-                match var.value {
-                    DataValue::Integer8(_) => var.value = DataValue::Integer8(self.motor_controller.mode_of_operation.clone() as i8),
-                    _ => {},
+                if let DataValue::Integer8(_) = var.value {
+                    var.value = DataValue::Integer8(self.motor_controller.mode_of_operation.clone() as i8);
                 }
             }
 
         if let Some(var) = self.eds_data.od.get_mut(&0x6041)
             .and_then(|vars| vars.get_mut(&0)) {
-                match var.value {
-                    DataValue::Unsigned16(_) => var.value = DataValue::Unsigned16(self.motor_controller.statusword),
-                    _ => {},
+                if let DataValue::Unsigned16(_) = var.value {
+                    var.value = DataValue::Unsigned16(self.motor_controller.statusword);
+                }
+            }
+        self.eds_data.set_dataval(0x1002, 0, DataValue::Unsigned32(self.manufacturer_status_register()));
+
+        // Position in user units, the demand follows the trajectory without following error, only the actual value has noise
+        let position = self.position_actual_value();
+        let noisy_position = self.add_position_noise(position);
+        self.eds_data.set_dataval(0x6062, 0, DataValue::Integer32(position));
+        self.eds_data.set_dataval(0x6063, 0, DataValue::Integer32(self.motor_controller.actual_position.round() as i32));
+        self.eds_data.set_dataval(0x6064, 0, DataValue::Integer32(noisy_position));
+
+        // Velocity in user units, the demand is the target of the velocity ramp
+        let velocity_factor = self.velocity_polarity() / self.factor(0x6094);
+        self.eds_data.set_dataval(0x606B, 0, DataValue::Integer32((velocity_factor * self.motor_controller.target_velocity).round() as i32));
+        self.eds_data.set_dataval(0x606C, 0, DataValue::Integer32((velocity_factor * self.motor_controller.actual_velocity).round() as i32));
+
+        // Torque and current in per thousand of the rated torque and current
+        let torque = self.motor_controller.actual_torque.round() as i16;
+        self.eds_data.set_dataval(0x6074, 0, DataValue::Integer16(torque));
+        self.eds_data.set_dataval(0x6077, 0, DataValue::Integer16(torque));
+        self.eds_data.set_dataval(0x6078, 0, DataValue::Integer16(torque));
+
+        // Objects changed by the update, like the statusword and mode of operation display, are sent by the event driven TPDOs
+        self.send_event_tpdos().await;
+
+    }
+
+    /// Manufacturer status register (0x1002): bit 0 fault, bit 1 warning and bit 2 main supply off.
+    fn manufacturer_status_register(&self) -> u32 {
+
+        let mut status_register = 0;
+        if matches!(self.motor_controller.state, State::FaultReactionActive | State::Fault) {
+            status_register |= STATUS_REGISTER_FAULT;
+        }
+        if self.motor_controller.warning {
+            status_register |= STATUS_REGISTER_WARNING;
+        }
+        if !self.motor_controller.voltage_enabled {
+            status_register |= STATUS_REGISTER_NO_MAIN_SUPPLY;
+        }
+
+        status_register
+    }
+
+    /// Abort the motion of the modes of operation and ignore the new setpoint and homing start bit (controlword bit 4)
+    /// until it changes, so a rising edge while operation is not enabled does not start a move. The axis stands still,
+    /// except in quick stop active and fault reaction active which decelerate it.
+    fn abort_motion(&mut self) {
+
+        if !matches!(self.motor_controller.state, State::QuickStopActive | State::FaultReactionActive) {
+            self.motor_controller.actual_velocity = 0.;
+            self.motor_controller.target_velocity = 0.;
+        }
+
+        self.motor_controller.profile_position_status = ProfilePositionStatus::SetpointAcknownlegde;
+        self.motor_controller.profile_velocity_status = ProfileVelocityStatus::WaitingForStart;
+        self.motor_controller.home_status = HomeStatus::WaitingForStart;
+        self.motor_controller.setpoint_queue.clear();
+
+        let new_setpoint = self.motor_controller.control_oms1[0];
+        self.motor_controller.control_oms1.iter_mut().for_each(|bit| *bit = new_setpoint);
+
+    }
+
+    /// Start the motion of the mode of operation at the current simulation time.
+    fn start_motion(&mut self, duration: Duration) {
+        self.motor_controller.motion.start(self.motor_controller.time, duration);
+    }
+
+    /// Simulation time since the start of the current motion.
+    fn elapsed(&self) -> Duration {
+        self.motor_controller.motion.elapsed(self.motor_controller.time)
+    }
+
+    /// Whether the planned duration of the current motion has passed.
+    fn motion_finished(&self) -> bool {
+        self.motor_controller.motion.finished(self.motor_controller.time)
+    }
+
+    /// Apply the halt bit (controlword bit 8) to the motion of a profile position move or homing run, see
+    /// [`Motion::halt`]. Returns true when the halt is released.
+    fn halt_motion(&mut self) -> bool {
+        self.motor_controller.motion.halt(self.motor_controller.halt, self.motor_controller.time)
+    }
+
+    /// Target of a mode that follows a setpoint, zero while the halt bit (controlword bit 8) is set so the axis
+    /// ramps to standstill and resumes once the bit is cleared.
+    fn unless_halted(&self, target: f64) -> f64 {
+        if self.motor_controller.halt { 0. } else { target }
+    }
+
+    /// Position actual value in user units.
+    fn position_actual_value(&self) -> i32 {
+        self.user_position(self.motor_controller.actual_position)
+    }
+
+    /// Add the configured encoder noise to a position in user units.
+    fn add_position_noise(&mut self, position: i32) -> i32 {
+        if self.position_noise_stddev == 0. {
+            return position;
+        }
+        let noise = self.position_noise_stddev * self.position_noise.next_gaussian();
+        (position as f64 + noise).round().clamp(i32::MIN as f64, i32::MAX as f64) as i32
+    }
+
+    /// Position in increments in the motor direction converted to user units.
+    fn user_position(&self, position: f64) -> i32 {
+        (self.position_polarity() * position / self.factor(0x6093)).round() as i32
+    }
+
+    /// Set the supported drive modes (0x6502) to the modes of operation the twin implements, also when the EDS defines other modes.
+    pub fn add_supported_drive_modes(&mut self) {
+        let supported_drive_modes = DataValue::Unsigned32(ModeOfOperation::supported_drive_modes());
+        self.eds_data.insert_var_if_missing(0x6502, 0, "Supported drive modes", "ro", supported_drive_modes.clone());
+        self.eds_data.set_dataval(0x6502, 0, supported_drive_modes);
+    }
+
+    /// Add the touch probe objects and the simulated touch probe input when the EDS does not define them.
+    pub fn add_touch_probe_objects(&mut self) {
+        self.eds_data.insert_var_if_missing(0x60B8, 0, "Touch probe function", "rw", DataValue::Unsigned16(0));
+        self.eds_data.insert_var_if_missing(0x60B9, 0, "Touch probe status", "ro", DataValue::Unsigned16(0));
+        self.eds_data.insert_var_if_missing(0x60BA, 0, "Touch probe pos1 pos value", "ro", DataValue::Integer32(0));
+        self.eds_data.insert_var_if_missing(0x60BB, 0, "Touch probe pos1 neg value", "ro", DataValue::Integer32(0));
+        self.eds_data.insert_var_if_missing(TOUCH_PROBE_INPUT, 0, "Simulated touch probe input", "rw", DataValue::Unsigned8(0));
+    }
+
+    /// Latch the position on edges of the simulated touch probe input, as configured by the touch probe function (0x60B8).
+    fn update_touch_probe(&mut self) {
+
+        let function = self.eds_data.get_f64(0x60B8, 0).unwrap_or(0.) as u16;
+        let mut status = self.eds_data.get_f64(0x60B9, 0).unwrap_or(0.) as u16;
+        let input = self.eds_data.get_f64(TOUCH_PROBE_INPUT, 0).unwrap_or(0.) != 0.;
+
+        let enabled = get_bit_16(&function, 0);
+        let continuous = get_bit_16(&function, 1);
+
+        if !enabled {
+            status = 0;
+        } else if !get_bit_16(&status, 0) {
+            // Newly enabled, clear the stored edges
+            status = set_bit_16(&0, 0, true);
+        }
+
+        if enabled && input != self.motor_controller.touch_probe_input {
+
+            let (sample_bit, stored_bit, index) = if input { (4, 1, 0x60BA) } else { (5, 2, 0x60BB) };
+
+            if get_bit_16(&function, sample_bit) && (continuous || !get_bit_16(&status, stored_bit)) {
+                let position = self.position_actual_value();
+                self.eds_data.set_dataval(index, 0, DataValue::Integer32(position));
+                status = set_bit_16(&status, stored_bit, true);
+                log::info!(target: &self.log_target, "Touch probe node {} latched position {} on {} edge", self.node_id, position, if input { "positive" } else { "negative" });
+            }
+        }
+
+        self.motor_controller.touch_probe_input = input;
+        self.eds_data.set_dataval(0x60B9, 0, DataValue::Unsigned16(status));
+
+    }
+
+    /// Add the digital inputs and outputs and the simulated digital inputs when the EDS does not define them.
+    pub fn add_digital_io_objects(&mut self, digital_inputs: u32) {
+        self.eds_data.insert_var_if_missing(0x60FD, 0, "Digital inputs", "ro", DataValue::Unsigned32(0));
+        self.eds_data.insert_var_if_missing(0x60FE, 0, "Digital outputs", "ro", DataValue::Unsigned8(2));
+        self.eds_data.insert_var_if_missing(0x60FE, 1, "Physical outputs", "rw", DataValue::Unsigned32(0));
+        self.eds_data.insert_var_if_missing(0x60FE, 2, "Bit mask", "rw", DataValue::Unsigned32(0xFFFF_FFFF));
+        self.eds_data.insert_var_if_missing(DIGITAL_INPUTS_INPUT, 0, "Simulated digital inputs", "rw", DataValue::Unsigned32(0));
+        self.eds_data.set_dataval(DIGITAL_INPUTS_INPUT, 0, DataValue::Unsigned32(digital_inputs));
+        self.eds_data.set_dataval(0x60FD, 0, DataValue::Unsigned32(digital_inputs));
+    }
+
+    /// Digital inputs (0x60FD), bit 0 negative limit switch, bit 1 positive limit switch and bit 2 home switch.
+    pub fn digital_inputs(&self) -> u32 {
+        self.eds_data.get_f64(0x60FD, 0).unwrap_or(0.) as u32
+    }
+
+    /// Mirror the simulated digital inputs to 0x60FD and report changes of the digital outputs (0x60FE).
+    fn update_digital_io(&mut self) {
+
+        let digital_inputs = self.eds_data.get_f64(DIGITAL_INPUTS_INPUT, 0).unwrap_or(0.) as u32;
+        if digital_inputs != self.digital_inputs() {
+            log::info!(target: &self.log_target, "Digital inputs node {}: 0x{:08X}", self.node_id, digital_inputs);
+            self.eds_data.set_dataval(0x60FD, 0, DataValue::Unsigned32(digital_inputs));
+        }
+
+        let physical_outputs = self.eds_data.get_f64(0x60FE, 1).unwrap_or(0.) as u32;
+        let bit_mask = self.eds_data.get_f64(0x60FE, 2).unwrap_or(u32::MAX as f64) as u32;
+        let digital_outputs = physical_outputs & bit_mask;
+        if digital_outputs != self.motor_controller.digital_outputs {
+            log::info!(target: &self.log_target, "Digital outputs node {}: 0x{:08X}", self.node_id, digital_outputs);
+            self.motor_controller.digital_outputs = digital_outputs;
+        }
+
+    }
+
+    /// Add the manufacturer objects to inject a fault or a warning and to switch the main supply when the EDS does not define them.
+    pub fn add_fault_injection_objects(&mut self) {
+        self.eds_data.insert_var_if_missing(FAULT_INPUT, 0, "Simulated fault", "rw", DataValue::Unsigned16(0));
+        self.eds_data.insert_var_if_missing(MAIN_SUPPLY_INPUT, 0, "Simulated main supply", "rw", DataValue::Unsigned8(1));
+        self.eds_data.insert_var_if_missing(WARNING_INPUT, 0, "Simulated warning", "rw", DataValue::Unsigned8(0));
+    }
+
+    /// Add the manufacturer object to place the axis at a position when the EDS does not define it.
+    pub fn add_set_position_object(&mut self) {
+        self.eds_data.insert_var_if_missing(SET_POSITION_INPUT, 0, "Simulated actual position", "rw", DataValue::Integer32(0));
+    }
+
+    /// Place the axis at a position in user units without a move, for example to set up a test. Fails while a profile
+    /// position move, homing or velocity ramp is in progress.
+    pub(crate) fn set_actual_position(&mut self, position: i32) -> Result<(), ()> {
+
+        let moving = matches!(self.motor_controller.profile_position_status, ProfilePositionStatus::Moving)
+            || matches!(self.motor_controller.home_status, HomeStatus::Homing)
+            || self.motor_controller.actual_velocity != 0.;
+        if moving {
+            log::warn!(target: &self.log_target, "Position node {} not set to {}: the axis is moving", self.node_id, position);
+            return Err(());
+        }
+
+        self.motor_controller.actual_position = self.position_polarity() * self.factor(0x6093) * position as f64;
+        let position = self.position_actual_value();
+        self.eds_data.set_dataval(0x6062, 0, DataValue::Integer32(position));
+        self.eds_data.set_dataval(0x6063, 0, DataValue::Integer32(self.motor_controller.actual_position.round() as i32));
+        self.eds_data.set_dataval(0x6064, 0, DataValue::Integer32(position));
+        log::info!(target: &self.log_target, "Position node {} set to {}", self.node_id, position);
+
+        Ok(())
+    }
+
+    /// Add the manufacturer object with the duration and end position of the last planned move when the EDS does not define it.
+    pub fn add_move_diagnostics_objects(&mut self) {
+        let index = self.move_diagnostics_index;
+        self.eds_data.insert_var_if_missing(index, 0, "Number of entries", "ro", DataValue::Unsigned8(2));
+        self.eds_data.insert_var_if_missing(index, 1, "Planned move duration", "ro", DataValue::Unsigned32(0));
+        self.eds_data.insert_var_if_missing(index, 2, "Planned end position", "ro", DataValue::Integer32(0));
+    }
+
+    /// Write the duration in ms and the end position in user units of a planned move to the move diagnostics object.
+    fn set_move_diagnostics(&mut self, duration: f64, end_position: f64) {
+        let index = self.move_diagnostics_index;
+        let end_position = self.user_position(end_position);
+        self.eds_data.set_dataval(index, 1, DataValue::Unsigned32((duration * 1000.).round() as u32));
+        self.eds_data.set_dataval(index, 2, DataValue::Integer32(end_position));
+    }
+
+    /// Report a fault with an EMCY and the error history and start the fault reaction.
+    pub async fn inject_fault(&mut self, error_code: u16) {
+
+        log::warn!(target: &self.log_target, "Fault node {}: error code 0x{:04X}", self.node_id, error_code);
+
+        self.activate_error(error_code);
+        self.send_emcy(error_code, [0; 5]).await;
+
+        if !matches!(self.motor_controller.state, State::FaultReactionActive | State::Fault) {
+            self.motor_controller.state = State::FaultReactionActive;
+        }
+
+    }
+
+    /// Capture the target position (0x607A) and the relative bit of the controlword as a new setpoint.
+    fn new_setpoint(&self) -> Result<Setpoint, String> {
+
+        let target_position = self.eds_data.get_f64(0x607A, 0).ok_or("Target position (0x607A) not available")?;
+
+        Ok(Setpoint {
+            target_position: self.position_polarity() * self.factor(0x6093) * target_position,
+            relative: get_bit_16(&self.motor_controller.controlword, 6),
+        })
+    }
+
+    /// Start a move to the setpoint from the actual position. A move that can not be planned, for example without a
+    /// profile acceleration, sets the warning bit and is reported with a parameter error EMCY until a move starts.
+    async fn start_move(&mut self, setpoint: &Setpoint) {
+
+        match self.plan_move(setpoint) {
+            Ok(duration) => {
+                self.start_motion(duration);
+                // A move without travel is reached at once, unless queued setpoints follow it
+                self.motor_controller.profile_position_status = if duration.is_zero() && self.motor_controller.setpoint_queue.is_empty() {
+                    ProfilePositionStatus::SetpointAcknownlegde
+                } else {
+                    ProfilePositionStatus::Moving
+                };
+                if self.clear_error(MOVE_PARAMETER_ERROR) && self.active_errors.is_empty() {
+                    self.send_emcy(0x0000, [0; 5]).await;
+                }
+            }
+            Err(e) => {
+                log::warn!(target: &self.log_target, "Failed to start move node {}: {}", self.node_id, e);
+                self.motor_controller.setpoint_queue.clear();
+                self.motor_controller.profile_position_status = ProfilePositionStatus::SetpointAcknownlegde;
+                if !self.active_errors.contains(&MOVE_PARAMETER_ERROR) {
+                    self.activate_error(MOVE_PARAMETER_ERROR);
+                    self.send_emcy(MOVE_PARAMETER_ERROR, [0; 5]).await;
                 }
             }
+        }
+    }
+
+    /// Plan a profile position move to the setpoint from the actual position and return the duration of the move.
+    fn plan_move(&mut self, setpoint: &Setpoint) -> Result<Duration, String> {
+
+        // Convert from user units to rpm and rpm/s
+        let requested_velocity = self.factor(0x6094) * self.eds_data.get_f64(0x6081, 0).ok_or("Profile velocity (0x6081) not available")?;
+        let profile_velocity = self.limit_velocity(requested_velocity);
+        let acceleration = self.factor(0x6097) * self.eds_data.get_f64(0x6083, 0).ok_or("Profile acceleration (0x6083) not available")?;
+        let deceleration = match self.eds_data.get_f64(0x6084, 0) {
+            Some(deceleration) if deceleration > 0. => self.factor(0x6097) * deceleration,
+            _ => acceleration,
+        };
+        let jerk = self.factor(0x60A2) * self.eds_data.get_f64(0x60A4, 1).unwrap_or(0.);
+        let increments_per_rotation = self.increments_per_rotation();
+
+        if profile_velocity == 0. {
+            return Err("Profile velocity (0x6081) is zero".to_string());
+        }
+        if acceleration == 0. {
+            return Err("Profile acceleration (0x6083) is zero".to_string());
+        }
+
+        let actual_position = self.motor_controller.actual_position;
+        let requested_position = if setpoint.relative {
+            actual_position + setpoint.target_position
+        } else {
+            setpoint.target_position
+        };
+        let end_position = self.limit_position(requested_position);
+
+        // The move stays limited until the next setpoint
+        self.motor_controller.internal_limit_active = profile_velocity != requested_velocity || end_position != requested_position;
 
+        if end_position == actual_position {
+            self.motor_controller.position_move = Some(PositionMove { start_position: actual_position, end_position, increments_per_rotation, profile: None });
+            self.set_move_diagnostics(0., end_position);
+            return Ok(Duration::ZERO);
+        }
+
+        // Velocity in rpm, acceleration and deceleration in rpm/s and jerk in rpm/s², converted to rotations per second
+        let profile = MotionProfile::new(
+            (end_position - actual_position).abs() / increments_per_rotation,
+            profile_velocity / 60.,
+            acceleration / 60.,
+            deceleration / 60.,
+            if jerk > 0. { jerk / 60. } else { DEFAULT_JERK },
+        );
+
+        let total_duration = profile.duration();
+        let duration = Duration::try_from_secs_f64(total_duration).map_err(|e| format!("Move duration of {} s: {e}", total_duration))?;
+        self.motor_controller.position_move = Some(PositionMove { start_position: actual_position, end_position, increments_per_rotation, profile: Some(profile) });
+        self.set_move_diagnostics(total_duration, end_position);
+
+        log::debug!(target: &self.log_target, "Move node {} from {} to {}, total duration: {} s", self.node_id, actual_position, end_position, total_duration);
+
+        Ok(duration)
+    }
+
+    /// Start a ramp from the actual velocity to the target velocity using the profile acceleration, or the profile
+    /// deceleration (0x6084) when slowing down or reversing.
+    fn start_velocity_ramp(&mut self, target_velocity: f64) {
+
+        let actual_velocity = self.motor_controller.actual_velocity;
+        let slowing_down = target_velocity.abs() < actual_velocity.abs() || target_velocity * actual_velocity < 0.;
+        let acceleration = if slowing_down {
+            self.deceleration(0x6084)
+        } else {
+            let mut acceleration = self.eds_data.get_f64(0x6083, 0).unwrap_or(0.);
+            if acceleration == 0. {
+                acceleration = self.eds_data.get_f64(0x60C5, 0).unwrap_or(0.);
+            }
+            self.factor(0x6097) * acceleration
+        };
+
+        let velocity_difference = (target_velocity - self.motor_controller.actual_velocity).abs();
+        let duration = if acceleration == 0. {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(velocity_difference / acceleration)
+        };
+
+        self.motor_controller.target_velocity = target_velocity;
+        self.motor_controller.ramp_start_velocity = self.motor_controller.actual_velocity;
+        self.start_motion(duration);
+
+    }
+
+    /// The velocity is reached when the actual velocity stays within the velocity window (0x606D) around the target velocity
+    /// for the velocity window time (0x606E) in ms.
+    fn velocity_reached(&mut self) -> bool {
+
+        let window = self.factor(0x6094) * self.eds_data.get_f64(0x606D, 0).unwrap_or(0.);
+        let window_time = Duration::from_millis(self.eds_data.get_f64(0x606E, 0).unwrap_or(0.) as u64);
+
+        if (self.motor_controller.actual_velocity - self.motor_controller.target_velocity).abs() > window {
+            self.motor_controller.velocity_window_entered = None;
+            return false;
+        }
+
+        let entered = *self.motor_controller.velocity_window_entered.get_or_insert(self.motor_controller.time);
+        self.motor_controller.time - entered >= window_time
+    }
+
+    /// Decelerate according to the fault reaction option code (0x605E), returns true at standstill.
+    fn fault_reaction(&mut self, cycle_time: Duration) -> bool {
+
+        // 0: disable drive, 1: slow down ramp, 2 and higher: quick stop ramp
+        let deceleration = match self.eds_data.get_f64(0x605E, 0).unwrap_or(2.) as i16 {
+            0 => 0.,
+            1 => self.deceleration(0x6084),
+            _ => self.deceleration(0x6085),
+        };
+
+        self.motor_controller.target_velocity = 0.;
+        self.decelerate(deceleration, cycle_time)
+
+    }
+
+    /// Whether the drive stays in quick stop active after the axis stopped, quick stop option code (0x605A) 5 to 8.
+    fn quick_stop_holds(&self) -> bool {
+        (5. ..=8.).contains(&self.eds_data.get_f64(0x605A, 0).unwrap_or(2.))
+    }
+
+    /// Deceleration in rpm/s of the profile (0x6084) or quick stop (0x6085) deceleration, the max deceleration (0x60C6)
+    /// when it is zero.
+    fn deceleration(&self, index: u16) -> f64 {
+
+        let mut deceleration = self.eds_data.get_f64(index, 0).unwrap_or(0.);
+        if deceleration == 0. {
+            deceleration = self.eds_data.get_f64(0x60C6, 0).unwrap_or(0.);
+        }
+
+        self.factor(0x6097) * deceleration
+    }
+
+    /// Ramp the actual velocity to zero with a deceleration in rpm/s and follow the position, returns true at standstill.
+    fn decelerate(&mut self, deceleration: f64, cycle_time: Duration) -> bool {
+
+        let velocity = self.motor_controller.actual_velocity;
+        let max_step = deceleration * cycle_time.as_secs_f64();
+
+        let new_velocity = if deceleration == 0. || velocity.abs() <= max_step {
+            0.
+        } else {
+            velocity - max_step.copysign(velocity)
+        };
+
+        // Average velocity in rpm over the cycle, converted to increments
+        let rotations = (velocity + new_velocity) / 2. / 60. * cycle_time.as_secs_f64();
+        self.motor_controller.actual_position += rotations * self.increments_per_rotation();
+        self.motor_controller.actual_velocity = new_velocity;
+
+        new_velocity == 0.
+
+    }
+
+    /// Move the actual torque towards the target torque, limited by the torque slope (0x6087) in per thousand per second.
+    fn torque_ramp(&mut self, target_torque: f64, cycle_time: Duration) {
+
+        let torque_slope = self.eds_data.get_f64(0x6087, 0).unwrap_or(0.);
+        let torque_difference = target_torque - self.motor_controller.actual_torque;
+        let max_step = torque_slope * cycle_time.as_secs_f64();
+
+        if torque_slope == 0. || torque_difference.abs() <= max_step {
+            self.motor_controller.actual_torque = target_torque;
+        } else {
+            self.motor_controller.actual_torque += max_step.copysign(torque_difference);
+        }
+
+    }
+
+    /// Sign of the position demand and actual values, inverted by bit 7 of the polarity (0x607E).
+    fn position_polarity(&self) -> f64 {
+        let polarity = self.eds_data.get_f64(0x607E, 0).unwrap_or(0.) as u8;
+        if get_bit_16(&(polarity as u16), 7) { -1. } else { 1. }
+    }
+
+    /// Sign of the velocity demand and actual values, inverted by bit 6 of the polarity (0x607E).
+    fn velocity_polarity(&self) -> f64 {
+        let polarity = self.eds_data.get_f64(0x607E, 0).unwrap_or(0.) as u8;
+        if get_bit_16(&(polarity as u16), 6) { -1. } else { 1. }
+    }
+
+    /// Saturate a velocity in rpm at the max profile velocity (0x607F) and max motor speed (0x6080).
+    fn limit_velocity(&self, velocity: f64) -> f64 {
+
+        let mut max_velocity = f64::MAX;
+        if let Some(max_profile_velocity) = self.eds_data.get_f64(0x607F, 0) {
+            max_velocity = max_velocity.min(self.factor(0x6094) * max_profile_velocity);
+        }
+        if let Some(max_motor_speed) = self.eds_data.get_f64(0x6080, 0) {
+            max_velocity = max_velocity.min(max_motor_speed);
+        }
+
+        velocity.clamp(-max_velocity, max_velocity)
+    }
+
+    /// Saturate a torque in per thousand of the rated torque at the max torque (0x6072) and the max current (0x6073),
+    /// the current is proportional to the torque.
+    fn limit_torque(&self, torque: f64) -> f64 {
+
+        let mut max_torque = f64::MAX;
+        if let Some(limit) = self.eds_data.get_f64(0x6072, 0) {
+            max_torque = max_torque.min(limit);
+        }
+        if let Some(max_current) = self.eds_data.get_f64(0x6073, 0) {
+            max_torque = max_torque.min(max_current);
+        }
+
+        torque.clamp(-max_torque, max_torque)
+    }
+
+    /// Saturate a position in increments at the software position limits (0x607D), disabled when the min limit is not below the max limit.
+    fn limit_position(&self, position: f64) -> f64 {
+
+        let (Some(min_position), Some(max_position)) = (self.eds_data.get_f64(0x607D, 1), self.eds_data.get_f64(0x607D, 2)) else {
+            return position;
+        };
+        if min_position >= max_position {
+            return position;
+        }
+
+        // Limits are in user units, a negative polarity swaps them
+        let scale = self.position_polarity() * self.factor(0x6093);
+        let (first, second) = (scale * min_position, scale * max_position);
+        position.clamp(first.min(second), first.max(second))
+    }
+
+    /// Numerator (sub index 1) divided by divisor (sub index 2) of a factor object, 1 when not set.
+    fn factor(&self, index: u16) -> f64 {
+        match (self.eds_data.get_f64(index, 1), self.eds_data.get_f64(index, 2)) {
+            (Some(numerator), Some(divisor)) if numerator != 0. && divisor != 0. => numerator / divisor,
+            _ => 1.,
+        }
+    }
+
+    /// Encoder increments per motor rotation from the position encoder resolution (0x608F).
+    fn increments_per_rotation(&self) -> f64 {
+        match (self.eds_data.get_f64(0x608F, 1), self.eds_data.get_f64(0x608F, 2)) {
+            (Some(increments), Some(revolutions)) if increments != 0. && revolutions != 0. => increments / revolutions,
+            _ => INC_PER_ROT,
+        }
     }
 
     fn parse_controlword(&mut self) {
 
-        const BIT_INDICES: [usize; 5] = [0, 1, 2, 3, 7];
-        
-        let bits: Vec<bool> = BIT_INDICES.iter().map(|&i| get_bit_16(&self.motor_controller.controlword, i)).collect();
-    
-        self.motor_controller.command = match (bits[4], bits[3], bits[2], bits[1], bits[0]) {
-            (false, _, true, true, false) => Command::Shutdown,
-            (false, false, true, true, true) => Command::SwitchOn,
-            (false, _, _, false, _) => Command::DisableVoltage,
-            (false, _, false, true, _) => Command::QuickStop,
-            (false, true, true, true, true) => Command::EnableOperation,
-            (true, _, _, _, _) => Command::FaultReset,
+        let controlword = self.motor_controller.controlword;
+
+        // A fault is reset on the rising edge of bit 7, holding the bit does not reset later faults
+        let fault_reset = get_bit_16(&controlword, 7);
+        let fault_reset_edge = fault_reset && !self.motor_controller.fault_reset;
+        self.motor_controller.fault_reset = fault_reset;
+
+        let command = decode_controlword(controlword, fault_reset_edge);
+
+        // Disable operation has the pattern of switch on and enable operation after quick stop the pattern of enable operation
+        self.motor_controller.command = match (&self.motor_controller.state, command) {
+            (State::OperationEnabled, Command::SwitchOn) => Command::DisableOperation,
+            (State::QuickStopActive, Command::EnableOperation) => Command::EnableOperationAfterQuickStop,
+            (_, command) => command,
         };
 
         self.motor_controller.control_oms1.push_front(get_bit_16(&self.motor_controller.controlword, 4));
@@ -251,35 +1092,41 @@ impl Node {
 
     fn update_state(&mut self) {
 
-
-        self.motor_controller.state = match self.motor_controller.state {
+        let state = match self.motor_controller.state {
             State::NotReadyToSwitchOn => State::SwitchedOnDisabled,
             State::SwitchedOnDisabled => match &self.motor_controller.command {
                 Command::Shutdown => State::ReadyToSwitchOn,
                 _ => State::SwitchedOnDisabled,
             }
             State::ReadyToSwitchOn => match &self.motor_controller.command {
-                Command::SwitchOn => State::SwitchedOn,
-                Command::DisableVoltage => State::SwitchedOnDisabled,
+                // Switch on and enable operation passes through switched on
+                Command::SwitchOn | Command::EnableOperation => State::SwitchedOn,
+                Command::DisableVoltage | Command::QuickStop => State::SwitchedOnDisabled,
                 _ => State::ReadyToSwitchOn,
             }
             State::SwitchedOn => match &self.motor_controller.command {
                 Command::EnableOperation => State::OperationEnabled,
                 Command::Shutdown => State::ReadyToSwitchOn,
+                Command::DisableVoltage | Command::QuickStop => State::SwitchedOnDisabled,
                 _ => State::SwitchedOn,
             }
             State::OperationEnabled => match &self.motor_controller.command {
                 Command::QuickStop => State::QuickStopActive,
                 Command::DisableVoltage => State::SwitchedOnDisabled,
-                Command::SwitchOn => State::SwitchedOn,
+                Command::DisableOperation => State::SwitchedOn,
+                Command::Shutdown => State::ReadyToSwitchOn,
                 _ => State::OperationEnabled,
             }
+            // The quick stop option code (0x605A) 0 to 4 leaves quick stop active once the axis stands still,
+            // 5 to 8 stay until operation is enabled again
             State::QuickStopActive => match &self.motor_controller.command {
                 Command::DisableVoltage => State::SwitchedOnDisabled,
-                Command::EnableOperationAfterQuickStop => State::OperationEnabled,
+                Command::EnableOperationAfterQuickStop if self.quick_stop_holds() => State::OperationEnabled,
+                _ if !self.quick_stop_holds() && self.motor_controller.actual_velocity == 0. => State::SwitchedOnDisabled,
                 _ => State::QuickStopActive,
             }
-            State::FaultReactionActive => State::Fault,
+            // Left for Fault once the fault reaction has stopped the axis
+            State::FaultReactionActive => State::FaultReactionActive,
             State::Fault => match &self.motor_controller.command {
                 Command::FaultReset => State::SwitchedOnDisabled,
                 _ => State::Fault,
@@ -287,6 +1134,11 @@ impl Node {
 
         };
 
+        if state != self.motor_controller.state {
+            log::debug!(target: &self.log_target, "State node {}: {:?} -> {:?} on {:?}", self.node_id, self.motor_controller.state, state, self.motor_controller.command);
+            self.motor_controller.state = state;
+        }
+
     }
     
     fn set_statusword(&mut self) {
@@ -305,13 +1157,31 @@ impl Node {
             set_bits(&mut self.motor_controller.statusword, bits);
         }
     
+        self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 4, self.motor_controller.voltage_enabled);
+        self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 7, self.motor_controller.warning);
+        self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 9, self.motor_controller.remote);
         self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 10, self.motor_controller.target_reached);
+        self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 11, self.motor_controller.internal_limit_active);
         self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 12, self.motor_controller.status_oms1);
         self.motor_controller.statusword = set_bit_16(&self.motor_controller.statusword, 13, self.motor_controller.status_oms2);
     }
 
 }
 
+/// Command of the controlword, a fault reset only on the rising edge of bit 7.
+/// Only bits 0 to 3 and 7 select the command, the other bits are mode specific or reserved and do not change the state.
+fn decode_controlword(controlword: u16, fault_reset_edge: bool) -> Command {
+    match (get_bit_16(&controlword, 7), get_bit_16(&controlword, 3), get_bit_16(&controlword, 2), get_bit_16(&controlword, 1), get_bit_16(&controlword, 0)) {
+        (false, _, true, true, false) => Command::Shutdown,
+        (false, false, true, true, true) => Command::SwitchOn,
+        (false, _, _, false, _) => Command::DisableVoltage,
+        (false, _, false, true, _) => Command::QuickStop,
+        (false, true, true, true, true) => Command::EnableOperation,
+        (true, _, _, _, _) if fault_reset_edge => Command::FaultReset,
+        (true, _, _, _, _) => Command::None,
+    }
+}
+
 fn get_bit_16(u16_value: &u16, index: usize) -> bool {
     let mask = 1 << index;
     (u16_value & mask) != 0
@@ -330,4 +1200,423 @@ fn set_bits(statusword: &mut u16, bits: &[(usize, bool)]) {
     for &(bit, value) in bits {
         *statusword = set_bit_16(statusword, bit, value);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::{decode_controlword, Command, PositionMove, DEFAULT_JERK};
+    use crate::profile::MotionProfile;
+    use crate::testing::TestTwin;
+
+    /// Manufacturer object with the planned duration (sub 1, ms) of the last move
+    const MOVE_DIAGNOSTICS: u16 = 0x2FF5;
+
+    /// Position actual value (0x6064) after every update of 1 ms until the target is reached (statusword bit 10).
+    async fn positions_until_reached(twin: &TestTwin, node_id: u8) -> Vec<i32> {
+        let mut positions = Vec::new();
+        for _ in 0..10_000 {
+            twin.advance(1).await;
+            positions.push(twin.read_i32(node_id, 0x6064, 0).await);
+            if twin.read_i32(node_id, 0x6041, 0).await & (1 << 10) != 0 {
+                return positions;
+            }
+        }
+        panic!("target not reached");
+    }
+
+    /// Velocity (0x606C) and position (0x6064) actual values after every update of 1 ms until the axis stands still.
+    async fn ramp_until_standstill(twin: &TestTwin, node_id: u8) -> Vec<(i32, i32)> {
+        let mut ramp = Vec::new();
+        for _ in 0..1000 {
+            twin.advance(1).await;
+            let velocity = twin.read_i32(node_id, 0x606C, 0).await;
+            ramp.push((velocity, twin.read_i32(node_id, 0x6064, 0).await));
+            if velocity == 0 {
+                break;
+            }
+        }
+        ramp
+    }
+
+    /// Whether the velocity falls by the deceleration of the ramp each update, in user units per ms, while the position
+    /// keeps moving forward.
+    fn decelerates_at(ramp: &[(i32, i32)], deceleration: i32) -> bool {
+        ramp.windows(2).all(|pair| {
+            let ((velocity, position), (next_velocity, next_position)) = (pair[0], pair[1]);
+            let step = velocity - next_velocity;
+            (next_velocity == 0 || (deceleration - 1..=deceleration + 1).contains(&step)) && step > 0 && next_position >= position
+        })
+    }
+
+    #[test]
+    fn controlword_commands() {
+        use Command::*;
+
+        // Commands of bits 3 to 0 without fault reset, CiA 402 table of the device control commands
+        let commands = [
+            DisableVoltage, DisableVoltage, QuickStop, QuickStop, DisableVoltage, DisableVoltage, Shutdown, SwitchOn,
+            DisableVoltage, DisableVoltage, QuickStop, QuickStop, DisableVoltage, DisableVoltage, Shutdown, EnableOperation,
+        ];
+
+        for (bits, command) in commands.into_iter().enumerate() {
+            let controlword = bits as u16;
+            assert_eq!(decode_controlword(controlword, false), command, "controlword 0x{controlword:04X}");
+            // The mode specific and reserved bits do not change the command
+            assert_eq!(decode_controlword(controlword | 0xFF70, false), command, "controlword 0x{:04X}", controlword | 0xFF70);
+
+            // Bit 7 resets a fault on its rising edge only, whatever the other bits
+            assert_eq!(decode_controlword(controlword | 0x80, true), FaultReset, "controlword 0x{:04X}", controlword | 0x80);
+            assert_eq!(decode_controlword(controlword | 0x80, false), None, "controlword 0x{:04X}", controlword | 0x80);
+        }
+    }
+
+    #[test]
+    fn only_the_command_bits_select_the_command() {
+        for controlword in 0..=u16::MAX {
+            for fault_reset_edge in [false, true] {
+                assert_eq!(decode_controlword(controlword, fault_reset_edge), decode_controlword(controlword & 0x8F, fault_reset_edge),
+                    "controlword 0x{controlword:04X}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reserved_bits_do_not_change_the_state() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.nmt(0x01, 1);
+        twin.advance(2).await;
+
+        // Every combination of the mode specific and reserved bits, with the command bits of disable voltage
+        for reserved in 0..1u16 << 11 {
+            let controlword = (reserved & 0x07) << 4 | (reserved >> 3) << 8;
+            twin.write(1, 0x6040, 0, &controlword.to_le_bytes()).await.unwrap();
+            twin.advance(1).await;
+            assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x40, "controlword 0x{controlword:04X}");
+        }
+
+        // Nor with enable operation
+        twin.enable(1).await;
+        for reserved in 0..1u16 << 11 {
+            let controlword = 0x0F | (reserved & 0x07) << 4 | (reserved >> 3) << 8;
+            twin.write(1, 0x6040, 0, &controlword.to_le_bytes()).await.unwrap();
+            twin.advance(1).await;
+            assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x27, "controlword 0x{controlword:04X}");
+        }
+    }
+
+    #[tokio::test]
+    async fn position_follows_the_profile_smoothly() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 4000, false).await;
+        let positions = positions_until_reached(&twin, 1).await;
+
+        // 500 rpm of 3600 increments per rotation is 30 increments per ms
+        assert!(positions.windows(2).all(|pair| (0..=31).contains(&(pair[1] - pair[0]))));
+        assert_eq!(positions.last(), Some(&4000));
+        let duration = twin.read_i32(1, MOVE_DIAGNOSTICS, 1).await as usize;
+        assert!(positions.len().abs_diff(duration) <= 2);
+    }
+
+    #[tokio::test]
+    async fn disabled_operation_stops_a_move_until_a_new_setpoint() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+        assert!(twin.read_i32(1, 0x606C, 0).await > 0);
+
+        // Disable operation mid-move
+        twin.write(1, 0x6040, 0, &0x07u16.to_le_bytes()).await.unwrap();
+        twin.advance(2).await;
+        let stopped = twin.read_i32(1, 0x6064, 0).await;
+        assert!(stopped > 0 && stopped < 4000);
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+
+        // Enabling again does not resume the move
+        twin.write(1, 0x6040, 0, &0x0Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(100).await;
+        assert_eq!(twin.read_i32(1, 0x6064, 0).await, stopped);
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+
+        // A new setpoint edge does
+        twin.start_move(1, 4000, false).await;
+        assert_eq!(positions_until_reached(&twin, 1).await.last(), Some(&4000));
+    }
+
+    #[tokio::test]
+    async fn halted_move_stops_and_continues_to_its_target() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        twin.write(1, 0x6040, 0, &0x10Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(500).await;
+        let halted = twin.read_i32(1, 0x6064, 0).await;
+        assert!(halted < 4000);
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+        assert_ne!(twin.read_i32(1, 0x6041, 0).await & (1 << 10), 0);
+        twin.advance(100).await;
+        assert_eq!(twin.read_i32(1, 0x6064, 0).await, halted);
+
+        twin.write(1, 0x6040, 0, &0x0Fu16.to_le_bytes()).await.unwrap();
+        assert_eq!(positions_until_reached(&twin, 1).await.last(), Some(&4000));
+    }
+
+    #[tokio::test]
+    async fn halted_homing_pauses() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.write(1, 0x6060, 0, &[6]).await.unwrap();
+        twin.advance(2).await;
+
+        twin.write(1, 0x6040, 0, &0x11Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(500).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & (1 << 12), 0);
+
+        twin.write(1, 0x6040, 0, &0x1Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(200).await;
+        assert_ne!(twin.read_i32(1, 0x6041, 0).await & (1 << 12), 0);
+    }
+
+    #[tokio::test]
+    async fn halted_velocity_ramps_to_standstill_and_back() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.write(1, 0x6060, 0, &[3]).await.unwrap();
+        twin.write(1, 0x60FF, 0, &100i32.to_le_bytes()).await.unwrap();
+        twin.advance(1000).await;
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 100);
+
+        twin.write(1, 0x6040, 0, &0x10Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(1000).await;
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+
+        twin.write(1, 0x6040, 0, &0x0Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(1000).await;
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 100);
+    }
+
+    #[tokio::test]
+    async fn gentle_deceleration_lengthens_the_move() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 4000, false).await;
+        let symmetric = positions_until_reached(&twin, 1).await.len();
+
+        twin.write(1, 0x6084, 0, &100u32.to_le_bytes()).await.unwrap();
+        twin.start_move(1, 0, false).await;
+        let positions = positions_until_reached(&twin, 1).await;
+
+        assert!(positions.len() > symmetric);
+        // The axis brakes over the second half of the move
+        let half = positions.len() / 2;
+        assert!(4000 - positions[half] > positions[half]);
+    }
+
+    #[tokio::test]
+    async fn mapped_torque_actual_value_follows_the_ramp() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // TPDO3 with the torque actual value, sent on every SYNC
+        twin.write(1, 0x1802, 1, &0x8000_0381u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1A02, 1, &0x6077_0010u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[1]).await.unwrap();
+        twin.write(1, 0x1802, 2, &[1]).await.unwrap();
+        twin.write(1, 0x1802, 1, &0x0000_0381u32.to_le_bytes()).await.unwrap();
+
+        twin.enable(1).await;
+        twin.write(1, 0x6060, 0, &[10]).await.unwrap();
+        // The maximum torque of 100 per mille is reached in 1 s with the torque slope of 100 per mille/s
+        twin.write(1, 0x6071, 0, &100i16.to_le_bytes()).await.unwrap();
+
+        let mut torques = Vec::new();
+        for _ in 0..12 {
+            twin.advance(100).await;
+            twin.sent(1).await;
+            twin.push(0x080, &[]);
+            let frames = twin.sent_with_id(1, 0x381).await;
+            assert_eq!(frames.len(), 1);
+            torques.push(i16::from_le_bytes(frames[0][..].try_into().unwrap()));
+        }
+
+        assert!(torques.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!((1..100).contains(&torques[4]));
+        assert_eq!(torques[11], 100);
+    }
+
+    #[tokio::test]
+    async fn relative_moves_in_both_directions() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+
+        twin.start_move(1, 3000, true).await;
+        assert_eq!(positions_until_reached(&twin, 1).await.last(), Some(&3000));
+
+        // A negative relative target moves back from the position reached
+        twin.start_move(1, -4000, true).await;
+        let positions = positions_until_reached(&twin, 1).await;
+        assert!(positions.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(positions.last(), Some(&-1000));
+
+        twin.start_move(1, 1500, true).await;
+        assert_eq!(positions_until_reached(&twin, 1).await.last(), Some(&500));
+    }
+
+    #[test]
+    fn backward_move_decreases_strictly_to_its_end() {
+        let profile = MotionProfile::new(4000. / 3600., 500. / 60., 500. / 60., 500. / 60., DEFAULT_JERK);
+        let position_move = PositionMove { start_position: 5000., end_position: 1000., increments_per_rotation: 3600., profile: Some(profile) };
+
+        let positions: Vec<f64> = (0..=(profile.duration() * 1000.) as usize)
+            .map(|millisecond| position_move.position(millisecond as f64 / 1000.))
+            .collect();
+        assert_eq!(positions[0], 5000.);
+        assert!(positions.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(position_move.position(profile.duration()), 1000.);
+    }
+
+    #[tokio::test]
+    async fn backward_move_ends_exactly_at_its_target() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.start_move(1, 5000, false).await;
+        positions_until_reached(&twin, 1).await;
+
+        twin.start_move(1, 1000, false).await;
+        let positions = positions_until_reached(&twin, 1).await;
+        assert!(positions.windows(2).all(|pair| pair[1] <= pair[0]));
+        assert_eq!(positions.last(), Some(&1000));
+    }
+
+    #[tokio::test]
+    async fn zero_travel_move_is_reached_immediately() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.start_move(1, 2000, false).await;
+        positions_until_reached(&twin, 1).await;
+
+        for (target, relative) in [(2000, false), (0, true)] {
+            twin.start_move(1, target, relative).await;
+            assert_eq!(positions_until_reached(&twin, 1).await, vec![2000]);
+            assert_eq!(twin.read_i32(1, MOVE_DIAGNOSTICS, 1).await, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn velocity_actual_value_ramps_up_and_down() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.write(1, 0x6060, 0, &[3]).await.unwrap();
+
+        // Velocity actual value every 10 ms for 800 ms, the ramps of 300 rpm take 600 ms with 500 rpm/s
+        async fn velocities(twin: &TestTwin) -> Vec<i32> {
+            let mut velocities = Vec::new();
+            for _ in 0..80 {
+                twin.advance(10).await;
+                velocities.push(twin.read_i32(1, 0x606C, 0).await);
+            }
+            velocities
+        }
+
+        twin.write(1, 0x60FF, 0, &300i32.to_le_bytes()).await.unwrap();
+        let ramp_up = velocities(&twin).await;
+        assert!(ramp_up.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!((140..=160).contains(&ramp_up[29]));
+        assert_eq!(ramp_up.last(), Some(&300));
+
+        twin.write(1, 0x60FF, 0, &0i32.to_le_bytes()).await.unwrap();
+        let ramp_down = velocities(&twin).await;
+        assert!(ramp_down.windows(2).all(|pair| pair[0] >= pair[1]));
+        assert!((140..=160).contains(&ramp_down[29]));
+        assert_eq!(ramp_down.last(), Some(&0));
+    }
+
+    #[tokio::test]
+    async fn quick_stop_decelerates_to_switch_on_disabled() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        // The quick stop deceleration (0x6085) of 5000 slows down the axis by 5 each ms, while it moves on
+        twin.write(1, 0x6040, 0, &0x0Bu16.to_le_bytes()).await.unwrap();
+        let ramp = ramp_until_standstill(&twin, 1).await;
+        assert!(ramp.len() > 10);
+        assert!(decelerates_at(&ramp, 5), "{ramp:?}");
+        assert!(ramp.last().unwrap().1 > ramp[0].1 + 30);
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x07);
+
+        // The quick stop option code 2 of the EDS disables the voltage once stopped
+        twin.advance(2).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x40);
+    }
+
+    #[tokio::test]
+    async fn quick_stop_holds_with_option_code_6() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x605A, 0, &6i16.to_le_bytes()).await.unwrap();
+        twin.enable(1).await;
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        twin.write(1, 0x6040, 0, &0x0Bu16.to_le_bytes()).await.unwrap();
+        let ramp = ramp_until_standstill(&twin, 1).await;
+        assert!(decelerates_at(&ramp, 5), "{ramp:?}");
+
+        // Quick stop active at standstill, until operation is enabled again
+        twin.advance(100).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x07);
+        twin.write(1, 0x6040, 0, &0x0Fu16.to_le_bytes()).await.unwrap();
+        twin.advance(2).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x6F, 0x27);
+    }
+
+    #[tokio::test]
+    async fn fault_reaction_decelerates_before_the_fault() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.enable(1).await;
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        // The fault reaction option code 2 of the EDS (0x605E) stops with the quick stop deceleration of 5000
+        twin.write(1, 0x2FF2, 0, &0x7300u16.to_le_bytes()).await.unwrap();
+        let mut ramp = Vec::new();
+        for _ in 0..1000 {
+            twin.advance(1).await;
+            let velocity = twin.read_i32(1, 0x606C, 0).await;
+            ramp.push((velocity, twin.read_i32(1, 0x6064, 0).await));
+            if velocity == 0 {
+                break;
+            }
+            // Fault reaction active
+            assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x0F);
+        }
+        assert!(ramp.len() > 10);
+        assert!(decelerates_at(&ramp, 5), "{ramp:?}");
+
+        // Fault once stopped
+        twin.advance(2).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x08);
+    }
+
+    #[tokio::test]
+    async fn fault_reaction_option_code_0_stops_at_once() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x605E, 0, &0i16.to_le_bytes()).await.unwrap();
+        twin.enable(1).await;
+        twin.start_move(1, 4000, false).await;
+        twin.advance(500).await;
+
+        twin.write(1, 0x2FF2, 0, &0x7300u16.to_le_bytes()).await.unwrap();
+        twin.advance(1).await;
+        assert_eq!(twin.read_i32(1, 0x606C, 0).await, 0);
+        twin.advance(2).await;
+        assert_eq!(twin.read_i32(1, 0x6041, 0).await & 0x4F, 0x08);
+    }
+}
@@ -0,0 +1,173 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::time::{Interval, MissedTickBehavior};
+
+/// Future returned by [`Ticker::tick`].
+pub type TickFuture<'a> = Pin<Box<dyn Future<Output = ()> + Send + 'a>>;
+
+/// Source of the update cycles of the nodes: [`RealClock`] to run in real time, [`ManualClock`] to step the simulation in tests.
+pub trait Clock: Send + Sync {
+    /// Ticks for a node updated with the cycle time.
+    fn ticker(&self, cycle_time: Duration) -> Box<dyn Ticker>;
+
+    /// Time of the clock, the received frames are timestamped with it.
+    fn now(&self) -> Duration;
+}
+
+/// Update cycles of a single node.
+pub trait Ticker: Send {
+    /// Wait for the next update cycle. Dropping the future before it completes does not skip a cycle.
+    fn tick(&mut self) -> TickFuture<'_>;
+}
+
+/// Clock that updates the nodes every cycle time in real time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn ticker(&self, cycle_time: Duration) -> Box<dyn Ticker> {
+        let mut interval = tokio::time::interval(cycle_time);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Box::new(RealTicker(interval))
+    }
+
+    fn now(&self) -> Duration {
+        // Time since the first use of a real clock
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed()
+    }
+}
+
+struct RealTicker(Interval);
+
+impl Ticker for RealTicker {
+    fn tick(&mut self) -> TickFuture<'_> {
+        Box::pin(async move {
+            self.0.tick().await;
+        })
+    }
+}
+
+/// Clock that only advances when told to, the nodes run all update cycles in the advanced time without waiting.
+/// Clones share the same time, keep one to advance and give the other to [`DigitalTwinBuilder::clock`](crate::DigitalTwinBuilder::clock).
+///
+/// ```
+/// # #[tokio::main]
+/// # async fn main() {
+/// use std::time::Duration;
+/// use fake_motorcontroller::{DigitalTwin, ManualClock, MockBus};
+///
+/// let clock = ManualClock::new();
+/// let mut twin = DigitalTwin::builder()
+///     .add_node(1, "CPB3-1-2.eds")
+///     .transport("vcan0", MockBus::new())
+///     .clock(clock.clone())
+///     .build()
+///     .expect("invalid configuration");
+/// twin.start().await.expect("failed to start the nodes");
+///
+/// // Run ten seconds of updates, as fast as the nodes can handle them
+/// clock.advance(Duration::from_secs(10)).await;
+/// assert_eq!(clock.now(), Duration::from_secs(10));
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ManualClock {
+    inner: Arc<ManualClockInner>,
+}
+
+struct ManualClockInner {
+    time: watch::Sender<Duration>,
+    /// Time up to which each ticker has completed its updates
+    tickers: Mutex<Vec<watch::Receiver<Duration>>>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(ManualClockInner {
+                time: watch::Sender::new(Duration::ZERO),
+                tickers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Time advanced since the clock was created.
+    pub fn now(&self) -> Duration {
+        *self.inner.time.borrow()
+    }
+
+    /// Advance the time and wait until the running nodes have completed the update cycles up to the new time.
+    pub async fn advance(&self, duration: Duration) {
+
+        let mut time = Duration::ZERO;
+        self.inner.time.send_modify(|now| {
+            *now += duration;
+            time = *now;
+        });
+
+        // Stopped nodes drop their ticker and are forgotten
+        let tickers: Vec<_> = {
+            let mut tickers = self.inner.tickers.lock().unwrap();
+            tickers.retain(|ticker| ticker.has_changed().is_ok());
+            tickers.clone()
+        };
+        for mut ticker in tickers {
+            ticker.wait_for(|&reached| reached >= time).await.ok();
+        }
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn ticker(&self, cycle_time: Duration) -> Box<dyn Ticker> {
+
+        let (reached, receiver) = watch::channel(self.now());
+        self.inner.tickers.lock().unwrap().push(receiver);
+
+        Box::new(ManualTicker {
+            time: self.inner.time.subscribe(),
+            reached,
+            next: self.now() + cycle_time,
+            cycle_time,
+        })
+    }
+
+    fn now(&self) -> Duration {
+        ManualClock::now(self)
+    }
+}
+
+struct ManualTicker {
+    time: watch::Receiver<Duration>,
+    /// Time up to which the updates are completed, the update of the previous tick is done when the next tick is requested
+    reached: watch::Sender<Duration>,
+    /// Time of the next tick
+    next: Duration,
+    cycle_time: Duration,
+}
+
+impl Ticker for ManualTicker {
+    fn tick(&mut self) -> TickFuture<'_> {
+        Box::pin(async move {
+
+            self.reached.send_replace(self.next - self.cycle_time);
+
+            // The clock is never dropped while a ticker holds a receiver of its time
+            let next = self.next;
+            if self.time.wait_for(|&time| time >= next).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+            self.next += self.cycle_time;
+
+        })
+    }
+}
@@ -1,60 +1,410 @@
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(rename_all = "PascalCase")]
 pub struct Config {
 
-    /// Configuration of the CANopen bus.
-    pub bus: BusConfig,
+    /// General configuration of the simulation.
+    #[serde(default)]
+    pub general: GeneralConfig,
+
+    /// Configuration of the CANopen buses, a single bus or a list of named buses.
+    pub bus: Buses,
 
     /// Configuration of the nodes.
     pub node: Vec<Node>,
 
+    /// Objects started with reproducible random values instead of their EDS defaults.
+    #[serde(default)]
+    pub randomize: Option<RandomizeConfig>,
+
+    /// Objects the master must not write, a write that breaks a rule is recorded as a violation.
+    #[serde(default)]
+    pub conformance: Vec<ConformanceRule>,
+
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(default)]
+pub struct GeneralConfig {
+
+    /// Period of the motor controller update in microseconds, motion advances by this fixed timestep.
+    pub cycle_time_us: u64,
+
+    /// File to append all sent and received frames to in the candump log format.
+    pub trace_file: Option<PathBuf>,
+
+    /// Time in milliseconds between the boot-up of consecutive nodes, so a large network powers up over time instead of in a single burst.
+    pub boot_stagger_ms: u64,
+
+}
+
+impl Default for GeneralConfig {
+    fn default() -> Self {
+        Self {
+            cycle_time_us: 1000,
+            trace_file: None,
+            boot_stagger_ms: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RandomizeConfig {
+
+    /// Seed of the random values, the same seed gives every node the same values on every start.
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Indices of the objects to randomize, all numeric sub indices except the number of entries of an array or record.
+    pub objects: Vec<u16>,
+
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConformanceRule {
+
+    /// Index of the object.
+    pub index: u16,
+
+    /// Sub index of the object, all sub indices when not set.
+    #[serde(default)]
+    pub sub_index: Option<u8>,
+
+    /// Node the rule applies to, all nodes when not set.
+    #[serde(default)]
+    pub node_id: Option<u8>,
+
+    /// NMT states in which the object must not be written, all states when empty.
+    #[serde(default)]
+    pub nmt_states: Vec<RuleNmtState>,
+
+}
+
+/// NMT state of a conformance rule
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleNmtState {
+    /// A node that is not self-starting waits in initializing for the first NMT command of the master.
+    Initializing,
+    PreOperational,
+    Operational,
+    Stopped,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum Buses {
+
+    /// A single bus, all nodes without an interface are on this bus.
+    Single(BusConfig),
+
+    /// Named buses, nodes refer to a bus by name.
+    Multiple(Vec<BusConfig>),
+
+}
+
+impl Buses {
+    /// The configured buses.
+    pub fn as_slice(&self) -> &[BusConfig] {
+        match self {
+            Buses::Single(bus) => std::slice::from_ref(bus),
+            Buses::Multiple(buses) => buses,
+        }
+    }
 }
 
 #[derive(Debug, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct BusConfig {
 
+    /// Name of the bus, used by nodes to refer to the bus.
+    #[serde(default)]
+    pub name: Option<String>,
+
     /// The CAN interface to use.
     pub interface: String,
 
-    /// The baudrate of the bus.
+    /// The baudrate of the bus in bit/s, used to estimate the bus load of the periodic frames.
     pub baud_rate: usize,
 
+    /// Maximum random delay of the sent frames in milliseconds.
+    #[serde(default)]
+    pub tx_latency_ms: u64,
+
+    /// Probability from 0 to 1 that a sent frame is dropped.
+    #[serde(default)]
+    pub drop_probability: f64,
+
+    /// Occasionally send a frame after the next one.
+    #[serde(default)]
+    pub reorder: bool,
+
+    /// Seed of the random delays, drops and reordering, the same seed gives the same sequence.
+    #[serde(default)]
+    pub seed: u64,
+
 }
 
-#[derive(Debug, serde::Deserialize)]
+impl BusConfig {
+    /// A bus on the interface at 1 Mbit/s that does not delay, drop or reorder frames.
+    pub fn new(interface: impl Into<String>) -> Self {
+        Self {
+            name: None,
+            interface: interface.into(),
+            baud_rate: 1_000_000,
+            tx_latency_ms: 0,
+            drop_probability: 0.,
+            reorder: false,
+            seed: 0,
+        }
+    }
+
+    /// Whether frames sent on the bus are delayed, dropped or reordered.
+    pub fn has_faults(&self) -> bool {
+        self.tx_latency_ms > 0 || self.drop_probability > 0. || self.reorder
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Node {
 
-    /// Node id
+    /// Node id, 255 for a node that gets its node id over LSS
     pub node_id: u8,
 
     /// Eds file
-    pub eds_file: String,
+    #[serde(default)]
+    pub eds_file: PathBuf,
+
+    /// CAN interface of the node, the interface of the bus when not set.
+    #[serde(default)]
+    pub interface: Option<String>,
+
+    /// Name of the bus of the node, the first bus when not set.
+    #[serde(default)]
+    pub bus: Option<String>,
+
+    /// Send an EMCY when an RPDO is received with a length that does not match its mapping.
+    #[serde(default = "default_true")]
+    pub emcy_on_pdo_length_error: bool,
+
+    /// Quick stop the drive when it is moved from operational to pre-operational.
+    #[serde(default)]
+    pub quick_stop_on_pre_operational: bool,
+
+    /// Minimum gap between consecutive segmented SDO responses in microseconds.
+    #[serde(default)]
+    pub sdo_segment_gap_us: u64,
+
+    /// Initial state of the digital inputs (0x60FD).
+    #[serde(default)]
+    pub digital_inputs: u32,
+
+    /// File the store parameters object (0x1010) saves the parameters to, loaded over the EDS defaults when the node starts.
+    /// `<eds file>.node<node id>.dcf` when not set.
+    #[serde(default)]
+    pub parameter_file: Option<PathBuf>,
+
+    /// Serial number in the identity object (0x1018 sub 4), the value of the EDS file when not set.
+    #[serde(default)]
+    pub serial_number: Option<u32>,
+
+    /// Enter operational after the boot-up without an NMT start from a master, also when bit 2 of NMT startup (0x1F80) is set.
+    #[serde(default)]
+    pub auto_start: bool,
+
+    /// Manufacturer object the duration (sub 1, ms) and end position (sub 2, user units) of the last planned move are written to.
+    #[serde(default = "default_move_diagnostics_index")]
+    pub move_diagnostics_index: u16,
+
+    /// Manufacturer object with the node id, for example 0x2009. It reads back the active node id, a write is only accepted
+    /// in pre-operational and activates the new node id on the next NMT reset, like a node id configured over LSS.
+    #[serde(default)]
+    pub node_id_index: Option<u16>,
+
+    /// Standard deviation in user units of the noise added to the position actual value (0x6064), the position demand
+    /// value (0x6062) and the trajectory stay without noise.
+    #[serde(default)]
+    pub position_noise_stddev: f64,
+
+    /// Seed of the position noise, combined with the node id so every node has its own reproducible noise.
+    #[serde(default)]
+    pub position_noise_seed: u64,
+
+    /// Node id of a node on the same bus whose position and velocity this axis takes after every update cycle,
+    /// for example the second axis of a gantry. The positions are in increments, so both axes need the same encoder.
+    #[serde(default)]
+    pub follow: Option<u8>,
+
+}
+
+/// Node id of a node that has no node id yet, it only responds to LSS until a node id is configured.
+pub const UNCONFIGURED_NODE_ID: u8 = 255;
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_move_diagnostics_index() -> u16 {
+    0x2FF5
+}
+
+impl Node {
+    /// A node with the EDS file and the defaults of the configuration file.
+    pub fn new(node_id: u8, eds_file: impl Into<PathBuf>) -> Self {
+        Self {
+            node_id,
+            eds_file: eds_file.into(),
+            interface: None,
+            bus: None,
+            emcy_on_pdo_length_error: true,
+            quick_stop_on_pre_operational: false,
+            sdo_segment_gap_us: 0,
+            digital_inputs: 0,
+            parameter_file: None,
+            serial_number: None,
+            auto_start: false,
+            move_diagnostics_index: default_move_diagnostics_index(),
+            node_id_index: None,
+            position_noise_stddev: 0.,
+            position_noise_seed: 0,
+            follow: None,
+        }
+    }
 
+    /// The configured parameter file or the default next to the EDS file.
+    pub fn parameter_file(&self) -> PathBuf {
+        self.parameter_file.clone()
+            .unwrap_or_else(|| self.eds_file.with_extension(format!("node{}.dcf", self.node_id)))
+    }
+}
+
+
+/// Error reading or validating the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be opened or read.
+    Io(PathBuf, std::io::Error),
+    /// The file is not valid UTF-8.
+    Utf8(PathBuf, std::str::Utf8Error),
+    /// The file is not a valid TOML configuration.
+    Parse(PathBuf, toml::de::Error),
+    /// The configuration is not consistent, for example duplicate node ids.
+    Invalid(String),
 }
 
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "Failed to read {}: {e}", path.display()),
+            ConfigError::Utf8(path, e) => write!(f, "Invalid UTF-8 in {}: {e}", path.display()),
+            ConfigError::Parse(path, e) => write!(f, "Failed to parse {}: {e}", path.display()),
+            ConfigError::Invalid(message) => write!(f, "Invalid configuration: {message}"),
+        }
+    }
+}
 
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(_, e) => Some(e),
+            ConfigError::Utf8(_, e) => Some(e),
+            ConfigError::Parse(_, e) => Some(e),
+            ConfigError::Invalid(_) => None,
+        }
+    }
+}
 
 impl Config {
     /// Read the configuration from a TOML file.
-    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self, ()> {
-        use std::io::Read;
+    pub fn read_from_file(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
 
         let path = path.as_ref();
-        let mut file = std::fs::File::open(path)
-            .map_err(|e| log::error!("Failed to open {} for reading: {e}", path.display()))?;
-        let mut data = Vec::new();
-        file.read_to_end(&mut data)
-            .map_err(|e| log::error!("Failed to read from {}: {e}", path.display()))?;
+        let data = std::fs::read(path)
+            .map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
         let data = std::str::from_utf8(&data)
-            .map_err(|e| log::error!("Invalid UTF-8 in {}: {e}", path.display()))?;
+            .map_err(|e| ConfigError::Utf8(path.to_path_buf(), e))?;
 
         let config: Self = toml::from_str(data)
-            .map_err(|e| log::error!("Failed to parse {}: {e}", path.display()))?;
+            .map_err(|e| ConfigError::Parse(path.to_path_buf(), e))?;
+
+        config.validate()?;
+
         Ok(config)
     }
-}
\ No newline at end of file
+
+    /// Check that the node ids are valid and unique on each interface and that every node has an EDS file and a bus.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+
+        for bus in self.bus.as_slice() {
+            if !(0. ..=1.).contains(&bus.drop_probability) {
+                return Err(ConfigError::Invalid(format!("drop_probability {} of interface {} not in the range 0 to 1", bus.drop_probability, bus.interface)));
+            }
+        }
+
+        let mut node_ids = HashSet::new();
+        for node in &self.node {
+            if !(1..=127).contains(&node.node_id) && node.node_id != UNCONFIGURED_NODE_ID {
+                return Err(ConfigError::Invalid(format!("node id {} not in the range 1 to 127 or {} for an unconfigured node", node.node_id, UNCONFIGURED_NODE_ID)));
+            }
+            if !(0x2000..=0x5FFF).contains(&node.move_diagnostics_index) {
+                return Err(ConfigError::Invalid(format!("move_diagnostics_index 0x{:04X} of node {} not in the manufacturer range 0x2000 to 0x5FFF", node.move_diagnostics_index, node.node_id)));
+            }
+            if let Some(index) = node.node_id_index.filter(|index| !(0x2000..=0x5FFF).contains(index)) {
+                return Err(ConfigError::Invalid(format!("node_id_index 0x{:04X} of node {} not in the manufacturer range 0x2000 to 0x5FFF", index, node.node_id)));
+            }
+            if !(node.position_noise_stddev >= 0. && node.position_noise_stddev.is_finite()) {
+                return Err(ConfigError::Invalid(format!("position_noise_stddev {} of node {} is not a finite value of 0 or more", node.position_noise_stddev, node.node_id)));
+            }
+            if node.eds_file.as_os_str().is_empty() {
+                return Err(ConfigError::Invalid(format!("missing eds_file for node {}", node.node_id)));
+            }
+            let interface = self.node_interface(node)?;
+            if !node_ids.insert((interface, node.node_id)) {
+                return Err(ConfigError::Invalid(format!("duplicate node id {} on interface {}", node.node_id, interface)));
+            }
+        }
+
+        // A coupled axis follows another node on its own bus
+        for node in &self.node {
+            let Some(follow) = node.follow else {
+                continue;
+            };
+            let interface = self.node_interface(node)?;
+            if follow == node.node_id || !node_ids.contains(&(interface, follow)) {
+                return Err(ConfigError::Invalid(format!("node {} follows node {}, which is not another node on interface {}", node.node_id, follow, interface)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The configuration of the bus on the CAN interface, if any.
+    pub fn bus_config(&self, interface: &str) -> Option<&BusConfig> {
+        self.bus.as_slice().iter().find(|bus| bus.interface == interface)
+    }
+
+    /// The CAN interface of a node: the interface of the node, of the bus it refers to or of the first bus.
+    pub fn node_interface<'a>(&'a self, node: &'a Node) -> Result<&'a str, ConfigError> {
+
+        if let Some(interface) = &node.interface {
+            return Ok(interface);
+        }
+
+        let buses = self.bus.as_slice();
+        let bus = match &node.bus {
+            Some(name) => buses.iter().find(|bus| bus.name.as_ref() == Some(name))
+                .ok_or_else(|| ConfigError::Invalid(format!("bus {} of node {} not configured", name, node.node_id)))?,
+            None => buses.first()
+                .ok_or_else(|| ConfigError::Invalid(format!("no bus configured for node {}", node.node_id)))?,
+        };
+
+        Ok(&bus.interface)
+    }
+}
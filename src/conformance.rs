@@ -0,0 +1,114 @@
+use std::sync::{Mutex, RwLock};
+
+use canopen_tokio::nmt::NmtState;
+
+use crate::cia301::Node;
+use crate::config::{ConformanceRule, RuleNmtState};
+
+/// How the master wrote an object
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WriteSource {
+    /// An SDO download, also when the node aborts it.
+    Sdo,
+    /// An RPDO, with the RPDO number 1 to 4.
+    Rpdo(u16),
+}
+
+impl std::fmt::Display for WriteSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteSource::Sdo => write!(f, "SDO"),
+            WriteSource::Rpdo(rpdo_number) => write!(f, "RPDO{}", rpdo_number),
+        }
+    }
+}
+
+/// A write of the master that broke a conformance rule. Repeated writes of the same object by the same source in the
+/// same NMT state are one violation with a count, so cyclic RPDOs do not grow the list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub node_id: u8,
+    pub index: u16,
+    pub sub_index: u8,
+    pub source: WriteSource,
+    pub nmt_state: NmtState,
+    /// Number of writes that broke the rule
+    pub count: u64,
+}
+
+impl std::fmt::Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "node {} written 0x{:04X} sub {} by {} in {}, {} times",
+            self.node_id, self.index, self.sub_index, self.source, self.nmt_state, self.count)
+    }
+}
+
+/// Conformance rules of the configuration and the violations of all nodes, shared by the nodes and kept when a node restarts
+#[derive(Debug, Default)]
+pub struct Conformance {
+    rules: RwLock<Vec<ConformanceRule>>,
+    violations: Mutex<Vec<Violation>>,
+}
+
+impl Conformance {
+    /// Replace the rules, the recorded violations are kept.
+    pub fn set_rules(&self, rules: Vec<ConformanceRule>) {
+        *self.rules.write().unwrap() = rules;
+    }
+
+    /// The violations recorded since the start.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.violations.lock().unwrap().clone()
+    }
+}
+
+impl ConformanceRule {
+    /// Whether the rule forbids the write of the object by the node in the NMT state.
+    fn forbids(&self, node_id: u8, index: u16, sub_index: u8, nmt_state: NmtState) -> bool {
+        self.index == index
+            && self.sub_index.is_none_or(|rule_sub_index| rule_sub_index == sub_index)
+            && self.node_id.is_none_or(|rule_node_id| rule_node_id == node_id)
+            && (self.nmt_states.is_empty() || self.nmt_states.iter().any(|state| state.matches(nmt_state)))
+    }
+}
+
+impl RuleNmtState {
+    fn matches(&self, nmt_state: NmtState) -> bool {
+        matches!((self, nmt_state),
+            (RuleNmtState::Initializing, NmtState::Initializing)
+            | (RuleNmtState::PreOperational, NmtState::PreOperational)
+            | (RuleNmtState::Operational, NmtState::Operational)
+            | (RuleNmtState::Stopped, NmtState::Stopped))
+    }
+}
+
+impl Node {
+
+    /// Record a violation when a conformance rule forbids the master to write the object in the present NMT state.
+    /// The write itself is handled as usual, the twin only observes the master.
+    pub(crate) fn check_write_rules(&self, index: u16, sub_index: u8, source: WriteSource) {
+
+        let forbidden = self.conformance.rules.read().unwrap().iter()
+            .any(|rule| rule.forbids(self.node_id, index, sub_index, self.nmt_state));
+        if !forbidden {
+            return;
+        }
+
+        let mut violations = self.conformance.violations.lock().unwrap();
+        let recorded = violations.iter_mut().find(|violation| {
+            (violation.node_id, violation.index, violation.sub_index, violation.source, violation.nmt_state)
+                == (self.node_id, index, sub_index, source, self.nmt_state)
+        });
+
+        match recorded {
+            Some(violation) => violation.count += 1,
+            None => {
+                let violation = Violation { node_id: self.node_id, index, sub_index, source, nmt_state: self.nmt_state, count: 1 };
+                log::warn!(target: &self.log_target, "Conformance rule violated: node {} written 0x{:04X} sub {} by {} in {}",
+                    self.node_id, index, sub_index, source, self.nmt_state);
+                violations.push(violation);
+            }
+        }
+    }
+
+}
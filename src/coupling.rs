@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use crate::cia301::Node;
+
+/// Position and velocity of an axis after its last update cycle, in increments and rpm
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AxisMotion {
+    pub position: f64,
+    pub velocity: f64,
+}
+
+/// Motion of the axes on a bus by node id, shared by the nodes of the bus to couple axes
+pub type AxisMotions = Arc<Mutex<BTreeMap<u8, AxisMotion>>>;
+
+impl Node {
+
+    /// Take the position and velocity of the followed axis, for example the second axis of a gantry, and publish the
+    /// motion of this axis for the axes that follow it. The nodes update in their own task, so a follower takes the
+    /// motion of the last completed update of the followed axis. Without a motion of the followed axis yet, the axis
+    /// keeps its own motion.
+    pub(crate) fn couple_axis(&mut self) {
+
+        let mut axis_motions = self.axis_motions.lock().unwrap();
+
+        if let Some(motion) = self.follow.and_then(|node_id| axis_motions.get(&node_id)) {
+            self.motor_controller.actual_position = motion.position;
+            self.motor_controller.actual_velocity = motion.velocity;
+        }
+
+        axis_motions.insert(self.node_id, AxisMotion {
+            position: self.motor_controller.actual_position,
+            velocity: self.motor_controller.actual_velocity,
+        });
+    }
+
+}
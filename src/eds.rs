@@ -1,8 +1,13 @@
 use ini::Ini;
+use serde_json::json;
 use std::fs;
+use std::path::Path;
 use std::collections::BTreeMap;
 
-#[derive(Debug)]
+use crate::rng::SplitMix64;
+
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct FileInfo {
     pub file_name: String,
     pub file_version: u32,
@@ -12,15 +17,18 @@ pub struct FileInfo {
     pub created_by: String,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct DeviceInfo {
     pub vendor_name: String,
     pub vendor_number: u32,
     pub product_name: String,
     pub product_number: u32,
+    pub revision_number: u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct Var {
     pub parameter_name: String,
     pub access_type: String,
@@ -28,11 +36,27 @@ pub struct Var {
     pub pdo_mapping: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub struct EDSData {
     pub file_info: FileInfo,
     pub device_info: DeviceInfo,
     pub od: BTreeMap<u16, BTreeMap<u8, Var>>,
+    /// Object type of the arrays and records, the other objects are variables
+    pub object_types: BTreeMap<u16, ObjectType>,
+    /// Parameter name of the arrays and records, the variables hold their own name
+    pub object_names: BTreeMap<u16, String>,
+}
+
+/// Object type of an object with sub indices
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectType {
+    /// A single value at sub index 0.
+    Var,
+    /// Entries of the same data type, sub index 0 holds the number of entries.
+    Array,
+    /// Entries of different data types, sub index 0 holds the highest sub index.
+    Record,
 }
 
 #[derive(Debug, Clone)]
@@ -41,24 +65,285 @@ pub enum DataType {
     Boolean,
     Integer8,
     Integer16,
+    Integer24,
     Integer32,
     Unsigned8,
     Unsigned16,
+    Unsigned24,
     Unsigned32,
     Real32,
+    VisibleString,
+    OctetString,
+    Domain,
 }
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum DataValue {
     Unknown(i32),
     Boolean(bool),
     Integer8(i8),
     Integer16(i16),
+    /// 24 bit signed value, sign extended to 32 bits
+    Integer24(i32),
     Integer32(i32),
     Unsigned8(u8),
     Unsigned16(u16),
+    /// 24 bit unsigned value, the upper byte is zero
+    Unsigned24(u32),
     Unsigned32(u32),
     Real32(f32),
+    VisibleString(String),
+    OctetString(Vec<u8>),
+    Domain(Vec<u8>),
+}
+
+impl Var {
+    /// Read only and constant objects can not be written over SDO.
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.access_type.as_str(), "ro" | "const")
+    }
+}
+
+impl DataValue {
+    /// Little endian representation of the value, as transferred over the bus.
+    pub fn to_le_bytes(&self) -> Vec<u8> {
+        match self {
+            DataValue::Unknown(_) => Vec::new(),
+            DataValue::Boolean(value) => vec![*value as u8],
+            DataValue::Integer8(value) => value.to_le_bytes().to_vec(),
+            DataValue::Integer16(value) => value.to_le_bytes().to_vec(),
+            DataValue::Integer24(value) => value.to_le_bytes()[..3].to_vec(),
+            DataValue::Integer32(value) => value.to_le_bytes().to_vec(),
+            DataValue::Unsigned8(value) => value.to_le_bytes().to_vec(),
+            DataValue::Unsigned16(value) => value.to_le_bytes().to_vec(),
+            DataValue::Unsigned24(value) => value.to_le_bytes()[..3].to_vec(),
+            DataValue::Unsigned32(value) => value.to_le_bytes().to_vec(),
+            DataValue::Real32(value) => value.to_le_bytes().to_vec(),
+            DataValue::VisibleString(value) => value.as_bytes().to_vec(),
+            DataValue::OctetString(value) => value.clone(),
+            DataValue::Domain(value) => value.clone(),
+        }
+    }
+
+    /// Data type of the value.
+    pub(crate) fn data_type(&self) -> DataType {
+        match self {
+            DataValue::Unknown(_) => DataType::Unknown,
+            DataValue::Boolean(_) => DataType::Boolean,
+            DataValue::Integer8(_) => DataType::Integer8,
+            DataValue::Integer16(_) => DataType::Integer16,
+            DataValue::Integer24(_) => DataType::Integer24,
+            DataValue::Integer32(_) => DataType::Integer32,
+            DataValue::Unsigned8(_) => DataType::Unsigned8,
+            DataValue::Unsigned16(_) => DataType::Unsigned16,
+            DataValue::Unsigned24(_) => DataType::Unsigned24,
+            DataValue::Unsigned32(_) => DataType::Unsigned32,
+            DataValue::Real32(_) => DataType::Real32,
+            DataValue::VisibleString(_) => DataType::VisibleString,
+            DataValue::OctetString(_) => DataType::OctetString,
+            DataValue::Domain(_) => DataType::Domain,
+        }
+    }
+
+    /// Name of the data type of the value.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            DataValue::Unknown(_) => "Unknown",
+            DataValue::Boolean(_) => "Boolean",
+            DataValue::Integer8(_) => "Integer8",
+            DataValue::Integer16(_) => "Integer16",
+            DataValue::Integer24(_) => "Integer24",
+            DataValue::Integer32(_) => "Integer32",
+            DataValue::Unsigned8(_) => "Unsigned8",
+            DataValue::Unsigned16(_) => "Unsigned16",
+            DataValue::Unsigned24(_) => "Unsigned24",
+            DataValue::Unsigned32(_) => "Unsigned32",
+            DataValue::Real32(_) => "Real32",
+            DataValue::VisibleString(_) => "VisibleString",
+            DataValue::OctetString(_) => "OctetString",
+            DataValue::Domain(_) => "Domain",
+        }
+    }
+
+    /// Numbers and strings as JSON values, byte arrays as a hexadecimal string.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            DataValue::Unknown(value) => json!(value),
+            DataValue::Boolean(value) => json!(value),
+            DataValue::Integer8(value) => json!(value),
+            DataValue::Integer16(value) => json!(value),
+            DataValue::Integer24(value) => json!(value),
+            DataValue::Integer32(value) => json!(value),
+            DataValue::Unsigned8(value) => json!(value),
+            DataValue::Unsigned16(value) => json!(value),
+            DataValue::Unsigned24(value) => json!(value),
+            DataValue::Unsigned32(value) => json!(value),
+            DataValue::Real32(value) => json!(value),
+            DataValue::VisibleString(value) => json!(value),
+            DataValue::OctetString(bytes) | DataValue::Domain(bytes) => {
+                json!(bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<String>())
+            }
+        }
+    }
+
+    /// The value as written in an EDS or DCF file, None for an unknown data type.
+    pub(crate) fn to_eds_string(&self) -> Option<String> {
+        match self {
+            DataValue::Unknown(_) => None,
+            DataValue::Boolean(value) => Some(value.to_string()),
+            DataValue::Integer8(value) => Some(value.to_string()),
+            DataValue::Integer16(value) => Some(value.to_string()),
+            DataValue::Integer24(value) => Some(value.to_string()),
+            DataValue::Integer32(value) => Some(value.to_string()),
+            DataValue::Unsigned8(value) => Some(format!("0x{:02X}", value)),
+            DataValue::Unsigned16(value) => Some(format!("0x{:04X}", value)),
+            DataValue::Unsigned24(value) => Some(format!("0x{:06X}", value)),
+            DataValue::Unsigned32(value) => Some(format!("0x{:08X}", value)),
+            DataValue::Real32(value) => Some(value.to_string()),
+            DataValue::VisibleString(value) => Some(value.clone()),
+            DataValue::OctetString(value) | DataValue::Domain(value) => Some(value.iter().map(|byte| format!("{:02X}", byte)).collect()),
+        }
+    }
+
+    /// A random value of the same numeric data type, over the full range of the type. None for strings and byte arrays.
+    pub(crate) fn random(&self, rng: &mut SplitMix64) -> Option<DataValue> {
+        let bits = rng.next_u64();
+        match self {
+            DataValue::Boolean(_) => Some(DataValue::Boolean(bits & 1 == 1)),
+            DataValue::Integer8(_) => Some(DataValue::Integer8(bits as i8)),
+            DataValue::Integer16(_) => Some(DataValue::Integer16(bits as i16)),
+            DataValue::Integer24(_) => Some(DataValue::Integer24(((bits as i32) << 8) >> 8)),
+            DataValue::Integer32(_) => Some(DataValue::Integer32(bits as i32)),
+            DataValue::Unsigned8(_) => Some(DataValue::Unsigned8(bits as u8)),
+            DataValue::Unsigned16(_) => Some(DataValue::Unsigned16(bits as u16)),
+            DataValue::Unsigned24(_) => Some(DataValue::Unsigned24(bits as u32 & 0xFF_FFFF)),
+            DataValue::Unsigned32(_) => Some(DataValue::Unsigned32(bits as u32)),
+            // Finite values in the range of an Integer32
+            DataValue::Real32(_) => Some(DataValue::Real32(bits as i32 as f32)),
+            DataValue::Unknown(_) | DataValue::VisibleString(_) | DataValue::OctetString(_) | DataValue::Domain(_) => None,
+        }
+    }
+
+    /// Parse little endian data into a value of the same data type. Returns None when the length does not fit.
+    pub fn with_le_bytes(&self, data: &[u8]) -> Option<DataValue> {
+        match self {
+            DataValue::Unknown(_) => None,
+            DataValue::Boolean(_) => Some(DataValue::Boolean(*data.first()? != 0)),
+            DataValue::Integer8(_) => Some(DataValue::Integer8(i8::from_le_bytes(data.get(..1)?.try_into().ok()?))),
+            DataValue::Integer16(_) => Some(DataValue::Integer16(i16::from_le_bytes(data.get(..2)?.try_into().ok()?))),
+            DataValue::Integer24(_) => {
+                let [b0, b1, b2] = data.get(..3)?.try_into().ok()?;
+                Some(DataValue::Integer24(i32::from_le_bytes([0, b0, b1, b2]) >> 8))
+            }
+            DataValue::Integer32(_) => Some(DataValue::Integer32(i32::from_le_bytes(data.get(..4)?.try_into().ok()?))),
+            DataValue::Unsigned8(_) => Some(DataValue::Unsigned8(u8::from_le_bytes(data.get(..1)?.try_into().ok()?))),
+            DataValue::Unsigned16(_) => Some(DataValue::Unsigned16(u16::from_le_bytes(data.get(..2)?.try_into().ok()?))),
+            DataValue::Unsigned24(_) => {
+                let [b0, b1, b2] = data.get(..3)?.try_into().ok()?;
+                Some(DataValue::Unsigned24(u32::from_le_bytes([b0, b1, b2, 0])))
+            }
+            DataValue::Unsigned32(_) => Some(DataValue::Unsigned32(u32::from_le_bytes(data.get(..4)?.try_into().ok()?))),
+            DataValue::Real32(_) => Some(DataValue::Real32(f32::from_le_bytes(data.get(..4)?.try_into().ok()?))),
+            DataValue::VisibleString(_) => Some(DataValue::VisibleString(String::from_utf8_lossy(data).into_owned())),
+            DataValue::OctetString(_) => Some(DataValue::OctetString(data.to_vec())),
+            DataValue::Domain(_) => Some(DataValue::Domain(data.to_vec())),
+        }
+    }
+}
+
+impl EDSData {
+    /// Get a variable from the object dictionary.
+    pub fn get_var(&self, index: u16, sub_index: u8) -> Option<&Var> {
+        self.od.get(&index).and_then(|vars| vars.get(&sub_index))
+    }
+
+    /// Object type of an object, variables and objects that are not in the EDS are [`ObjectType::Var`].
+    pub fn object_type(&self, index: u16) -> ObjectType {
+        self.object_types.get(&index).copied().unwrap_or(ObjectType::Var)
+    }
+
+    /// Get the numeric value of a variable as f64.
+    pub fn get_f64(&self, index: u16, sub_index: u8) -> Option<f64> {
+        match self.get_var(index, sub_index)?.value {
+            DataValue::Integer8(value) => Some(value as f64),
+            DataValue::Integer16(value) => Some(value as f64),
+            DataValue::Integer24(value) => Some(value as f64),
+            DataValue::Integer32(value) => Some(value as f64),
+            DataValue::Unsigned8(value) => Some(value as f64),
+            DataValue::Unsigned16(value) => Some(value as f64),
+            DataValue::Unsigned24(value) => Some(value as f64),
+            DataValue::Unsigned32(value) => Some(value as f64),
+            DataValue::Real32(value) => Some(value as f64),
+            _ => None,
+        }
+    }
+
+    /// Add a variable to the object dictionary, unless the EDS already defines it.
+    pub fn insert_var_if_missing(&mut self, index: u16, sub_index: u8, parameter_name: &str, access_type: &str, value: DataValue) {
+        self.od.entry(index)
+            .or_default()
+            .entry(sub_index)
+            .or_insert_with(|| Var {
+                parameter_name: parameter_name.to_string(),
+                access_type: access_type.to_string(),
+                value,
+                pdo_mapping: true,
+            });
+    }
+
+    /// Write the values of the writable variables to a DCF file as `ParameterValue`, except the variables of the excluded indices.
+    pub fn write_parameters(&self, path: &Path, exclude: &[u16]) -> std::io::Result<()> {
+
+        let mut ini = Ini::new();
+        for (&index, vars) in self.od.iter().filter(|(index, _)| !exclude.contains(index)) {
+            for (&sub_index, var) in vars.iter().filter(|(_, var)| !var.is_read_only()) {
+                if let Some(value) = var.value.to_eds_string() {
+                    ini.with_section(Some(format!("{:04X}sub{:X}", index, sub_index)))
+                        .set("ParameterName", var.parameter_name.as_str())
+                        .set("ParameterValue", value);
+                }
+            }
+        }
+
+        ini.write_to_file(path)
+    }
+
+    /// Set the variables to the `ParameterValue` of a DCF file written by [`EDSData::write_parameters`].
+    pub fn load_parameters(&mut self, node_id: u8, path: &Path) -> Result<(), Box<dyn std::error::Error>> {
+
+        let ini = Ini::load_from_file(path)?;
+
+        for (section, properties) in ini.iter() {
+
+            let (Some(section), Some(parameter_value)) = (section, properties.get("ParameterValue")) else {
+                continue;
+            };
+
+            let (index, sub_index) = parse_section(section);
+            let Some(var) = self.get_var(index, sub_index) else {
+                log::warn!("Parameter [{}] of {} not in the object dictionary, ignored", section, path.display());
+                continue;
+            };
+
+            let value = parse_default_value(node_id, var.value.data_type(), parameter_value)
+                .map_err(|e| format!("Invalid ParameterValue {:?} in section [{}]: {e}", parameter_value, section))?;
+            self.set_dataval(index, sub_index, value);
+        }
+
+        Ok(())
+    }
+
+    /// Set the value of a variable, only if the data type matches the one in the object dictionary.
+    pub fn set_dataval(&mut self, index: u16, sub_index: u8, value: DataValue) {
+        if let Some(var) = self.od.get_mut(&index).and_then(|vars| vars.get_mut(&sub_index)) {
+            if std::mem::discriminant(&var.value) == std::mem::discriminant(&value) {
+                var.value = value;
+            } else {
+                log::error!("Data type mismatch when setting 0x{:X} sub {}: {:?}", index, sub_index, value);
+            }
+        }
+    }
 }
 
 fn get_data_type(data_type: &u32) -> DataType {
@@ -71,6 +356,11 @@ fn get_data_type(data_type: &u32) -> DataType {
         0x0006 => DataType::Unsigned16,
         0x0007 => DataType::Unsigned32,
         0x0008 => DataType::Real32,
+        0x0009 => DataType::VisibleString,
+        0x000A => DataType::OctetString,
+        0x000F => DataType::Domain,
+        0x0010 => DataType::Integer24,
+        0x0016 => DataType::Unsigned24,
         _ => DataType::Unknown,
     }
 }
@@ -78,6 +368,23 @@ fn get_data_type(data_type: &u32) -> DataType {
 // Function to parse the default value into a typed DataValue
 fn parse_default_value(node_id: u8, data_type: DataType, default_value: &str) -> Result<DataValue, String> {
 
+    // Strings and byte arrays may be empty
+    match data_type {
+        DataType::VisibleString => return Ok(DataValue::VisibleString(default_value.to_string())),
+        DataType::OctetString | DataType::Domain => {
+            let hex = default_value.trim_start_matches("0x");
+            let bytes = (0..hex.len() / 2)
+                .map(|i| u8::from_str_radix(&hex[2 * i..2 * i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| "Invalid byte string value")?;
+            return Ok(match data_type {
+                DataType::OctetString => DataValue::OctetString(bytes),
+                _ => DataValue::Domain(bytes),
+            });
+        }
+        _ => {},
+    }
+
     let default_value = if default_value.is_empty() { "0" } else { default_value };
 
     match data_type {
@@ -88,9 +395,13 @@ fn parse_default_value(node_id: u8, data_type: DataType, default_value: &str) ->
             let val = default_value.parse::<bool>().map_err(|_| "Invalid Boolean value")?;
             Ok(DataValue::Boolean(val))
         }
+        // Hex values may have a minus sign or be the two's complement of the full width, 0x80 is -128
         DataType::Integer8 => {
             if default_value.contains("0x") {
-                let val = i8::from_str_radix(default_value.trim_start_matches("0x"), 16).map_err(|_| "Invalid i8 value")?;
+                let hex = default_value.replacen("0x", "", 1);
+                let val = i8::from_str_radix(&hex, 16)
+                    .or_else(|_| u8::from_str_radix(&hex, 16).map(|val| val as i8))
+                    .map_err(|_| "Invalid i8 value")?;
                 Ok(DataValue::Integer8(val))
             } else {
                 let val = default_value.parse::<i8>().map_err(|_| "Invalid i8 value")?;
@@ -99,16 +410,37 @@ fn parse_default_value(node_id: u8, data_type: DataType, default_value: &str) ->
         }
         DataType::Integer16 => {
             if default_value.contains("0x") {
-                let val = i16::from_str_radix(default_value.trim_start_matches("0x"), 16).map_err(|_| "Invalid i16 value")?;
+                let hex = default_value.replacen("0x", "", 1);
+                let val = i16::from_str_radix(&hex, 16)
+                    .or_else(|_| u16::from_str_radix(&hex, 16).map(|val| val as i16))
+                    .map_err(|_| "Invalid i16 value")?;
                 Ok(DataValue::Integer16(val))
             } else {
                 let val = default_value.parse::<i16>().map_err(|_| "Invalid i16 value")?;
                 Ok(DataValue::Integer16(val))
             }
         }
+        DataType::Integer24 => {
+            let val = if default_value.contains("0x") {
+                let val = u32::from_str_radix(&default_value.replacen("0x", "", 1), 16).map_err(|_| "Invalid i24 value")?;
+                if val > 0xFF_FFFF {
+                    return Err("Invalid i24 value".into());
+                }
+                // Sign extend the two's complement of 24 bits
+                ((val << 8) as i32) >> 8
+            } else {
+                default_value.parse::<i32>().ok()
+                    .filter(|val| (-0x80_0000..0x80_0000).contains(val))
+                    .ok_or("Invalid i24 value")?
+            };
+            Ok(DataValue::Integer24(val))
+        }
         DataType::Integer32 => {
             if default_value.contains("0x") {
-                let val = i32::from_str_radix(default_value.trim_start_matches("0x"), 16).map_err(|_| "Invalid i32 value")?;
+                let hex = default_value.replacen("0x", "", 1);
+                let val = i32::from_str_radix(&hex, 16)
+                    .or_else(|_| u32::from_str_radix(&hex, 16).map(|val| val as i32))
+                    .map_err(|_| "Invalid i32 value")?;
                 Ok(DataValue::Integer32(val))
             } else {
                 let val = default_value.parse::<i32>().map_err(|_| "Invalid i32 value")?;
@@ -136,6 +468,17 @@ fn parse_default_value(node_id: u8, data_type: DataType, default_value: &str) ->
                 Ok(DataValue::Unsigned16(val))
             }
         }
+        DataType::Unsigned24 => {
+            let val = if default_value.contains("0x") {
+                u32::from_str_radix(default_value.trim_start_matches("0x"), 16).map_err(|_| "Invalid u24 value")?
+            } else {
+                default_value.parse::<u32>().map_err(|_| "Invalid u24 value")?
+            };
+            if val > 0xFF_FFFF {
+                return Err("Invalid u24 value".into());
+            }
+            Ok(DataValue::Unsigned24(val))
+        }
         DataType::Unsigned32 => {
             if default_value.contains("$NODEID") {
                 let val = (u16::from_str_radix(default_value.trim_start_matches("$NODEID+0x"), 16).map_err(|_| "Invalid u32 value")? | node_id as u16) as u32;
@@ -154,11 +497,12 @@ fn parse_default_value(node_id: u8, data_type: DataType, default_value: &str) ->
             Ok(DataValue::Real32(val))
 
         }
+        DataType::VisibleString | DataType::OctetString | DataType::Domain => unreachable!(),
     }
 }
 
 
-pub fn parse_eds(node_id: &u8, eds_file: &String) -> Result<EDSData, Box<dyn std::error::Error>> {
+pub fn parse_eds(node_id: &u8, eds_file: &Path) -> Result<EDSData, Box<dyn std::error::Error>> {
     
     // Load the EDS file
     let eds_content = fs::read_to_string(eds_file)?;
@@ -167,7 +511,7 @@ pub fn parse_eds(node_id: &u8, eds_file: &String) -> Result<EDSData, Box<dyn std
     let ini = Ini::load_from_str(&eds_content)?;
 
     // Extract DeviceInfo
-    let file_info_section = ini.section(Some("FileInfo")).expect("Missing FileInfo section");
+    let file_info_section = ini.section(Some("FileInfo")).ok_or("Missing FileInfo section")?;
     let file_info = FileInfo {
         file_name: file_info_section.get("FileName").unwrap_or_default().to_string(),
         file_version: file_info_section.get("FileVersion").unwrap_or("0").parse().unwrap_or(0),
@@ -178,32 +522,52 @@ pub fn parse_eds(node_id: &u8, eds_file: &String) -> Result<EDSData, Box<dyn std
     };
 
     // Extract DeviceInfo
-    let device_info_section = ini.section(Some("DeviceInfo")).expect("Missing DeviceInfo section");
+    let device_info_section = ini.section(Some("DeviceInfo")).ok_or("Missing DeviceInfo section")?;
     let device_info = DeviceInfo {
         vendor_name: device_info_section.get("VendorName").unwrap_or_default().to_string(),
-        vendor_number: device_info_section.get("VendorNumber").unwrap_or("0").parse().unwrap_or(0),
+        vendor_number: parse_number(device_info_section.get("VendorNumber").unwrap_or("0")).unwrap_or(0),
         product_name: device_info_section.get("ProductName").unwrap_or_default().to_string(),
-        product_number: device_info_section.get("ProductNumber").unwrap_or("0").parse().unwrap_or(0),
+        product_number: parse_number(device_info_section.get("ProductNumber").unwrap_or("0")).unwrap_or(0),
+        revision_number: parse_number(device_info_section.get("RevisionNumber").unwrap_or("0")).unwrap_or(0),
     };
 
     // Extact Objects
     let mut od = BTreeMap::new();
+    let mut object_types = BTreeMap::new();
+    let mut object_names = BTreeMap::new();
+
+    for (section, properties) in ini.iter() {
 
-    for section in ini.sections().flatten() {
+        let Some(section) = section else {
+            continue;
+        };
 
         let (index, sub_index) = parse_section(section);
-        let parameter_name = ini.section(Some(section)).unwrap().get("ParameterName").unwrap_or_default().to_string();
-        let object_type = parse_str_to_u8(ini.section(Some(section)).unwrap().get("ObjectType").unwrap_or("0"));
-        let data_type = get_data_type(&parse_str_to_u32(ini.section(Some(section)).unwrap().get("DataType").unwrap_or("0")));
-        let default_value = ini.section(Some(section)).unwrap().get("DefaultValue").unwrap_or_default().to_string();
+        let parameter_name = properties.get("ParameterName").unwrap_or_default().to_string();
+        let object_type = parse_str_to_u8(properties.get("ObjectType").unwrap_or("0"))
+            .map_err(|e| format!("Invalid ObjectType in section [{}]: {e}", section))?;
+        let data_type = parse_str_to_u32(properties.get("DataType").unwrap_or("0"))
+            .map_err(|e| format!("Invalid DataType in section [{}]: {e}", section))?;
+        let data_type = get_data_type(&data_type);
+        let default_value = properties.get("DefaultValue").unwrap_or_default().to_string();
+
+        match object_type {
+            0x8 => { object_types.insert(index, ObjectType::Array); }
+            0x9 => { object_types.insert(index, ObjectType::Record); }
+            _ => {}
+        }
+        if matches!(object_type, 0x8 | 0x9) {
+            object_names.insert(index, parameter_name.clone());
+        }
 
         if object_type == 0x7 {
 
             let var = Var {
                 parameter_name,
-                access_type: ini.section(Some(section)).unwrap().get("AccessType").unwrap_or_default().to_string(),
-                value: parse_default_value(*node_id, data_type.clone(), default_value.clone().as_str()).unwrap(),
-                pdo_mapping: parse_str_to_bool(ini.section(Some(section)).unwrap().get("PDOMapping").unwrap_or_default()),
+                access_type: properties.get("AccessType").unwrap_or_default().to_string(),
+                value: parse_default_value(*node_id, data_type.clone(), default_value.as_str())
+                    .map_err(|e| format!("Invalid DefaultValue {:?} in section [{}]: {e}", default_value, section))?,
+                pdo_mapping: parse_str_to_bool(properties.get("PDOMapping").unwrap_or_default()),
             };
 
             log::debug!("Adding object with index: 0x{:X}, Sub Index: {}, Object type: {:?}, Default value: {}", index, sub_index, object_type, default_value);
@@ -217,12 +581,26 @@ pub fn parse_eds(node_id: &u8, eds_file: &String) -> Result<EDSData, Box<dyn std
     }
 
     // Create EDSData struct
-    let eds_data = EDSData {
+    let mut eds_data = EDSData {
         file_info,
         device_info,
-        od
+        od,
+        object_types,
+        object_names,
     };
 
+    // Identity object from the device info, when the EDS does not define it
+    let identity = [
+        (1, "Vendor-ID", eds_data.device_info.vendor_number),
+        (2, "Product code", eds_data.device_info.product_number),
+        (3, "Revision number", eds_data.device_info.revision_number),
+        (4, "Serial number", 0),
+    ];
+    eds_data.insert_var_if_missing(0x1018, 0, "Number of entries", "ro", DataValue::Unsigned8(4));
+    for (sub_index, parameter_name, value) in identity {
+        eds_data.insert_var_if_missing(0x1018, sub_index, parameter_name, "ro", DataValue::Unsigned32(value));
+    }
+
     Ok(eds_data)
 }
 
@@ -230,7 +608,9 @@ pub fn parse_eds(node_id: &u8, eds_file: &String) -> Result<EDSData, Box<dyn std
 fn parse_section(section: &str) -> (u16, u8) {
 
     // Split the input into the prefix and suffix
-    let (prefix, suffix) = section.split_at(4);
+    let Some((prefix, suffix)) = section.split_at_checked(4) else {
+        return (0, 0);
+    };
 
     // Check if the prefix is exactly 4 hex digits
     if let Ok(index) = u16::from_str_radix(prefix, 16) {
@@ -241,9 +621,9 @@ fn parse_section(section: &str) -> (u16, u8) {
             }
         }
         // If no "sub" prefix or parsing sub_index fails, return None
-        return (index, 0);
+        (index, 0)
     } else {
-        return (0, 0)
+        (0, 0)
     }
 }
 
@@ -256,20 +636,28 @@ fn parse_str_to_bool(hex_str: &str) -> bool {
     }
 }
 
-fn parse_str_to_u8(hex_str: &str) -> u8 {
+/// Parse a hexadecimal number with the "0x" prefix or a decimal number.
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_str_to_u8(hex_str: &str) -> Result<u8, std::num::ParseIntError> {
 
     // Remove the "0x" prefix if it exists
     let trimmed_hex = hex_str.trim_start_matches("0x");
 
     // Convert the trimmed string to a u8 using base 16
-    u8::from_str_radix(trimmed_hex, 16).unwrap()
+    u8::from_str_radix(trimmed_hex, 16)
 }
 
-fn parse_str_to_u32(hex_str: &str) -> u32 {
+fn parse_str_to_u32(hex_str: &str) -> Result<u32, std::num::ParseIntError> {
 
     // Remove the "0x" prefix if it exists
     let trimmed_hex = hex_str.trim_start_matches("0x");
 
     // Convert the trimmed string to a u8 using base 16
-    u32::from_str_radix(trimmed_hex, 16).unwrap()
+    u32::from_str_radix(trimmed_hex, 16)
 }
\ No newline at end of file
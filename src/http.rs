@@ -0,0 +1,223 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tokio::sync::oneshot;
+
+use canopen_tokio::nmt::NmtCommand;
+
+use crate::cia301::{NodeMetrics, NodeRegistry, NodeRequest};
+use crate::eds::{DataValue, Var};
+
+type HttpError = (StatusCode, String);
+
+/// Name, type, help text and value of a metric
+type MetricFamily = (&'static str, &'static str, &'static str, fn(&NodeMetrics) -> f64);
+
+/// Serve the object dictionaries of the running nodes as JSON:
+/// `GET /node/{node_id}/od` for all objects and `GET /node/{node_id}/od/{index}/{sub_index}` for a single object.
+/// `POST /node/{node_id}/od/{index}/{sub_index}` with `{"value": 6}` writes an object as an SDO download does
+/// and `POST /node/{node_id}/nmt` with `{"command": "start"}` applies an NMT command.
+/// `GET /metrics` publishes the state and frame counters of all nodes in the Prometheus text format.
+pub async fn serve(listener: TcpListener, registry: NodeRegistry) {
+
+    let router = Router::new()
+        .route("/node/{node_id}/od", get(read_objects))
+        .route("/node/{node_id}/od/{index}/{sub_index}", get(read_object).post(write_object))
+        .route("/node/{node_id}/nmt", post(nmt_command))
+        .route("/metrics", get(metrics))
+        .with_state(registry);
+
+    if let Err(e) = axum::serve(listener, router).await {
+        log::error!("HTTP server stopped: {e}");
+    }
+
+}
+
+async fn read_objects(
+    State(registry): State<NodeRegistry>,
+    Path(node_id): Path<u8>,
+) -> Result<Json<Value>, HttpError> {
+
+    let objects = request_objects(&registry, node_id, None).await?;
+
+    Ok(Json(Value::Array(objects.iter().map(object_json).collect())))
+}
+
+async fn read_object(
+    State(registry): State<NodeRegistry>,
+    Path((node_id, index, sub_index)): Path<(u8, String, String)>,
+) -> Result<Json<Value>, HttpError> {
+
+    let (index, sub_index) = parse_object(&index, &sub_index)?;
+    let objects = request_objects(&registry, node_id, Some((index, sub_index))).await?;
+
+    objects.first()
+        .map(|object| Json(object_json(object)))
+        .ok_or((StatusCode::NOT_FOUND, format!("Object 0x{:04X} sub {} not found on node {}", index, sub_index, node_id)))
+}
+
+async fn write_object(
+    State(registry): State<NodeRegistry>,
+    Path((node_id, index, sub_index)): Path<(u8, String, String)>,
+    Json(body): Json<Value>,
+) -> Result<Json<Value>, HttpError> {
+
+    let (index, sub_index) = parse_object(&index, &sub_index)?;
+
+    // The data type of the object determines the encoding of the value
+    let objects = request_objects(&registry, node_id, Some((index, sub_index))).await?;
+    let (_, _, var) = objects.first()
+        .ok_or((StatusCode::NOT_FOUND, format!("Object 0x{:04X} sub {} not found on node {}", index, sub_index, node_id)))?;
+    let value = body.get("value")
+        .and_then(|value| json_to_value(&var.value, value))
+        .ok_or((StatusCode::BAD_REQUEST, format!("Invalid value for {} object: {}", var.value.type_name(), body)))?;
+
+    let (reply, response) = oneshot::channel();
+    request(&registry, node_id, NodeRequest::WriteObject { index, sub_index, data: value.to_le_bytes(), reply }).await?;
+    response.await.map_err(|_| stopped(node_id))?
+        .map_err(|abort_code| (StatusCode::BAD_REQUEST, format!("Write rejected with SDO abort code 0x{:08X}", abort_code)))?;
+
+    let objects = request_objects(&registry, node_id, Some((index, sub_index))).await?;
+    Ok(Json(objects.first().map(object_json).unwrap_or_default()))
+}
+
+async fn nmt_command(
+    State(registry): State<NodeRegistry>,
+    Path(node_id): Path<u8>,
+    Json(body): Json<Value>,
+) -> Result<StatusCode, HttpError> {
+
+    let command = match body.get("command").and_then(Value::as_str) {
+        Some("start") => NmtCommand::Start,
+        Some("stop") => NmtCommand::Stop,
+        Some("pre-operational") => NmtCommand::GoToPreOperational,
+        Some("reset") => NmtCommand::Reset,
+        Some("reset-communication") => NmtCommand::ResetCommunication,
+        _ => return Err((StatusCode::BAD_REQUEST, format!(
+            "Invalid NMT command {}, expected start, stop, pre-operational, reset or reset-communication", body))),
+    };
+
+    let (reply, response) = oneshot::channel();
+    request(&registry, node_id, NodeRequest::Nmt { command, reply }).await?;
+    response.await.map_err(|_| stopped(node_id))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn metrics(State(registry): State<NodeRegistry>) -> String {
+
+    let senders: Vec<_> = registry.read().unwrap().iter()
+        .map(|((interface, node_id), sender)| (interface.clone(), *node_id, sender.clone()))
+        .collect();
+
+    let mut nodes = Vec::new();
+    for (interface, node_id, sender) in senders {
+        let (reply, response) = oneshot::channel();
+        if sender.send(NodeRequest::Metrics { reply }).await.is_ok() {
+            if let Ok(metrics) = response.await {
+                nodes.push((format!("interface=\"{}\",node=\"{}\"", interface, node_id), metrics));
+            }
+        }
+    }
+
+    let families: [MetricFamily; 8] = [
+        ("twin_statusword", "gauge", "Statusword (0x6041).", |metrics| metrics.statusword as f64),
+        ("twin_position_actual_value", "gauge", "Position actual value (0x6064) in user units.", |metrics| metrics.position),
+        ("twin_velocity_actual_value", "gauge", "Velocity actual value (0x606C) in user units.", |metrics| metrics.velocity),
+        ("twin_nmt_state", "gauge", "NMT state: 0 initializing, 4 stopped, 5 operational, 127 pre-operational.", |metrics| metrics.nmt_state as f64),
+        ("twin_frames_received_total", "counter", "Frames received by the node.", |metrics| metrics.frames_received as f64),
+        ("twin_frames_sent_total", "counter", "Frames sent by the node.", |metrics| metrics.frames_sent as f64),
+        ("twin_sdo_aborts_total", "counter", "SDO transfers aborted by the node.", |metrics| metrics.sdo_aborts as f64),
+        ("twin_sync_window_drops_total", "counter", "Synchronous TPDOs not sent because the synchronous window (0x1007) had passed.", |metrics| metrics.sync_window_drops as f64),
+    ];
+
+    let mut text = String::new();
+    for (name, metric_type, help, value) in families {
+        text += &format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type);
+        for (labels, metrics) in &nodes {
+            text += &format!("{}{{{}}} {}\n", name, labels, value(metrics));
+        }
+    }
+
+    text
+}
+
+/// Read objects from the first running node with the node id.
+async fn request_objects(registry: &NodeRegistry, node_id: u8, object: Option<(u16, u8)>) -> Result<Vec<(u16, u8, Var)>, HttpError> {
+
+    let (reply, response) = oneshot::channel();
+    request(registry, node_id, NodeRequest::ReadObjects { object, reply }).await?;
+    response.await.map_err(|_| stopped(node_id))
+}
+
+/// Send a request to the first running node with the node id.
+async fn request(registry: &NodeRegistry, node_id: u8, request: NodeRequest) -> Result<(), HttpError> {
+
+    let sender = registry.read().unwrap().iter()
+        .find(|((_, id), _)| *id == node_id)
+        .map(|(_, sender)| sender.clone())
+        .ok_or((StatusCode::NOT_FOUND, format!("Node {} not running", node_id)))?;
+
+    sender.send(request).await.map_err(|_| stopped(node_id))
+}
+
+fn stopped(node_id: u8) -> HttpError {
+    (StatusCode::SERVICE_UNAVAILABLE, format!("Node {} stopped", node_id))
+}
+
+/// Parse an index and sub index, decimal or hexadecimal with a `0x` prefix.
+fn parse_object(index: &str, sub_index: &str) -> Result<(u16, u8), HttpError> {
+
+    let parse = |text: &str| match text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    };
+
+    let index = parse(index).and_then(|value| u16::try_from(value).ok())
+        .ok_or((StatusCode::BAD_REQUEST, format!("Invalid index {}", index)))?;
+    let sub_index = parse(sub_index).and_then(|value| u8::try_from(value).ok())
+        .ok_or((StatusCode::BAD_REQUEST, format!("Invalid sub index {}", sub_index)))?;
+
+    Ok((index, sub_index))
+}
+
+fn object_json((index, sub_index, var): &(u16, u8, Var)) -> Value {
+    json!({
+        "index": index,
+        "sub": sub_index,
+        "name": var.parameter_name,
+        "type": var.value.type_name(),
+        "value": var.value.to_json(),
+    })
+}
+
+/// Convert a JSON value to a value of the same data type as the object.
+fn json_to_value(object: &DataValue, value: &Value) -> Option<DataValue> {
+    Some(match object {
+        DataValue::Unknown(_) => return None,
+        DataValue::Boolean(_) => DataValue::Boolean(value.as_bool()?),
+        DataValue::Integer8(_) => DataValue::Integer8(value.as_i64()?.try_into().ok()?),
+        DataValue::Integer16(_) => DataValue::Integer16(value.as_i64()?.try_into().ok()?),
+        DataValue::Integer24(_) => DataValue::Integer24(value.as_i64().filter(|value| (-0x80_0000..0x80_0000).contains(value))? as i32),
+        DataValue::Integer32(_) => DataValue::Integer32(value.as_i64()?.try_into().ok()?),
+        DataValue::Unsigned8(_) => DataValue::Unsigned8(value.as_u64()?.try_into().ok()?),
+        DataValue::Unsigned16(_) => DataValue::Unsigned16(value.as_u64()?.try_into().ok()?),
+        DataValue::Unsigned24(_) => DataValue::Unsigned24(value.as_u64().filter(|&value| value <= 0xFF_FFFF)? as u32),
+        DataValue::Unsigned32(_) => DataValue::Unsigned32(value.as_u64()?.try_into().ok()?),
+        DataValue::Real32(_) => DataValue::Real32(value.as_f64()? as f32),
+        DataValue::VisibleString(_) => DataValue::VisibleString(value.as_str()?.to_string()),
+        DataValue::OctetString(_) | DataValue::Domain(_) => {
+            let hex = value.as_str()?;
+            let bytes = (0..hex.len() / 2)
+                .map(|i| u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            match object {
+                DataValue::OctetString(_) => DataValue::OctetString(bytes),
+                _ => DataValue::Domain(bytes),
+            }
+        }
+    })
+}
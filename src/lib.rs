@@ -0,0 +1,31 @@
+//! Digital twin of CANopen CiA 402 motor controllers, to run as a binary or embedded in another program.
+
+// Errors are logged where they occur, the callers only need to know that something failed.
+#![allow(clippy::result_unit_err)]
+
+mod bus;
+mod bus_load;
+mod clock;
+pub mod eds;
+pub mod config;
+mod cia301;
+mod cia402_runner;
+mod conformance;
+mod coupling;
+mod sdo;
+mod pdo;
+mod lss;
+mod profile;
+mod rng;
+#[cfg(feature = "http")]
+mod http;
+mod twin;
+mod xdd;
+#[cfg(test)]
+mod testing;
+
+pub use bus::{CanTransport, MockBus, TransportFuture};
+pub use cia301::NodeStats;
+pub use conformance::{Violation, WriteSource};
+pub use clock::{Clock, ManualClock, RealClock, TickFuture, Ticker};
+pub use twin::{DigitalTwin, DigitalTwinBuilder};
@@ -0,0 +1,207 @@
+use can_socket::{CanFrame, CanId};
+use canopen_tokio::nmt::NmtState;
+
+use crate::cia301::{self, Node};
+use crate::config::UNCONFIGURED_NODE_ID;
+use crate::eds::{self, DataValue};
+
+/// COB-ID of the LSS requests of the master
+pub const LSS_REQUEST_COB_ID: u32 = 0x7E5;
+
+/// COB-ID of the LSS responses of the slaves
+const LSS_RESPONSE_COB_ID: u16 = 0x7E4;
+
+/// LSS state of a node and the node id configured over LSS
+#[derive(Debug, Default)]
+pub struct LssSlave {
+
+    /// The node is in the LSS configuration state, otherwise in the waiting state.
+    pub configuration: bool,
+
+    /// Number of identity values matched by the switch state selective requests, in the order vendor, product, revision and serial number.
+    pub selective_matched: u8,
+
+    /// Node id configured over LSS or written to the node id object, activated on an NMT reset or, for an unconfigured node,
+    /// when switching to the waiting state.
+    pub pending_node_id: Option<u8>,
+}
+
+impl Node {
+
+    pub(crate) async fn parse_lss_request(&mut self, data: &[u8]) {
+
+        // LSS requests always have 8 bytes
+        let Ok(data) = <&[u8; 8]>::try_from(data) else {
+            log::warn!(target: &self.log_target, "LSS request node {} ignored: received {} bytes, expected 8", self.node_id, data.len());
+            return;
+        };
+
+        let command_specifier = data[0];
+        let value = u32::from_le_bytes([data[1], data[2], data[3], data[4]]);
+
+        match command_specifier {
+
+            // Switch state global
+            0x04 => match data[1] {
+                0 => self.lss_switch_to_waiting().await,
+                1 => self.lss.configuration = true,
+                mode => log::warn!(target: &self.log_target, "LSS switch state global node {} with invalid mode {}", self.node_id, mode),
+            },
+
+            // Switch state selective, vendor id, product code, revision number and serial number in sequence
+            0x40..=0x43 => {
+                let step = command_specifier - 0x40;
+                if step == self.lss.selective_matched && value == self.identity(step + 1) {
+                    self.lss.selective_matched += 1;
+                } else {
+                    self.lss.selective_matched = 0;
+                }
+                if self.lss.selective_matched == 4 {
+                    self.lss.selective_matched = 0;
+                    self.lss.configuration = true;
+                    self.send_lss_response([0x44, 0, 0, 0, 0, 0, 0, 0]).await;
+                }
+            }
+
+            // Identify non-configured remote slave
+            0x4C if self.node_id == UNCONFIGURED_NODE_ID => self.send_lss_response([0x50, 0, 0, 0, 0, 0, 0, 0]).await,
+
+            // The other services are only answered in the configuration state
+            _ if !self.lss.configuration => {},
+
+            // Configure node id, error code 1 when the node id is out of range
+            0x11 => {
+                let node_id = data[1];
+                let error_code = if (1..=127).contains(&node_id) || node_id == UNCONFIGURED_NODE_ID {
+                    self.lss.pending_node_id = Some(node_id);
+                    log::info!(target: &self.log_target, "LSS node {}: node id {} configured", self.node_id, node_id);
+                    0
+                } else {
+                    1
+                };
+                self.send_lss_response([0x11, error_code, 0, 0, 0, 0, 0, 0]).await;
+            }
+
+            // Configure bit timing, not supported
+            0x13 => self.send_lss_response([0x13, 1, 0, 0, 0, 0, 0, 0]).await,
+
+            // Store configuration, the configured node id is kept for the lifetime of the node
+            0x17 => self.send_lss_response([0x17, 0, 0, 0, 0, 0, 0, 0]).await,
+
+            // Inquire vendor id, product code, revision number and serial number
+            0x5A..=0x5D => {
+                let mut response = [command_specifier, 0, 0, 0, 0, 0, 0, 0];
+                response[1..5].copy_from_slice(&self.identity(command_specifier - 0x59).to_le_bytes());
+                self.send_lss_response(response).await;
+            }
+
+            // Inquire node id
+            0x5E => self.send_lss_response([0x5E, self.node_id, 0, 0, 0, 0, 0, 0]).await,
+
+            _ => log::warn!(target: &self.log_target, "LSS command 0x{:02X} not implemented", command_specifier),
+        }
+
+    }
+
+    /// Leave the configuration state, an unconfigured node starts with the node id configured over LSS.
+    async fn lss_switch_to_waiting(&mut self) {
+
+        self.lss.configuration = false;
+
+        if self.node_id == UNCONFIGURED_NODE_ID && self.activate_pending_node_id() {
+            self.set_nmt_state(NmtState::Initializing);
+            self.send_new_nmt_state().await;
+            self.self_start().await;
+        }
+
+    }
+
+    /// Switch to the node id configured over LSS or the node id object, returns true when the node id changed.
+    pub(crate) fn activate_pending_node_id(&mut self) -> bool {
+
+        let Some(node_id) = self.lss.pending_node_id.take() else {
+            return false;
+        };
+        if node_id == self.node_id {
+            return false;
+        }
+
+        log::info!(target: &self.log_target, "Node {}: node id {} activated", self.node_id, node_id);
+        let previous_node_id = self.node_id;
+        self.node_id = node_id;
+        self.log_target = cia301::log_target(node_id);
+        self.resolve_node_id_objects(previous_node_id);
+        self.update_node_id_object();
+        self.update_pdo_mappings();
+
+        true
+    }
+
+    /// Set the objects with a default value that depends on the node id ($NODEID in the EDS) to the default of the current node id.
+    fn resolve_node_id_objects(&mut self, previous_node_id: u8) {
+
+        let (previous, current) = match (eds::parse_eds(&previous_node_id, &self.eds_file), eds::parse_eds(&self.node_id, &self.eds_file)) {
+            (Ok(previous), Ok(current)) => (previous, current),
+            (Err(e), _) | (_, Err(e)) => {
+                log::error!(target: &self.log_target, "Failed to parse {} for node {}, objects with $NODEID not updated: {e}", self.eds_file.display(), self.node_id);
+                return;
+            }
+        };
+
+        for (&index, vars) in current.od.iter() {
+            for (&sub_index, var) in vars.iter() {
+                let Some(previous_default) = previous.get_var(index, sub_index) else {
+                    continue;
+                };
+                if previous_default.value.to_le_bytes() != var.value.to_le_bytes() {
+                    self.eds_data.set_dataval(index, sub_index, var.value.clone());
+                    self.default_eds_data.set_dataval(index, sub_index, var.value.clone());
+                }
+            }
+        }
+
+    }
+
+    /// Add the configured node id object when the EDS does not define it.
+    pub(crate) fn add_node_id_object(&mut self) {
+        if let Some(index) = self.node_id_index {
+            self.eds_data.insert_var_if_missing(index, 0, "Node id", "rw", DataValue::Unsigned8(self.node_id));
+        }
+    }
+
+    /// Set the configured node id object to the active node id, in the data type of the object.
+    pub(crate) fn update_node_id_object(&mut self) {
+
+        let Some(index) = self.node_id_index else {
+            return;
+        };
+        let Some(var) = self.eds_data.get_var(index, 0) else {
+            return;
+        };
+
+        let mut data = var.value.to_le_bytes();
+        data.iter_mut().for_each(|byte| *byte = 0);
+        if let Some(byte) = data.first_mut() {
+            *byte = self.node_id;
+        }
+        if let Some(value) = var.value.with_le_bytes(&data) {
+            self.eds_data.set_dataval(index, 0, value);
+        }
+
+    }
+
+    /// Value of the identity object (0x1018) at the sub index.
+    fn identity(&self, sub_index: u8) -> u32 {
+        self.eds_data.get_f64(0x1018, sub_index).unwrap_or(0.) as u32
+    }
+
+    async fn send_lss_response(&self, data: [u8; 8]) {
+
+        let cob_id = CanId::new_base(LSS_RESPONSE_COB_ID).unwrap();
+        let frame = &CanFrame::new(cob_id, &data, None).unwrap();
+
+        self.send_frame(frame).await;
+
+    }
+
+}
@@ -1,17 +1,14 @@
-use can_socket::tokio::CanSocket;
+use serde_json::json;
+use std::io::Write;
 use std::path::PathBuf;
-use tokio::task;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use futures::future;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 
-mod eds;
-mod config;
-mod cia301;
-mod cia402_runner;
+use fake_motorcontroller::config::Config;
+use fake_motorcontroller::DigitalTwin;
 
-use crate::cia301::Node;
-use crate::config::Config;
+/// Time the nodes get to announce that they stop before the process exits anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(clap::Parser)]
 struct Options {
@@ -19,65 +16,219 @@ struct Options {
     #[clap(long, short)]
     #[clap(value_name = "CONFIG.toml")]
     config: PathBuf,
+
+    /// Replay the frames of a candump log to the nodes instead of opening the CAN interfaces.
+    #[clap(long)]
+    #[clap(value_name = "TRACE.log")]
+    replay: Option<PathBuf>,
+
+    /// Serve the object dictionaries of the nodes over HTTP on this address.
+    #[cfg(feature = "http")]
+    #[clap(long)]
+    #[clap(value_name = "ADDRESS")]
+    http: Option<std::net::SocketAddr>,
+
+    /// Print the object dictionaries of the nodes and exit, without opening the CAN interfaces.
+    #[clap(long)]
+    dump_od: bool,
+
+    /// Output format of the object dictionary dump.
+    #[clap(long, value_enum, default_value_t, requires = "dump_od")]
+    format: Format,
+
+    /// Format of the log messages.
+    #[clap(long, value_enum, default_value_t)]
+    log_format: LogFormat,
+
+    /// Exit with a non-zero code when the master broke a conformance rule of the configuration.
+    #[clap(long)]
+    fail_on_violation: bool,
+}
+
+/// Output format of the object dictionary dump
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum Format {
+    /// A table per node.
+    #[default]
+    Table,
+    /// An array of nodes with their objects as JSON.
+    Json,
+}
+
+/// Format of the log messages
+#[derive(Clone, Copy, Default, clap::ValueEnum)]
+enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Plain,
+    /// A JSON object per line with the timestamp, level, node and message.
+    Json,
 }
 
 #[tokio::main]
 async fn main() {
 
+    let options: Options = clap::Parser::parse();
+
     // Initialize the logging system.
-    env_logger::builder()
+    let mut builder = env_logger::builder();
+    builder
         .filter_module(module_path!(), log::LevelFilter::Info)
-        .parse_default_env()
-        .init();
+        .filter_module("twin", log::LevelFilter::Info)
+        .parse_default_env();
+    if let LogFormat::Json = options.log_format {
+        builder.format(|buf, record| {
+            // Nodes log to `twin::node::<node id>`, other messages have no node
+            let node = record.target().strip_prefix("twin::node::").and_then(|id| id.parse::<u8>().ok());
+            writeln!(buf, "{}", json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "node": node,
+                "message": record.args().to_string(),
+            }))
+        });
+    }
+    builder.init();
 
 
     // Run the server and set a non-zero exit code if we had an error.
-    do_main(clap::Parser::parse()).await.ok();
+    if do_main(options).await.is_err() {
+        std::process::exit(1);
+    }
 
 }
 
 async fn do_main(options: Options) -> Result<(), ()> {
 
     // Read the configuration file.
-    let config = Config::read_from_file(&options.config)?;
-    
-    // Initialize nodes
-    let mut nodes = Vec::new();
-
-    // Build nodes from eds files and bind socket
-    for node in config.node.iter() {
-
-        // Bind socket
-        let socket = CanSocket::bind(&config.bus.interface).map_err(|e| {
-            log::error!("Failed to create CAN socket for interface {}: {e}", &config.bus.interface)
-        })?;
-        log::info!("CAN bus on interface {} opened for node {}", &config.bus.interface, node.node_id);
-
-        // Parse eds data
-        let node_data = eds::parse_eds(&node.node_id, &node.eds_file).unwrap();
-
-        // Initialize controller
-        let node= Arc::new(Mutex::new(
-            Node::initialize(socket, node.node_id, node_data).await.unwrap()
-        ));
-        nodes.push(node);
+    let config = Config::read_from_file(&options.config).map_err(|e| log::error!("{e}"))?;
+
+    // Reload the configuration on SIGHUP, stop the nodes on SIGINT and SIGTERM
+    let mut hangup = signal(SignalKind::hangup())
+        .map_err(|e| log::error!("Failed to register SIGHUP handler: {e}"))?;
+    let mut interrupt = signal(SignalKind::interrupt())
+        .map_err(|e| log::error!("Failed to register SIGINT handler: {e}"))?;
+    let mut terminate = signal(SignalKind::terminate())
+        .map_err(|e| log::error!("Failed to register SIGTERM handler: {e}"))?;
+
+    let mut twin = DigitalTwin::builder()
+        .config(config)
+        .detached(options.replay.is_some() || options.dump_od)
+        .build()
+        .map_err(|e| log::error!("{e}"))?;
+    if twin.start().await.is_err() {
+        if twin.nodes().next().is_none() {
+            log::error!("No node started");
+            return Err(());
+        }
+        log::warn!("Not all nodes started, continuing with the started nodes");
     }
 
-    let mut futures = Vec::new();
-
-    // Start nodes
-    for node in nodes.iter() {
-        let node_clone: Arc<Mutex<Node>>  = Arc::clone(node);
-        futures.push(
-            task::spawn(async move {
-            let mut node = node_clone.lock().await;
-            node.start_socket().await;
-            })
-        );
+    if options.dump_od {
+        return dump_od(&twin, options.format).await;
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(address) = options.http {
+        let listener = tokio::net::TcpListener::bind(address).await
+            .map_err(|e| log::error!("Failed to bind HTTP server to {address}: {e}"))?;
+        log::info!("HTTP server listening on {}", address);
+        tokio::task::spawn(twin.serve_http(listener));
+    }
+
+    let result = tokio::select! {
+        result = run(&mut twin, &options, &mut hangup) => result,
+        _ = interrupt.recv() => {
+            log::info!("Interrupted, stopping the nodes");
+            Ok(())
+        }
+        _ = terminate.recv() => {
+            log::info!("Terminated, stopping the nodes");
+            Ok(())
+        }
+    };
+
+    // A node that does not stop in time is left behind, the process exits anyway
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, twin.shutdown()).await.is_err() {
+        log::error!("Nodes did not stop within {:?}", SHUTDOWN_TIMEOUT);
+        return Err(());
+    }
+
+    let violations = twin.violations();
+    for violation in &violations {
+        log::warn!("Conformance violation: {}", violation);
+    }
+    if options.fail_on_violation && !violations.is_empty() {
+        log::error!("The master broke {} conformance rules", violations.len());
+        return Err(());
+    }
+
+    result
+}
+
+/// Replay the trace when requested, otherwise reload the configuration on SIGHUP until the process is stopped.
+async fn run(twin: &mut DigitalTwin, options: &Options, hangup: &mut Signal) -> Result<(), ()> {
+
+    if let Some(path) = &options.replay {
+        return twin.replay(path).await;
+    }
+
+    loop {
+
+        hangup.recv().await;
+        log::info!("Reloading configuration from {}", options.config.display());
+
+        match Config::read_from_file(&options.config) {
+            Ok(config) => {
+                if twin.reload(config).await.is_err() {
+                    log::error!("Failed to apply the reloaded configuration");
+                }
+            }
+            Err(e) => log::error!("{e}, keeping the running configuration"),
+        }
+
     }
 
-    future::join_all(futures).await;
-    
-    Ok(())
 }
 
+/// Print the object dictionaries of the started nodes with the current values.
+async fn dump_od(twin: &DigitalTwin, format: Format) -> Result<(), ()> {
+
+    let nodes: Vec<(String, u8)> = twin.nodes().map(|(interface, node_id)| (interface.to_string(), node_id)).collect();
+    let mut dumps = Vec::new();
+
+    for (interface, node_id) in nodes {
+
+        let eds_data = twin.eds_data(node_id).await.ok_or_else(|| log::error!("Node {} stopped", node_id))?;
+        let objects = eds_data.od.iter()
+            .flat_map(|(&index, vars)| vars.iter().map(move |(&sub_index, var)| (index, sub_index, var)));
+
+        match format {
+            Format::Table => {
+                println!("Node {} on interface {}", node_id, interface);
+                println!("{:<6} {:<4} {:<48} {:<13} {:<6} Value", "Index", "Sub", "Name", "Type", "Access");
+                for (index, sub_index, var) in objects {
+                    println!("0x{:04X} {:<4} {:<48} {:<13} {:<6} {}", index, sub_index, var.parameter_name, var.value.type_name(), var.access_type, var.value.to_json());
+                }
+                println!();
+            }
+            Format::Json => {
+                let objects: Vec<_> = objects.map(|(index, sub_index, var)| json!({
+                    "index": index,
+                    "sub": sub_index,
+                    "name": var.parameter_name,
+                    "type": var.value.type_name(),
+                    "access": var.access_type,
+                    "value": var.value.to_json(),
+                })).collect();
+                dumps.push(json!({ "interface": interface, "node_id": node_id, "objects": objects }));
+            }
+        }
+    }
+
+    if let Format::Json = format {
+        println!("{}", serde_json::Value::Array(dumps));
+    }
+
+    Ok(())
+}
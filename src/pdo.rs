@@ -0,0 +1,398 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use can_socket::{CanFrame, CanId};
+use canopen_tokio::nmt::NmtState;
+
+use crate::cia301::Node;
+use crate::eds::DataValue;
+
+/// Number of RPDOs received on the predefined COB-IDs
+const RPDO_COUNT: u16 = 4;
+
+/// Number of TPDOs sent on SYNC or on a change of the mapped objects
+const TPDO_COUNT: u16 = 8;
+
+/// Object mapped into a PDO, from a sub index of the mapping parameter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MappedObject {
+    pub index: u16,
+    pub sub_index: u8,
+    /// Length of the object in the PDO in bits
+    pub bits: u8,
+}
+
+impl MappedObject {
+    /// Index 0x0000 or a dummy entry (the index of a standard data type) only pads the PDO to align the next object.
+    pub fn is_padding(&self) -> bool {
+        self.index <= 0x0007
+    }
+}
+
+/// Communication and mapping parameters of a TPDO
+#[derive(Clone, Debug)]
+pub struct Tpdo {
+    /// COB-ID (sub 1 of the communication parameter)
+    pub cob_id: u32,
+    /// Bit 31 of the COB-ID is not set
+    pub enabled: bool,
+    pub transmission_type: u8,
+    /// Shortest time between two event driven transmissions (sub 3 in multiples of 100 µs)
+    pub inhibit_time: Duration,
+    pub objects: Vec<MappedObject>,
+    /// Data and simulation time of the last event driven transmission
+    pub last_event: Option<(Vec<u8>, Duration)>,
+    /// SYNCs counted since the last transmission of a cyclic synchronous TPDO, for a SYNC without counter
+    pub sync_count: u8,
+    /// Data of the last transmission on a SYNC, an acyclic synchronous TPDO is only sent again when it changed
+    pub last_sync_data: Option<Vec<u8>>,
+}
+
+/// PDO parameters taken from the object dictionary, so a PDO or SYNC does not search the mapping.
+/// Rebuilt when the object dictionary is replaced or an object of 0x1400 to 0x1BFF is written.
+#[derive(Debug, Default)]
+pub struct PdoMappings {
+    /// Mapped objects of RPDO 1 to 4
+    pub rpdos: Vec<Vec<MappedObject>>,
+    /// COB-ID (sub 1 of the communication parameter) of RPDO 1 to 4, None when the RPDO is not valid (bit 31) or missing
+    pub rpdo_cob_ids: Vec<Option<u32>>,
+    /// TPDO 1 to 8, None when the communication or mapping parameter is missing
+    pub tpdos: Vec<Option<Tpdo>>,
+}
+
+impl PdoMappings {
+    /// Whether the PDO parameters depend on the object.
+    pub fn contains(index: u16) -> bool {
+        matches!(index, 0x1400..=0x1BFF)
+    }
+
+    /// Mapped objects of the mapping parameter of an RPDO (0x1600 to 0x1603) or a TPDO (0x1A00 to 0x1A07).
+    pub fn objects(&self, mapping_index: u16) -> &[MappedObject] {
+        let objects = match mapping_index {
+            0x1600..=0x17FF => self.rpdos.get((mapping_index - 0x1600) as usize),
+            0x1A00..=0x1BFF => self.tpdos.get((mapping_index - 0x1A00) as usize).and_then(|tpdo| Some(&tpdo.as_ref()?.objects)),
+            _ => None,
+        };
+        objects.map_or(&[], |objects| objects.as_slice())
+    }
+}
+
+impl Node {
+
+    /// Rebuild the PDO parameters from the object dictionary.
+    pub(crate) fn update_pdo_mappings(&mut self) {
+
+        let rpdos = (0..RPDO_COUNT)
+            .map(|i| self.mapped_objects(0x1600 + i).unwrap_or_default())
+            .collect();
+        let rpdo_cob_ids = (0..RPDO_COUNT)
+            .map(|i| match self.eds_data.get_var(0x1400 + i, 1)?.value {
+                DataValue::Unsigned32(cob_id) if cob_id & (1 << 31) == 0 => Some(cob_id),
+                _ => None,
+            })
+            .collect();
+        let tpdos = (0..TPDO_COUNT)
+            .map(|i| self.tpdo(i))
+            .collect();
+
+        let pdo_mappings = PdoMappings { rpdos, rpdo_cob_ids, tpdos };
+        self.warn_missing_mapped_objects(&pdo_mappings);
+        self.pdo_mappings = pdo_mappings;
+
+    }
+
+    /// Warn for the mapped objects that are not in the object dictionary, they are left out of the PDO so it is shorter
+    /// than its mapping. Only entries that changed are reported, not every rebuild of the PDO parameters.
+    fn warn_missing_mapped_objects(&self, pdo_mappings: &PdoMappings) {
+
+        let mapping_indices = (0..RPDO_COUNT).map(|i| 0x1600 + i).chain((0..TPDO_COUNT).map(|i| 0x1A00 + i));
+
+        for mapping_index in mapping_indices {
+
+            let previous = self.pdo_mappings.objects(mapping_index);
+
+            for (entry, object) in pdo_mappings.objects(mapping_index).iter().enumerate() {
+                if object.is_padding() || self.eds_data.get_var(object.index, object.sub_index).is_some() || previous.get(entry) == Some(object) {
+                    continue;
+                }
+                log::warn!(target: &self.log_target, "PDO mapping 0x{:04X} sub {} of node {} maps index 0x{:04X} sub {}, which is not in the object dictionary and left out of the PDO",
+                    mapping_index, entry + 1, self.node_id, object.index, object.sub_index);
+            }
+        }
+
+    }
+
+    fn tpdo(&self, i: u16) -> Option<Tpdo> {
+
+        let DataValue::Unsigned32(cob_id) = self.eds_data.get_var(0x1800 + i, 1)?.value else {
+            return None;
+        };
+        let DataValue::Unsigned8(transmission_type) = self.eds_data.get_var(0x1800 + i, 2)?.value else {
+            return None;
+        };
+
+        let inhibit_time = match self.eds_data.get_var(0x1800 + i, 3).map(|var| &var.value) {
+            Some(DataValue::Unsigned16(inhibit_time)) => Duration::from_micros(*inhibit_time as u64 * 100),
+            _ => Duration::ZERO,
+        };
+
+        Some(Tpdo {
+            cob_id,
+            enabled: cob_id & (1 << 31) == 0,
+            transmission_type,
+            inhibit_time,
+            objects: self.mapped_objects(0x1A00 + i)?,
+            last_event: None,
+            sync_count: 0,
+            last_sync_data: None,
+        })
+    }
+
+    /// Objects mapped by sub index 1 up to the number of entries (sub index 0) of a mapping parameter, None without a number of entries.
+    fn mapped_objects(&self, index: u16) -> Option<Vec<MappedObject>> {
+
+        let DataValue::Unsigned8(number_of_entries) = self.eds_data.get_var(index, 0)?.value else {
+            return None;
+        };
+
+        let objects = (1..=number_of_entries)
+            .filter_map(|sub_index| match self.eds_data.get_var(index, sub_index)?.value {
+                DataValue::Unsigned32(mapping) => Some(MappedObject {
+                    index: (mapping >> 16) as u16,
+                    sub_index: ((mapping >> 8) & 0xFF) as u8,
+                    bits: (mapping & 0xFF) as u8,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Some(objects)
+    }
+
+    /// Send the event driven TPDOs (transmission type 254 and 255) in operational when a mapped object changed,
+    /// for example the mode of operation display (0x6061) after a mode switch. A changed TPDO waits for its inhibit time.
+    pub(crate) async fn send_event_tpdos(&mut self) {
+
+        if self.nmt_state != NmtState::Operational {
+            return;
+        }
+
+        let time = self.motor_controller.time;
+
+        for tpdo_number in 0..self.pdo_mappings.tpdos.len() {
+
+            let Some(tpdo) = &self.pdo_mappings.tpdos[tpdo_number] else {
+                continue;
+            };
+            if !tpdo.enabled || tpdo.transmission_type < 254 || tpdo.objects.is_empty() {
+                continue;
+            }
+
+            let data = self.tpdo_data(tpdo);
+            let due = match &tpdo.last_event {
+                Some((sent, sent_time)) => *sent != data && time.saturating_sub(*sent_time) >= tpdo.inhibit_time,
+                None => true,
+            };
+            if !due {
+                continue;
+            }
+
+            self.send_tpdo(tpdo_number, &data).await;
+            if let Some(tpdo) = &mut self.pdo_mappings.tpdos[tpdo_number] {
+                tpdo.last_event = Some((data, time));
+            }
+        }
+
+    }
+
+    /// Data of the mapped objects of a TPDO, objects that are missing or do not match the mapped length are left out.
+    /// Padding entries are sent as zeros.
+    pub(crate) fn tpdo_data(&self, tpdo: &Tpdo) -> Vec<u8> {
+
+        let mut data = Vec::new();
+
+        for object in tpdo.objects.iter() {
+
+            if object.is_padding() {
+                data.resize(data.len() + (object.bits as usize).div_ceil(8), 0);
+                continue;
+            }
+
+            let Some(var) = self.eds_data.get_var(object.index, object.sub_index) else {
+                continue;
+            };
+
+            match (object.bits, &var.value) {
+                (0x08, DataValue::Unsigned8(value)) => data.extend(&value.to_le_bytes()),
+                (0x08, DataValue::Integer8(value)) => data.extend(&value.to_le_bytes()),
+                (0x10, DataValue::Unsigned16(value)) => data.extend(&value.to_le_bytes()),
+                (0x10, DataValue::Integer16(value)) => data.extend(&value.to_le_bytes()),
+                (0x18, DataValue::Unsigned24(_) | DataValue::Integer24(_)) => data.extend(var.value.to_le_bytes()),
+                (0x20, DataValue::Unsigned32(value)) => data.extend(&value.to_le_bytes()),
+                (0x20, DataValue::Integer32(value)) => data.extend(&value.to_le_bytes()),
+                (0x20, DataValue::Real32(value)) => data.extend(&value.to_le_bytes()),
+                _ => {},
+            }
+        }
+
+        data
+    }
+
+    /// Send TPDO 1 to 8 (tpdo_number 0 to 7) on the COB-ID of its communication parameter.
+    pub(crate) async fn send_tpdo(&self, tpdo_number: usize, data: &[u8]) {
+
+        let Some(Some(tpdo)) = self.pdo_mappings.tpdos.get(tpdo_number) else {
+            return;
+        };
+
+        // Bit 29 of the COB-ID selects an extended identifier
+        let id = tpdo.cob_id & 0x1FFF_FFFF;
+        let cob_id = match (tpdo.cob_id & (1 << 29) != 0, u16::try_from(id)) {
+            (true, _) => CanId::new_extended(id).ok(),
+            (false, Ok(id)) => CanId::new_base(id).ok(),
+            (false, Err(_)) => None,
+        };
+        let Some(cob_id) = cob_id else {
+            log::error!(target: &self.log_target, "COB-ID 0x{:08X} of TPDO{} of node {} is not a valid CAN identifier, the TPDO is not sent",
+                tpdo.cob_id, tpdo_number + 1, self.node_id);
+            return;
+        };
+
+        let frame = match CanFrame::new(cob_id, data, None) {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::error!(target: &self.log_target, "TPDO{} of node {} with {} bytes is not sent: {e}", tpdo_number + 1, self.node_id, data.len());
+                return;
+            }
+        };
+
+        self.send_frame(&frame).await;
+        self.counters.tpdos_sent.fetch_add(1, Ordering::Relaxed);
+
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config;
+    use crate::testing::{TestTwin, EDS_FILE};
+
+    #[tokio::test]
+    async fn tpdo_is_sent_on_the_configured_cob_id() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1801, 1, &0x8000_0281u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1801, 1, &0x0000_0391u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1801, 2, &[1]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x391).await.len(), 1);
+        assert!(twin.sent_with_id(1, 0x281).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn tpdo_with_invalid_cob_id_is_not_sent() {
+        let twin = TestTwin::start(&[1]).await;
+        twin.write(1, 0x1801, 1, &0x8000_0281u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1801, 1, &0x0000_0800u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1801, 2, &[1]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+
+        // The node keeps running and only leaves out the TPDO
+        twin.push(0x080, &[]);
+        let sent = twin.sent(1).await;
+        assert!(sent.iter().all(|frame| frame.id().as_u32() & 0x780 != 0x280));
+    }
+
+    #[tokio::test]
+    async fn rpdo_writes_two_subs_of_the_same_index() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // RPDO3 with the NanoJ inputs 1 and 2
+        twin.write(1, 0x1402, 1, &0x8000_0401u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1602, 1, &0x2400_0120u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 2, &0x2400_0220u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[2]).await.unwrap();
+        twin.write(1, 0x1402, 1, &0x0000_0401u32.to_le_bytes()).await.unwrap();
+        twin.nmt(0x01, 1);
+
+        let data: Vec<u8> = [1234i32.to_le_bytes(), (-5678i32).to_le_bytes()].concat();
+        twin.push(0x401, &data);
+
+        assert_eq!(twin.read_i32(1, 0x2400, 1).await, 1234);
+        assert_eq!(twin.read_i32(1, 0x2400, 2).await, -5678);
+    }
+
+    #[tokio::test]
+    async fn padding_between_mapped_objects() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // RPDO3 with a byte of padding between the NanoJ input 1 and the following error time out
+        twin.write(1, 0x1402, 1, &0x8000_0401u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1602, 1, &0x2400_0120u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 2, &0x0005_0008u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 3, &0x6066_0010u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[3]).await.unwrap();
+        twin.write(1, 0x1402, 1, &0x0000_0401u32.to_le_bytes()).await.unwrap();
+
+        // TPDO3 with the same layout, sent on every SYNC
+        twin.write(1, 0x1802, 1, &0x8000_0381u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1A02, 1, &0x2400_0120u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 2, &0x0005_0008u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 3, &0x6066_0010u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[3]).await.unwrap();
+        twin.write(1, 0x1802, 2, &[1]).await.unwrap();
+        twin.write(1, 0x1802, 1, &0x0000_0381u32.to_le_bytes()).await.unwrap();
+        twin.nmt(0x01, 1);
+
+        // The padding byte is skipped on receive and sent as zero
+        twin.push(0x401, &[1, 0, 0, 0, 0xFF, 0x34, 0x12]);
+        assert_eq!(twin.read_i32(1, 0x2400, 1).await, 1);
+        assert_eq!(twin.read_i32(1, 0x6066, 0).await, 0x1234);
+
+        twin.sent(1).await;
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x381).await, vec![vec![1, 0, 0, 0, 0, 0x34, 0x12]]);
+    }
+
+    #[tokio::test]
+    async fn real32_round_trip() {
+
+        // The EDS of the tests with a manufacturer object of the data type Real32
+        let eds = std::fs::read_to_string(EDS_FILE).unwrap()
+            + "\n[2FF0]\nParameterName=Scaled feedback\nObjectType=0x7\nDataType=0x0008\nAccessType=rww\nDefaultValue=0\nPDOMapping=1\n";
+        let eds_file = std::env::temp_dir().join(format!("real32-{}.eds", std::process::id()));
+        std::fs::write(&eds_file, eds).unwrap();
+        let twin = TestTwin::start_nodes(vec![config::Node::new(1, &eds_file)]).await;
+
+        // RPDO3 and TPDO3, sent on every SYNC, with the Real32 object
+        twin.write(1, 0x1402, 1, &0x8000_0401u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1602, 1, &0x2FF0_0020u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1602, 0, &[1]).await.unwrap();
+        twin.write(1, 0x1402, 1, &0x0000_0401u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1802, 1, &0x8000_0381u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1A02, 1, &0x2FF0_0020u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A02, 0, &[1]).await.unwrap();
+        twin.write(1, 0x1802, 2, &[1]).await.unwrap();
+        twin.write(1, 0x1802, 1, &0x0000_0381u32.to_le_bytes()).await.unwrap();
+        twin.nmt(0x01, 1);
+
+        let value = (-1234.5f32).to_le_bytes();
+        twin.push(0x401, &value);
+        assert_eq!(twin.read(1, 0x2FF0, 0).await, Ok(value.to_vec()));
+
+        twin.sent(1).await;
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x381).await, vec![value.to_vec()]);
+
+        std::fs::remove_file(eds_file).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+/// Jerk limited point to point profile that starts and ends at standstill, with separate acceleration and deceleration.
+/// Positions, velocities, accelerations and jerk are in any consistent unit, for example rotations and seconds.
+#[derive(Clone, Copy, Debug)]
+pub struct MotionProfile {
+    distance: f64,
+    velocity: f64,
+    acceleration: Ramp,
+    cruise_duration: f64,
+    deceleration: Ramp,
+}
+
+/// Change of the velocity between standstill and the peak velocity, with a constant jerk up to the maximum acceleration.
+#[derive(Clone, Copy, Debug)]
+struct Ramp {
+    velocity: f64,
+    jerk: f64,
+    /// Duration of each of the two constant jerk phases
+    jerk_duration: f64,
+    duration: f64,
+}
+
+impl Ramp {
+    fn new(velocity: f64, max_acceleration: f64, jerk: f64) -> Self {
+
+        // Without reaching the maximum acceleration the ramp only has the two constant jerk phases
+        let (jerk_duration, duration) = if velocity * jerk >= max_acceleration * max_acceleration {
+            (max_acceleration / jerk, velocity / max_acceleration + max_acceleration / jerk)
+        } else {
+            let jerk_duration = (velocity / jerk).sqrt();
+            (jerk_duration, 2. * jerk_duration)
+        };
+
+        Self { velocity, jerk, jerk_duration, duration }
+    }
+
+    /// Distance covered during the ramp, the ramp is symmetric so the average velocity is half the peak velocity.
+    fn distance(&self) -> f64 {
+        self.velocity * self.duration / 2.
+    }
+
+    /// Distance covered from standstill at the time since the start of the ramp.
+    fn position(&self, time: f64) -> f64 {
+
+        let Self { velocity, jerk, jerk_duration, duration } = *self;
+        let time = time.clamp(0., duration);
+
+        if time < jerk_duration {
+            jerk * time.powi(3) / 6.
+        } else if time < duration - jerk_duration {
+            let acceleration = jerk * jerk_duration;
+            let constant = time - jerk_duration;
+            jerk * jerk_duration.powi(3) / 6. + jerk * jerk_duration.powi(2) / 2. * constant + acceleration * constant.powi(2) / 2.
+        } else {
+            let remaining = duration - time;
+            self.distance() - velocity * remaining + jerk * remaining.powi(3) / 6.
+        }
+    }
+}
+
+impl MotionProfile {
+    /// Profile over a distance of zero or more, limited by the maximum velocity. All limits have to be above zero.
+    pub fn new(distance: f64, max_velocity: f64, acceleration: f64, deceleration: f64, jerk: f64) -> Self {
+
+        let ramps = |velocity| (Ramp::new(velocity, acceleration, jerk), Ramp::new(velocity, deceleration, jerk));
+        let ramps_distance = |(up, down): (Ramp, Ramp)| up.distance() + down.distance();
+
+        // A short move does not reach the maximum velocity, search the highest velocity the ramps fit in
+        let mut velocity = max_velocity;
+        if ramps_distance(ramps(velocity)) > distance {
+            let (mut low, mut high) = (0., max_velocity);
+            for _ in 0..64 {
+                let middle = (low + high) / 2.;
+                if ramps_distance(ramps(middle)) > distance {
+                    high = middle;
+                } else {
+                    low = middle;
+                }
+            }
+            velocity = low;
+        }
+
+        let (up, down) = ramps(velocity);
+        let cruise_duration = if velocity > 0. {
+            ((distance - ramps_distance((up, down))) / velocity).max(0.)
+        } else {
+            0.
+        };
+
+        Self { distance, velocity, acceleration: up, cruise_duration, deceleration: down }
+    }
+
+    /// Duration of the move.
+    pub fn duration(&self) -> f64 {
+        self.acceleration.duration + self.cruise_duration + self.deceleration.duration
+    }
+
+    /// Distance covered at the time since the start of the move, the full distance after the end.
+    pub fn position(&self, time: f64) -> f64 {
+
+        let cruise_start = self.acceleration.duration;
+        let deceleration_start = cruise_start + self.cruise_duration;
+
+        if time < cruise_start {
+            self.acceleration.position(time)
+        } else if time < deceleration_start {
+            self.acceleration.distance() + self.velocity * (time - cruise_start)
+        } else {
+            // The deceleration is the acceleration ramp in reverse
+            self.distance - self.deceleration.position(self.duration() - time)
+        }
+    }
+}
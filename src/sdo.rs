@@ -0,0 +1,1016 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use can_socket::{CanFrame, CanId};
+use canopen_tokio::nmt::NmtState;
+
+use crate::cia301::Node;
+use crate::conformance::WriteSource;
+use crate::cia402_runner::{ModeOfOperation, SET_POSITION_INPUT};
+use crate::eds::{DataValue, ObjectType};
+use crate::pdo::PdoMappings;
+
+#[derive(Debug)]
+enum ServerCommand {
+
+	/// The server is uploading a segment.
+	SegmentUploadResponse = 0,
+
+	/// The server has downloaded the segment.
+	SegmentDownloadResponse = 1,
+
+	/// The server accepts the upload request.
+	InitiateUploadResponse = 2,
+
+	/// The server accepts the download request.
+	InitiateDownloadResponse = 3,
+
+	/// The server is aborting the transfer.
+	AbortTransfer = 4,
+
+	/// The server is responding in a block upload.
+	BlockUpload = 6,
+}
+
+#[derive(Debug)]
+enum ClientCommand {
+
+	/// Download a segment to the server.
+	SegmentDownload = 0,
+
+	/// Initiate a download to the server.
+	InitiateDownload = 1,
+
+	/// Initiate an upload from the server.
+	InitiateUpload = 2,
+
+	/// Request the server to upload a segment.
+	SegmentUpload = 3,
+
+	/// Tell the server we are aborting the transfer.
+	AbortTransfer = 4,
+
+	/// Initiate, start, acknowledge or end a block upload from the server.
+	BlockUpload = 5,
+
+    /// Unknown client command.
+    Unknown = 8,
+}
+
+impl ClientCommand {
+    fn from_ccs(value: u8) -> ClientCommand {
+        match value {
+            0 => ClientCommand::SegmentDownload,
+            1 => ClientCommand::InitiateDownload,
+            2 => ClientCommand::InitiateUpload,
+            3 => ClientCommand::SegmentUpload,
+            4 => ClientCommand::AbortTransfer,
+            5 => ClientCommand::BlockUpload,
+            _ => ClientCommand::Unknown,
+        }
+    }
+}
+
+/// Segmented transfer in progress. The SDO channel of a node (0x600 + node id) serves one transfer at a time,
+/// an initiate for another object is aborted until the transfer is completed, aborted or timed out.
+#[derive(Debug)]
+pub enum SdoTransfer {
+
+    /// The server is uploading the data to the client.
+    Upload {
+        index: u16,
+        sub_index: u8,
+        data: Vec<u8>,
+        toggle: bool,
+    },
+
+    /// The client is downloading data to the server.
+    Download {
+        index: u16,
+        sub_index: u8,
+        data: Vec<u8>,
+        toggle: bool,
+    },
+
+    /// The server is uploading the data to the client in blocks.
+    BlockUpload {
+        index: u16,
+        sub_index: u8,
+        data: Vec<u8>,
+        /// The client and server check the data with a CRC.
+        crc: bool,
+        /// Number of segments in a block.
+        block_size: u8,
+        /// Number of bytes acknowledged by the client.
+        acknowledged: usize,
+        phase: BlockUploadPhase,
+    },
+}
+
+impl SdoTransfer {
+    /// Index and sub index of the transferred object.
+    fn object(&self) -> (u16, u8) {
+        match self {
+            SdoTransfer::Upload { index, sub_index, .. }
+            | SdoTransfer::Download { index, sub_index, .. }
+            | SdoTransfer::BlockUpload { index, sub_index, .. } => (*index, *sub_index),
+        }
+    }
+}
+
+/// Step of a block upload, the server waits for the client in each phase.
+#[derive(Debug, PartialEq)]
+pub enum BlockUploadPhase {
+
+    /// The upload is initiated, waiting for the client to start it.
+    Initiated,
+
+    /// A block is sent, waiting for the acknowledgement of the client.
+    Block,
+
+    /// The end of the upload is sent, waiting for the response of the client.
+    End,
+}
+
+/// Client subcommand: end of the block upload.
+const BLOCK_UPLOAD_END: u8 = 1;
+
+/// Client subcommand: block acknowledgement.
+const BLOCK_UPLOAD_ACK: u8 = 2;
+
+/// Client subcommand: start the upload after the initiate response.
+const BLOCK_UPLOAD_START: u8 = 3;
+
+/// Maximum number of segments in a block.
+const MAX_BLOCK_SIZE: u8 = 127;
+
+/// Time without requests of the client after which a transfer in progress is discarded.
+const SDO_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Abort code: toggle bit not alternated.
+const ABORT_TOGGLE_BIT: u32 = 0x0503_0000;
+
+/// Abort code: SDO protocol timed out.
+const ABORT_TIMEOUT: u32 = 0x0504_0000;
+
+/// Abort code: client/server command specifier not valid or unknown.
+const ABORT_INVALID_COMMAND: u32 = 0x0504_0001;
+
+/// Abort code: invalid block size.
+const ABORT_INVALID_BLOCK_SIZE: u32 = 0x0504_0002;
+
+/// Abort code: invalid sequence number.
+const ABORT_INVALID_SEQUENCE_NUMBER: u32 = 0x0504_0003;
+
+/// Abort code: data type does not match, length of service parameter does not match.
+const ABORT_LENGTH_MISMATCH: u32 = 0x0607_0010;
+
+/// Abort code: attempt to write a read only object.
+const ABORT_READ_ONLY: u32 = 0x0601_0002;
+
+/// Abort code: object does not exist in the object dictionary.
+const ABORT_OBJECT_DOES_NOT_EXIST: u32 = 0x0602_0000;
+
+/// Abort code: value range of parameter exceeded.
+const ABORT_VALUE_RANGE: u32 = 0x0609_0030;
+
+/// Abort code: value of parameter written too high.
+const ABORT_VALUE_TOO_HIGH: u32 = 0x0609_0031;
+
+/// Abort code: the number and length of the objects to be mapped would exceed the PDO length.
+const ABORT_PDO_LENGTH: u32 = 0x0604_0042;
+
+/// Abort code: general parameter incompatibility reason.
+const ABORT_PARAMETER_INCOMPATIBILITY: u32 = 0x0604_0043;
+
+/// Abort code: data can not be transferred or stored to the application.
+const ABORT_CANNOT_STORE: u32 = 0x0800_0020;
+
+/// Abort code: data can not be transferred or stored to the application because of the present device state.
+const ABORT_DEVICE_STATE: u32 = 0x0800_0022;
+
+/// Signature written to the store parameters object (0x1010): "save".
+const STORE_SIGNATURE: u32 = 0x6576_6173;
+
+/// Signature written to the restore default parameters object (0x1011): "load".
+const RESTORE_SIGNATURE: u32 = 0x6461_6F6C;
+
+impl Node {
+
+    pub(crate) async fn parse_sdo_client_request(&mut self, data: &[u8]) {
+
+        // SDO requests always have 8 bytes
+        let Ok(data) = <&[u8; 8]>::try_from(data) else {
+            log::warn!(target: &self.log_target, "SDO request node {} ignored: received {} bytes, expected 8", self.node_id, data.len());
+            return;
+        };
+
+        // A transfer the client stopped responding to does not block the channel
+        let now = self.clock.now();
+        let timed_out = self.last_sdo_request.is_some_and(|last_request| now.saturating_sub(last_request) > SDO_TIMEOUT);
+        self.last_sdo_request = Some(now);
+        if timed_out {
+            if let Some(transfer) = self.sdo_transfer.take() {
+                self.sdo_segment_queue.clear();
+                let (index, sub_index) = transfer.object();
+                log::warn!(target: &self.log_target, "SDO transfer node {} timed out: index 0x{:X} sub {}", self.node_id, index, sub_index);
+                self.send_sdo_abort(index, sub_index, ABORT_TIMEOUT).await;
+            }
+        }
+
+        let ccs = (data[0] >> 5) & 0b111;
+        let command = ClientCommand::from_ccs(ccs);
+
+        let initiate = match command {
+            ClientCommand::InitiateUpload | ClientCommand::InitiateDownload => true,
+            ClientCommand::BlockUpload => data[0] & 0b11 == 0,
+            _ => false,
+        };
+        if initiate {
+            let index = u16::from_le_bytes([data[1], data[2]]);
+            let sub_index = data[3];
+            match &self.sdo_transfer {
+                Some(transfer) if transfer.object() != (index, sub_index) => {
+                    let (busy_index, busy_sub_index) = transfer.object();
+                    log::warn!(target: &self.log_target, "SDO initiate node {} for index 0x{:X} sub {} rejected: transfer of index 0x{:X} sub {} in progress",
+                        self.node_id, index, sub_index, busy_index, busy_sub_index);
+                    self.send_sdo_abort(index, sub_index, ABORT_INVALID_COMMAND).await;
+                    return;
+                }
+                // An initiate for the same object restarts the transfer
+                _ => self.sdo_transfer = None,
+            }
+        }
+
+        self.sdo_response(&command, data).await;
+
+    }
+
+    async fn sdo_response(&mut self, command: &ClientCommand, input_data: &[u8; 8]) {
+
+        match command {
+            ClientCommand::InitiateUpload => self.sdo_initiate_upload(input_data).await,
+            ClientCommand::InitiateDownload => self.sdo_initiate_download(input_data).await,
+            ClientCommand::SegmentUpload => self.sdo_segment_upload(input_data).await,
+            ClientCommand::SegmentDownload => self.sdo_segment_download(input_data).await,
+            ClientCommand::BlockUpload => self.sdo_block_upload(input_data).await,
+            ClientCommand::Unknown => {
+                let index = u16::from_le_bytes([input_data[1], input_data[2]]);
+                log::warn!(target: &self.log_target, "SDO client command specifier {} not supported by node {}", input_data[0] >> 5, self.node_id);
+                self.send_sdo_abort(index, input_data[3], ABORT_INVALID_COMMAND).await;
+            }
+            ClientCommand::AbortTransfer => self.sdo_client_abort(input_data),
+        }
+
+    }
+
+    async fn sdo_initiate_upload(&mut self, input_data: &[u8; 8]) {
+
+        let input_index = u16::from_le_bytes([input_data[1], input_data[2]]);
+        let input_sub_index = input_data[3];
+
+        if let Some(var) = self.eds_data.get_var(input_index, input_sub_index) {
+
+            let value = var.value.to_le_bytes();
+            let mut data: [u8; 8] = [0; 8];
+            let scs = ServerCommand::InitiateUploadResponse;
+            let s = 1;
+
+            // Values of one to four bytes are sent expedited, the two bits of n can not indicate an empty value,
+            // larger and empty values are sent in segments
+            if (1..=4).contains(&value.len()) {
+                let n = (4 - value.len()) as u8;
+                let e = 1;
+                data[0] = (scs as u8 & 0b111) << 5 | (n & 0b11) << 2 | e << 1 | s;
+                data[4..4 + value.len()].copy_from_slice(&value);
+            } else {
+                data[0] = (scs as u8 & 0b111) << 5 | s;
+                data[4..].copy_from_slice(&(value.len() as u32).to_le_bytes());
+                self.sdo_transfer = Some(SdoTransfer::Upload {
+                    index: input_index,
+                    sub_index: input_sub_index,
+                    data: value,
+                    toggle: false,
+                });
+            }
+
+            data[1..3].copy_from_slice(&input_index.to_le_bytes());
+            data[3] = input_sub_index;
+
+            self.counters.sdo_uploads.fetch_add(1, Ordering::Relaxed);
+            self.send_sdo_response(&data).await;
+
+        }
+
+    }
+
+    async fn sdo_initiate_download(&mut self, input_data: &[u8; 8]) {
+
+        let input_index = u16::from_le_bytes([input_data[1], input_data[2]]);
+        let input_sub_index = input_data[3];
+
+        let e = (input_data[0] >> 1) & 0b1;
+        let s = input_data[0] & 0b1;
+        let n = ((input_data[0] >> 2) & 0b11) as usize;
+
+        self.check_write_rules(input_index, input_sub_index, WriteSource::Sdo);
+
+        if let Some(var) = self.eds_data.get_var(input_index, input_sub_index) {
+
+                if var.is_read_only() {
+                    self.send_sdo_abort(input_index, input_sub_index, ABORT_READ_ONLY).await;
+                    return;
+                }
+
+                if e == 1 {
+
+                    // Update value with incoming data
+                    let size = if s == 1 { 4 - n } else { 4 };
+                    if let Err(abort_code) = self.write_object(input_index, input_sub_index, &input_data[4..4 + size]) {
+                        self.send_sdo_abort(input_index, input_sub_index, abort_code).await;
+                        return;
+                    }
+
+                } else {
+                    self.sdo_transfer = Some(SdoTransfer::Download {
+                        index: input_index,
+                        sub_index: input_sub_index,
+                        data: Vec::new(),
+                        toggle: false,
+                    });
+                }
+
+                let mut data: [u8; 8] = [0; 8];
+                data[0] = (ServerCommand::InitiateDownloadResponse as u8 & 0b111) << 5;
+                data[1..3].copy_from_slice(&input_index.to_le_bytes());
+                data[3] = input_sub_index;
+
+                self.counters.sdo_downloads.fetch_add(1, Ordering::Relaxed);
+                self.send_sdo_response(&data).await;
+
+        }
+
+    }
+
+    async fn sdo_segment_upload(&mut self, input_data: &[u8; 8]) {
+
+        let client_toggle = (input_data[0] >> 4) & 0b1 == 1;
+
+        let Some(SdoTransfer::Upload { index, sub_index, data: remaining, toggle }) = &mut self.sdo_transfer else {
+            log::error!(target: &self.log_target, "Segment upload requested without transfer in progress on node {}", self.node_id);
+            self.send_sdo_abort(0, 0, ABORT_INVALID_COMMAND).await;
+            return;
+        };
+
+        if client_toggle != *toggle {
+            let (index, sub_index) = (*index, *sub_index);
+            self.sdo_transfer = None;
+            self.send_sdo_abort(index, sub_index, ABORT_TOGGLE_BIT).await;
+            return;
+        }
+
+        let segment: Vec<u8> = remaining.drain(..remaining.len().min(7)).collect();
+        let n = (7 - segment.len()) as u8;
+        let c = remaining.is_empty() as u8;
+
+        let mut data: [u8; 8] = [0; 8];
+        data[0] = (ServerCommand::SegmentUploadResponse as u8 & 0b111) << 5 | (*toggle as u8) << 4 | (n & 0b111) << 1 | c;
+        data[1..1 + segment.len()].copy_from_slice(&segment);
+
+        *toggle = !*toggle;
+        if c == 1 {
+            self.sdo_transfer = None;
+        }
+
+        self.send_sdo_segment_response(&data).await;
+
+    }
+
+    async fn sdo_segment_download(&mut self, input_data: &[u8; 8]) {
+
+        let client_toggle = (input_data[0] >> 4) & 0b1 == 1;
+        let n = ((input_data[0] >> 1) & 0b111) as usize;
+        let c = input_data[0] & 0b1;
+
+        let Some(SdoTransfer::Download { index, sub_index, data: received, toggle }) = &mut self.sdo_transfer else {
+            log::error!(target: &self.log_target, "Segment download received without transfer in progress on node {}", self.node_id);
+            self.send_sdo_abort(0, 0, ABORT_INVALID_COMMAND).await;
+            return;
+        };
+
+        let (index, sub_index) = (*index, *sub_index);
+
+        if client_toggle != *toggle {
+            self.sdo_transfer = None;
+            self.send_sdo_abort(index, sub_index, ABORT_TOGGLE_BIT).await;
+            return;
+        }
+
+        received.extend_from_slice(&input_data[1..8 - n]);
+
+        let mut data: [u8; 8] = [0; 8];
+        data[0] = (ServerCommand::SegmentDownloadResponse as u8 & 0b111) << 5 | (*toggle as u8) << 4;
+        *toggle = !*toggle;
+
+        if c == 1 {
+
+            let received = std::mem::take(received);
+            self.sdo_transfer = None;
+
+            if self.eds_data.get_var(index, sub_index).is_some() {
+                if let Err(abort_code) = self.write_object(index, sub_index, &received) {
+                    self.send_sdo_abort(index, sub_index, abort_code).await;
+                    return;
+                }
+            }
+
+        }
+
+        self.send_sdo_segment_response(&data).await;
+
+    }
+
+    async fn sdo_block_upload(&mut self, input_data: &[u8; 8]) {
+
+        match input_data[0] & 0b11 {
+            0 => self.sdo_initiate_block_upload(input_data).await,
+            BLOCK_UPLOAD_START => {
+                if let Some(SdoTransfer::BlockUpload { phase: phase @ BlockUploadPhase::Initiated, .. }) = &mut self.sdo_transfer {
+                    *phase = BlockUploadPhase::Block;
+                    self.sdo_send_block().await;
+                } else {
+                    self.sdo_block_upload_out_of_sequence("Block upload start").await;
+                }
+            }
+            BLOCK_UPLOAD_ACK => self.sdo_block_upload_ack(input_data).await,
+            BLOCK_UPLOAD_END => {
+                if let Some(SdoTransfer::BlockUpload { phase: BlockUploadPhase::End, .. }) = &self.sdo_transfer {
+                    self.sdo_transfer = None;
+                } else {
+                    self.sdo_block_upload_out_of_sequence("Block upload end").await;
+                }
+            }
+            _ => unreachable!(),
+        }
+
+    }
+
+    async fn sdo_initiate_block_upload(&mut self, input_data: &[u8; 8]) {
+
+        let input_index = u16::from_le_bytes([input_data[1], input_data[2]]);
+        let input_sub_index = input_data[3];
+        let client_crc = (input_data[0] >> 2) & 0b1 == 1;
+        let block_size = input_data[4];
+        let protocol_switch_threshold = input_data[5] as usize;
+
+        let Some(var) = self.eds_data.get_var(input_index, input_sub_index) else {
+            self.send_sdo_abort(input_index, input_sub_index, ABORT_OBJECT_DOES_NOT_EXIST).await;
+            return;
+        };
+        let value = var.value.to_le_bytes();
+
+        if !(1..=MAX_BLOCK_SIZE).contains(&block_size) {
+            self.send_sdo_abort(input_index, input_sub_index, ABORT_INVALID_BLOCK_SIZE).await;
+            return;
+        }
+
+        // Small values are uploaded with the normal protocol when the client allows it
+        if value.len() <= protocol_switch_threshold {
+            self.sdo_initiate_upload(input_data).await;
+            return;
+        }
+
+        // The server supports CRC, it is only used when the client supports it too
+        let sc = 1;
+        let s = 1;
+        let mut data: [u8; 8] = [0; 8];
+        data[0] = (ServerCommand::BlockUpload as u8 & 0b111) << 5 | sc << 2 | s << 1;
+        data[1..3].copy_from_slice(&input_index.to_le_bytes());
+        data[3] = input_sub_index;
+        data[4..].copy_from_slice(&(value.len() as u32).to_le_bytes());
+
+        self.sdo_transfer = Some(SdoTransfer::BlockUpload {
+            index: input_index,
+            sub_index: input_sub_index,
+            data: value,
+            crc: client_crc,
+            block_size,
+            acknowledged: 0,
+            phase: BlockUploadPhase::Initiated,
+        });
+
+        self.send_sdo_response(&data).await;
+
+    }
+
+    async fn sdo_block_upload_ack(&mut self, input_data: &[u8; 8]) {
+
+        let Some(SdoTransfer::BlockUpload { index, sub_index, data, crc, block_size, acknowledged, phase: phase @ BlockUploadPhase::Block }) = &mut self.sdo_transfer else {
+            self.sdo_block_upload_out_of_sequence("Block acknowledgement").await;
+            return;
+        };
+
+        let (index, sub_index) = (*index, *sub_index);
+        let ack_sequence = input_data[1] as usize;
+        let next_block_size = input_data[2];
+
+        // Segments after the last one received correctly are sent again in the next block
+        let segments = (data.len() - *acknowledged).div_ceil(7).clamp(1, *block_size as usize);
+        if ack_sequence > segments {
+            self.sdo_transfer = None;
+            self.send_sdo_abort(index, sub_index, ABORT_INVALID_SEQUENCE_NUMBER).await;
+            return;
+        }
+        if !(1..=MAX_BLOCK_SIZE).contains(&next_block_size) {
+            self.sdo_transfer = None;
+            self.send_sdo_abort(index, sub_index, ABORT_INVALID_BLOCK_SIZE).await;
+            return;
+        }
+
+        let complete = ack_sequence == segments && *acknowledged + segments * 7 >= data.len();
+        *acknowledged = (*acknowledged + ack_sequence * 7).min(data.len());
+        *block_size = next_block_size;
+
+        if complete {
+
+            // Number of bytes in the last segment that do not contain data
+            let n = match data.len() % 7 {
+                0 if data.is_empty() => 7,
+                0 => 0,
+                used => 7 - used as u8,
+            };
+            let checksum = if *crc { crc16(data) } else { 0 };
+            *phase = BlockUploadPhase::End;
+
+            let mut response: [u8; 8] = [0; 8];
+            response[0] = (ServerCommand::BlockUpload as u8 & 0b111) << 5 | (n & 0b111) << 2 | 1;
+            response[1..3].copy_from_slice(&checksum.to_le_bytes());
+            self.send_sdo_response(&response).await;
+
+        } else {
+            self.sdo_send_block().await;
+        }
+
+    }
+
+    /// Send the next block of segments, starting at the data acknowledged by the client.
+    async fn sdo_send_block(&mut self) {
+
+        let Some(SdoTransfer::BlockUpload { data, block_size, acknowledged, .. }) = &self.sdo_transfer else {
+            return;
+        };
+
+        let mut segments = Vec::new();
+        let mut position = *acknowledged;
+        for sequence in 1..=*block_size {
+            let end = (position + 7).min(data.len());
+            let last = end == data.len();
+
+            let mut segment: [u8; 8] = [0; 8];
+            segment[0] = (last as u8) << 7 | sequence;
+            segment[1..1 + end - position].copy_from_slice(&data[position..end]);
+            segments.push(segment);
+
+            position = end;
+            if last {
+                break;
+            }
+        }
+
+        for segment in segments {
+            self.send_sdo_segment_response(&segment).await;
+        }
+
+    }
+
+    /// Abort a block upload request that does not match the state of the transfer.
+    async fn sdo_block_upload_out_of_sequence(&mut self, request: &str) {
+
+        log::error!(target: &self.log_target, "{} received without matching block upload in progress on node {}", request, self.node_id);
+        let (index, sub_index) = match self.sdo_transfer.take() {
+            Some(SdoTransfer::BlockUpload { index, sub_index, .. }) => (index, sub_index),
+            _ => (0, 0),
+        };
+        self.send_sdo_abort(index, sub_index, ABORT_INVALID_COMMAND).await;
+
+    }
+
+    /// The client aborts the transfer: the transfer in progress is discarded without a response.
+    fn sdo_client_abort(&mut self, input_data: &[u8; 8]) {
+
+        let index = u16::from_le_bytes([input_data[1], input_data[2]]);
+        let sub_index = input_data[3];
+        let abort_code = u32::from_le_bytes([input_data[4], input_data[5], input_data[6], input_data[7]]);
+
+        log::info!(target: &self.log_target, "SDO transfer node {} aborted by the client: index 0x{:X} sub {}, abort code 0x{:08X}", self.node_id, index, sub_index, abort_code);
+        self.sdo_transfer = None;
+        self.sdo_segment_queue.clear();
+
+    }
+
+    /// Write downloaded data to an object, returns the abort code when the data is rejected.
+    pub(crate) fn write_object(&mut self, index: u16, sub_index: u8, data: &[u8]) -> Result<(), u32> {
+
+        let Some(var) = self.eds_data.get_var(index, sub_index) else {
+            return Err(ABORT_OBJECT_DOES_NOT_EXIST);
+        };
+        if var.is_read_only() {
+            return Err(ABORT_READ_ONLY);
+        }
+        let value = var.value.with_le_bytes(data).ok_or(ABORT_LENGTH_MISMATCH)?;
+
+        // Writing zero to the number of errors clears the error history, other values are not allowed
+        if index == 0x1003 && sub_index == 0 {
+            if !matches!(value, DataValue::Unsigned8(0)) {
+                return Err(ABORT_VALUE_RANGE);
+            }
+            self.clear_error_history();
+            return Ok(());
+        }
+
+        // Only the implemented modes of operation can be selected
+        if let (0x6060, DataValue::Integer8(mode)) = (index, &value) {
+            if ModeOfOperation::from_i8(*mode).is_none() {
+                return Err(ABORT_VALUE_RANGE);
+            }
+        }
+
+        // A mapping is only changed while its PDO is not valid, the master disables the PDO, remaps it and enables it again
+        if matches!(index, 0x1600..=0x17FF | 0x1A00..=0x1BFF) && self.pdo_valid(index - 0x200) {
+            return Err(ABORT_PARAMETER_INCOMPATIBILITY);
+        }
+
+        if sub_index == 0 {
+            self.validate_number_of_entries(index, &value)?;
+        }
+
+        // The axis is only placed at a new position while it does not move
+        if let (SET_POSITION_INPUT, DataValue::Integer32(position)) = (index, &value) {
+            self.set_actual_position(*position).map_err(|()| ABORT_DEVICE_STATE)?;
+        }
+
+        // The COB-IDs follow the node id, a new node id is only accepted in pre-operational and activated on the next reset
+        if self.node_id_index == Some(index) && sub_index == 0 {
+            if self.nmt_state != NmtState::PreOperational {
+                return Err(ABORT_DEVICE_STATE);
+            }
+            let node_id = match value.to_le_bytes().split_first() {
+                Some((&node_id, rest)) if (1..=127).contains(&node_id) && rest.iter().all(|&byte| byte == 0) => node_id,
+                _ => return Err(ABORT_VALUE_RANGE),
+            };
+            log::info!(target: &self.log_target, "Node {}: node id {} configured, activated on the next reset", self.node_id, node_id);
+            self.lss.pending_node_id = Some(node_id);
+        }
+
+        // Writing the signature to a sub index of 0x1010 stores and to 0x1011 restores all parameters, the value is not changed
+        if (index == 0x1010 || index == 0x1011) && sub_index != 0 {
+            return match (index, value) {
+                (0x1010, DataValue::Unsigned32(STORE_SIGNATURE)) => self.store_parameters().map_err(|()| ABORT_CANNOT_STORE),
+                (0x1011, DataValue::Unsigned32(RESTORE_SIGNATURE)) => self.restore_default_parameters().map_err(|()| ABORT_CANNOT_STORE),
+                _ => Err(ABORT_CANNOT_STORE),
+            };
+        }
+
+        self.eds_data.set_dataval(index, sub_index, value);
+        if PdoMappings::contains(index) {
+            self.update_pdo_mappings();
+        }
+        self.notify_write(index, sub_index);
+
+        Ok(())
+    }
+
+    /// Whether bit 31 of the COB-ID (sub 1) of a PDO communication parameter is clear.
+    fn pdo_valid(&self, communication_index: u16) -> bool {
+        matches!(self.eds_data.get_var(communication_index, 1).map(|var| &var.value), Some(DataValue::Unsigned32(cob_id)) if cob_id & (1 << 31) == 0)
+    }
+
+    /// Sub index 0 of an array or a PDO mapping sets the number of entries in use, of another record it is fixed.
+    fn validate_number_of_entries(&self, index: u16, value: &DataValue) -> Result<(), u32> {
+
+        let pdo_mapping = matches!(index, 0x1600..=0x17FF | 0x1A00..=0x1BFF);
+        match self.eds_data.object_type(index) {
+            ObjectType::Var => return Ok(()),
+            ObjectType::Record if !pdo_mapping => return Err(ABORT_READ_ONLY),
+            ObjectType::Array | ObjectType::Record => {}
+        }
+
+        let DataValue::Unsigned8(entries) = *value else {
+            return Ok(());
+        };
+
+        let Some(vars) = self.eds_data.od.get(&index) else {
+            return Ok(());
+        };
+        if entries as usize > vars.keys().filter(|&&sub_index| sub_index != 0).count() {
+            return Err(ABORT_VALUE_TOO_HIGH);
+        }
+
+        // The mapped objects have to fit in the 8 bytes of a PDO
+        if pdo_mapping {
+            let mapped_bits: u32 = (1..=entries)
+                .filter_map(|sub_index| match vars.get(&sub_index)?.value {
+                    DataValue::Unsigned32(mapping) => Some(mapping & 0xFF),
+                    _ => None,
+                })
+                .sum();
+            if mapped_bits > 64 {
+                return Err(ABORT_PDO_LENGTH);
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_sdo_abort(&mut self, index: u16, sub_index: u8, abort_code: u32) {
+
+        let mut data: [u8; 8] = [0; 8];
+        data[0] = (ServerCommand::AbortTransfer as u8 & 0b111) << 5;
+        data[1..3].copy_from_slice(&index.to_le_bytes());
+        data[3] = sub_index;
+        data[4..].copy_from_slice(&abort_code.to_le_bytes());
+
+        self.counters.sdo_aborts.fetch_add(1, Ordering::Relaxed);
+        log::warn!(target: &self.log_target, "SDO abort node {}: index 0x{:X} sub {}, abort code 0x{:08X}", self.node_id, index, sub_index, abort_code);
+
+        self.send_sdo_response(&data).await;
+
+    }
+
+    /// Send a segment response, keeping at least the configured gap on the clock since the previous segment response.
+    /// A response within the gap is queued and sent by the update cycle, so the node keeps running meanwhile.
+    async fn send_sdo_segment_response(&mut self, data: &[u8; 8]) {
+
+        self.sdo_segment_queue.push_back(*data);
+        self.send_due_sdo_segments().await;
+
+    }
+
+    /// Send the queued segment responses for which the gap since the previous segment response has passed.
+    pub(crate) async fn send_due_sdo_segments(&mut self) {
+
+        while !self.sdo_segment_queue.is_empty() {
+            let now = self.clock.now();
+            if self.last_sdo_segment.is_some_and(|last_segment| now.saturating_sub(last_segment) < self.sdo_segment_gap) {
+                return;
+            }
+            if let Some(data) = self.sdo_segment_queue.pop_front() {
+                self.send_sdo_response(&data).await;
+                self.last_sdo_segment = Some(now);
+            }
+        }
+
+    }
+
+    async fn send_sdo_response(&self, data: &[u8; 8]) {
+
+        let cob_id = CanId::new_base(0x580 | self.node_id as u16).unwrap();
+
+        let frame = &CanFrame::new(
+            cob_id,
+            data,
+            None,
+        )
+        .unwrap();
+
+        self.send_frame(frame).await;
+
+    }
+
+}
+
+/// CRC of a block transfer: CRC-16-CCITT with polynomial 0x1021 and initial value 0.
+fn crc16(data: &[u8]) -> u16 {
+
+    let mut crc: u16 = 0;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { crc << 1 ^ 0x1021 } else { crc << 1 };
+        }
+    }
+
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc16, ABORT_INVALID_BLOCK_SIZE, ABORT_INVALID_COMMAND, ABORT_INVALID_SEQUENCE_NUMBER, ABORT_PARAMETER_INCOMPATIBILITY,
+        ABORT_TIMEOUT};
+    use crate::config;
+    use crate::testing::{TestTwin, EDS_FILE};
+
+    /// Software version (0x100A) of 18 bytes, uploaded in three segments
+    const SOFTWARE_VERSION: &[u8] = b"FIR-v2213-B1031134";
+
+    /// Abort response of the server.
+    fn abort(index: u16, sub_index: u8, abort_code: u32) -> [u8; 8] {
+        let mut response = [0x80, 0, 0, sub_index, 0, 0, 0, 0];
+        response[1..3].copy_from_slice(&index.to_le_bytes());
+        response[4..].copy_from_slice(&abort_code.to_le_bytes());
+        response
+    }
+
+    /// Block upload segment with the sequence number and data, bit 7 set on the last segment.
+    fn segment(sequence: u8, data: &[u8]) -> [u8; 8] {
+        let mut segment = [sequence, 0, 0, 0, 0, 0, 0, 0];
+        segment[1..1 + data.len()].copy_from_slice(data);
+        segment
+    }
+
+    #[test]
+    fn crc_of_the_block_upload() {
+        // Check value of CRC-16/XMODEM, the CRC of CiA 301
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+        assert_eq!(crc16(&[]), 0);
+    }
+
+    #[tokio::test]
+    async fn mapping_of_valid_pdo_is_rejected() {
+        let twin = TestTwin::start(&[1]).await;
+
+        assert_eq!(twin.write(1, 0x1A00, 0, &[0]).await, Err(ABORT_PARAMETER_INCOMPATIBILITY));
+        assert_eq!(twin.write(1, 0x1600, 1, &0x6060_0008u32.to_le_bytes()).await, Err(ABORT_PARAMETER_INCOMPATIBILITY));
+        assert_eq!(twin.read(1, 0x1A00, 0).await, Ok(vec![2]));
+    }
+
+    #[tokio::test]
+    async fn mapping_of_disabled_pdo_is_accepted() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Disable, remap to the position actual value and enable again
+        twin.write(1, 0x1800, 1, &0x8000_0181u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A00, 0, &[0]).await.unwrap();
+        twin.write(1, 0x1A00, 1, &0x6064_0020u32.to_le_bytes()).await.unwrap();
+        twin.write(1, 0x1A00, 0, &[1]).await.unwrap();
+        twin.write(1, 0x1800, 1, &0x0000_0181u32.to_le_bytes()).await.unwrap();
+
+        twin.write(1, 0x1800, 2, &[1]).await.unwrap();
+        twin.nmt(0x01, 1);
+        twin.sent(1).await;
+        twin.push(0x080, &[]);
+        assert_eq!(twin.sent_with_id(1, 0x181).await, vec![vec![0; 4]]);
+    }
+
+    #[tokio::test]
+    async fn upload_segments_keep_the_configured_gap() {
+        let mut node = config::Node::new(1, EDS_FILE);
+        node.sdo_segment_gap_us = 20_000;
+        let twin = TestTwin::start_nodes(vec![node]).await;
+
+        // The manufacturer device name of 8 bytes is uploaded in two segments, the first without a previous segment
+        assert_eq!(twin.sdo(1, [0x40, 0x08, 0x10, 0, 0, 0, 0, 0]).await[0], 0x41);
+        assert_eq!(twin.sdo(1, [0x60, 0, 0, 0, 0, 0, 0, 0]).await[0], 0x00);
+
+        // The last segment waits for the gap on the clock, the node answers other requests meanwhile
+        twin.push(0x601, &[0x70, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(twin.sent_with_id(1, 0x581).await.is_empty());
+        twin.advance(19).await;
+        assert!(twin.sent_with_id(1, 0x581).await.is_empty());
+        // Sent by the update at the end of the gap, read from the bus as sent() would take it for the response of its SDO read
+        twin.advance(1).await;
+        let segment = std::iter::from_fn(|| twin.bus.try_next_sent()).find(|frame| frame.id().as_u32() == 0x581);
+        assert_eq!(segment.map(|frame| frame.data().to_vec()), Some(vec![0x1D, b'2', 0, 0, 0, 0, 0, 0]));
+    }
+
+    #[tokio::test]
+    async fn interleaved_initiate_is_aborted() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Segmented upload of the manufacturer device name in progress
+        assert_eq!(twin.sdo(1, [0x40, 0x08, 0x10, 0, 0, 0, 0, 0]).await[0], 0x41);
+
+        // An initiate for another object is aborted, the transfer in progress continues
+        assert_eq!(twin.read(1, 0x6081, 0).await, Err(ABORT_INVALID_COMMAND));
+        assert_eq!(twin.write(1, 0x6081, 0, &100u32.to_le_bytes()).await, Err(ABORT_INVALID_COMMAND));
+
+        let first = twin.sdo(1, [0x60, 0, 0, 0, 0, 0, 0, 0]).await;
+        let last = twin.sdo(1, [0x70, 0, 0, 0, 0, 0, 0, 0]).await;
+        assert_eq!([&first[1..8], &last[1..2]].concat(), b"CPB3-1-2");
+
+        // Once done the channel serves the next transfer
+        assert_eq!(twin.read_i32(1, 0x6081, 0).await, 500);
+    }
+
+    #[tokio::test]
+    async fn expedited_upload_indicates_the_size_of_each_type() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Expedited with the size indicated, n the number of bytes without data
+        let objects = [
+            (0x6060, 0x4F, "Integer8"),
+            (0x1001, 0x4F, "Unsigned8"),
+            (0x6071, 0x4B, "Integer16"),
+            (0x6040, 0x4B, "Unsigned16"),
+            (0x607A, 0x43, "Integer32"),
+            (0x6081, 0x43, "Unsigned32"),
+        ];
+        for (index, command, data_type) in objects {
+            let [index_low, index_high] = u16::to_le_bytes(index);
+            let response = twin.sdo(1, [0x40, index_low, index_high, 0, 0, 0, 0, 0]).await;
+            assert_eq!(response[0], command, "{data_type}");
+            // The bytes without data are zero
+            let size = 4 - ((command >> 2) & 0x03) as usize;
+            assert!(response[4 + size..].iter().all(|&byte| byte == 0), "{data_type}");
+        }
+    }
+
+    #[tokio::test]
+    async fn transfer_times_out_on_the_clock() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Within the timeout of 1 s the segmented upload continues
+        assert_eq!(twin.sdo(1, [0x40, 0x08, 0x10, 0, 0, 0, 0, 0]).await[0], 0x41);
+        twin.advance(1000).await;
+        assert_eq!(twin.sdo(1, [0x60, 0, 0, 0, 0, 0, 0, 0]).await[0], 0x00);
+
+        // After it the next request aborts the transfer
+        twin.advance(1001).await;
+        let response = twin.sdo(1, [0x70, 0, 0, 0, 0, 0, 0, 0]).await;
+        assert_eq!(response[..4], [0x80, 0x08, 0x10, 0]);
+        assert_eq!(u32::from_le_bytes(response[4..].try_into().unwrap()), ABORT_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn block_upload_with_crc() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Initiate with CRC and a block size of 127, the server supports CRC and indicates the size
+        let response = twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 127, 0, 0, 0]).await;
+        assert_eq!(response, [0xC6, 0x0A, 0x10, 0, 18, 0, 0, 0]);
+
+        let segments = twin.sdo_responses(1, [0xA3, 0, 0, 0, 0, 0, 0, 0], 3).await;
+        assert_eq!(segments, [segment(1, &SOFTWARE_VERSION[..7]), segment(2, &SOFTWARE_VERSION[7..14]), segment(0x83, &SOFTWARE_VERSION[14..])]);
+
+        // End with 3 bytes without data in the last segment and the CRC of the data
+        let response = twin.sdo(1, [0xA2, 3, 127, 0, 0, 0, 0, 0]).await;
+        assert_eq!(response, [0xC1 | 3 << 2, 0x5F, 0xF8, 0, 0, 0, 0, 0]);
+
+        // The client ends the transfer without response, the channel serves the next transfer
+        twin.push(0x601, &[0xA1, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(twin.read_i32(1, 0x6081, 0).await, 500);
+    }
+
+    #[tokio::test]
+    async fn block_upload_without_crc() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // The client does not support CRC, the server leaves the CRC zero
+        assert_eq!(twin.sdo(1, [0xA0, 0x0A, 0x10, 0, 127, 0, 0, 0]).await[0], 0xC6);
+        twin.sdo_responses(1, [0xA3, 0, 0, 0, 0, 0, 0, 0], 3).await;
+        assert_eq!(twin.sdo(1, [0xA2, 3, 127, 0, 0, 0, 0, 0]).await, [0xCD, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn block_upload_resends_after_a_partial_acknowledgement() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Blocks of two segments
+        twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 2, 0, 0, 0]).await;
+        let segments = twin.sdo_responses(1, [0xA3, 0, 0, 0, 0, 0, 0, 0], 2).await;
+        assert_eq!(segments, [segment(1, &SOFTWARE_VERSION[..7]), segment(2, &SOFTWARE_VERSION[7..14])]);
+
+        // Only the first segment was received, the next block starts with the second segment
+        let segments = twin.sdo_responses(1, [0xA2, 1, 2, 0, 0, 0, 0, 0], 2).await;
+        assert_eq!(segments, [segment(1, &SOFTWARE_VERSION[7..14]), segment(0x82, &SOFTWARE_VERSION[14..])]);
+
+        let response = twin.sdo(1, [0xA2, 2, 2, 0, 0, 0, 0, 0]).await;
+        assert_eq!(response[..3], [0xCD, 0x5F, 0xF8]);
+    }
+
+    #[tokio::test]
+    async fn block_upload_aborts_an_invalid_block_size_or_sequence_number() {
+        let twin = TestTwin::start(&[1]).await;
+
+        for block_size in [0, 128] {
+            let response = twin.sdo(1, [0xA4, 0x0A, 0x10, 0, block_size, 0, 0, 0]).await;
+            assert_eq!(response, abort(0x100A, 0, ABORT_INVALID_BLOCK_SIZE), "block size {block_size}");
+        }
+
+        // The block size of the acknowledgement
+        twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 127, 0, 0, 0]).await;
+        twin.sdo_responses(1, [0xA3, 0, 0, 0, 0, 0, 0, 0], 3).await;
+        assert_eq!(twin.sdo(1, [0xA2, 3, 0, 0, 0, 0, 0, 0]).await, abort(0x100A, 0, ABORT_INVALID_BLOCK_SIZE));
+
+        // A sequence number after the last segment of the block
+        twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 127, 0, 0, 0]).await;
+        twin.sdo_responses(1, [0xA3, 0, 0, 0, 0, 0, 0, 0], 3).await;
+        assert_eq!(twin.sdo(1, [0xA2, 4, 127, 0, 0, 0, 0, 0]).await, abort(0x100A, 0, ABORT_INVALID_SEQUENCE_NUMBER));
+
+        // Each abort ended the transfer
+        assert_eq!(twin.sdo(1, [0xA2, 1, 127, 0, 0, 0, 0, 0]).await[0], 0x80);
+    }
+
+    #[tokio::test]
+    async fn block_upload_switches_to_the_normal_protocol() {
+        let twin = TestTwin::start(&[1]).await;
+
+        // Up to the protocol switch threshold of the client the server answers with an expedited or segmented upload
+        assert_eq!(twin.sdo(1, [0xA4, 0x81, 0x60, 0, 127, 4, 0, 0]).await, [0x43, 0x81, 0x60, 0, 0xF4, 0x01, 0, 0]);
+        assert_eq!(twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 127, 18, 0, 0]).await, [0x41, 0x0A, 0x10, 0, 18, 0, 0, 0]);
+        assert_eq!(twin.read(1, 0x100A, 0).await, Ok(SOFTWARE_VERSION.to_vec()));
+
+        // Above it the block upload is used
+        assert_eq!(twin.sdo(1, [0xA4, 0x0A, 0x10, 0, 127, 17, 0, 0]).await[0], 0xC6);
+    }
+}
@@ -0,0 +1,195 @@
+//! Twin on a [`MockBus`] with a [`ManualClock`] for the tests of the nodes, with a minimal master to drive them.
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use can_socket::{CanFrame, CanId};
+
+use crate::bus::MockBus;
+use crate::clock::ManualClock;
+use crate::config;
+use crate::twin::DigitalTwin;
+
+/// EDS file of the nodes of the tests
+pub const EDS_FILE: &str = "CPB3-1-2.eds";
+
+/// Longest wait for a response of a node, the nodes respond without the clock advancing
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestTwin {
+    /// The nodes are stopped when the twin is dropped
+    _twin: DigitalTwin,
+    pub bus: MockBus,
+    pub clock: ManualClock,
+    /// Frames sent by the nodes that were received while waiting for an SDO response
+    frames: Mutex<VecDeque<CanFrame>>,
+}
+
+impl TestTwin {
+    /// Start the nodes with the EDS file of the tests, in the NMT state the nodes boot into.
+    pub async fn start(node_ids: &[u8]) -> Self {
+        Self::start_nodes(node_ids.iter().map(|&node_id| config::Node::new(node_id, EDS_FILE)).collect()).await
+    }
+
+    pub async fn start_nodes(nodes: Vec<config::Node>) -> Self {
+
+        let bus = MockBus::new();
+        let clock = ManualClock::new();
+
+        let mut builder = DigitalTwin::builder()
+            .transport("vcan0", bus.clone())
+            .clock(clock.clone());
+        for node in nodes {
+            builder = builder.add_node_config(node);
+        }
+        let mut twin = builder.build().expect("invalid configuration");
+        twin.start().await.expect("failed to start the nodes");
+
+        Self { _twin: twin, bus, clock, frames: Mutex::new(VecDeque::new()) }
+    }
+
+    /// Put a frame with a base identifier on the bus.
+    pub fn push(&self, cob_id: u16, data: &[u8]) {
+        self.bus.push(CanFrame::new(CanId::new_base(cob_id).unwrap(), data, None).unwrap());
+    }
+
+    /// Send an NMT command to a node, or all nodes with node id 0.
+    pub fn nmt(&self, command: u8, node_id: u8) {
+        self.push(0x000, &[command, node_id]);
+    }
+
+    /// Start a node and the CiA 402 state machine up to operation enabled in profile position mode.
+    pub async fn enable(&self, node_id: u8) {
+        self.nmt(0x01, node_id);
+        self.write(node_id, 0x6060, 0, &[1]).await.unwrap();
+        for controlword in [0x06u16, 0x07, 0x0F] {
+            self.write(node_id, 0x6040, 0, &controlword.to_le_bytes()).await.unwrap();
+            self.advance(2).await;
+        }
+    }
+
+    /// Start a profile position move to the target with a rising edge of the new setpoint bit, relative with bit 6.
+    pub async fn start_move(&self, node_id: u8, target: i32, relative: bool) {
+        let controlword: u16 = if relative { 0x4F } else { 0x0F };
+        self.write(node_id, 0x607A, 0, &target.to_le_bytes()).await.unwrap();
+        self.write(node_id, 0x6040, 0, &(controlword | 0x10).to_le_bytes()).await.unwrap();
+        self.advance(1).await;
+        self.write(node_id, 0x6040, 0, &controlword.to_le_bytes()).await.unwrap();
+    }
+
+    /// Advance the clock millisecond by millisecond, so every node runs each update before the next update of another node.
+    pub async fn advance(&self, milliseconds: u64) {
+        for _ in 0..milliseconds {
+            self.clock.advance(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Send an SDO request to a node and wait for its response. The frames the nodes sent before the response are kept.
+    pub async fn sdo(&self, node_id: u8, request: [u8; 8]) -> [u8; 8] {
+        self.sdo_responses(node_id, request, 1).await[0]
+    }
+
+    /// Send an SDO request to a node and wait for a number of responses, like the segments of a block.
+    pub async fn sdo_responses(&self, node_id: u8, request: [u8; 8], count: usize) -> Vec<[u8; 8]> {
+
+        self.push(0x600 + node_id as u16, &request);
+
+        let response_id = 0x580 + node_id as u16;
+        let wait = async {
+            let mut responses = Vec::new();
+            while responses.len() < count {
+                let frame = self.bus.next_sent().await;
+                if frame.id() == CanId::new_base(response_id).unwrap() {
+                    responses.push(frame.data().try_into().expect("SDO response of 8 bytes"));
+                } else {
+                    self.frames.lock().unwrap().push_back(frame);
+                }
+            }
+            responses
+        };
+        tokio::time::timeout(RESPONSE_TIMEOUT, wait).await.expect("no SDO response")
+    }
+
+    /// Write an object with an expedited download, the abort code when the node aborts.
+    pub async fn write(&self, node_id: u8, index: u16, sub_index: u8, data: &[u8]) -> Result<(), u32> {
+
+        assert!((1..=4).contains(&data.len()));
+        let mut request = [0; 8];
+        request[0] = 0x23 | ((4 - data.len() as u8) << 2);
+        request[1..3].copy_from_slice(&index.to_le_bytes());
+        request[3] = sub_index;
+        request[4..4 + data.len()].copy_from_slice(data);
+
+        let response = self.sdo(node_id, request).await;
+        match response[0] {
+            0x60 => Ok(()),
+            0x80 => Err(u32::from_le_bytes(response[4..8].try_into().unwrap())),
+            command => panic!("unexpected SDO response 0x{command:02X}"),
+        }
+    }
+
+    /// Read an object with an expedited or segmented upload, the abort code when the node aborts.
+    pub async fn read(&self, node_id: u8, index: u16, sub_index: u8) -> Result<Vec<u8>, u32> {
+
+        let mut request = [0x40, 0, 0, sub_index, 0, 0, 0, 0];
+        request[1..3].copy_from_slice(&index.to_le_bytes());
+
+        let response = self.sdo(node_id, request).await;
+        let abort_code = |response: [u8; 8]| u32::from_le_bytes(response[4..8].try_into().unwrap());
+
+        match response[0] {
+            0x80 => Err(abort_code(response)),
+            // Expedited with the size indicated
+            command if command & 0x03 == 0x03 => Ok(response[4..8 - ((command >> 2) & 0x03) as usize].to_vec()),
+            0x41 => {
+                let mut data = Vec::new();
+                let mut toggle = 0;
+                loop {
+                    let segment = self.sdo(node_id, [0x60 | toggle, 0, 0, 0, 0, 0, 0, 0]).await;
+                    if segment[0] == 0x80 {
+                        return Err(abort_code(segment));
+                    }
+                    let unused = ((segment[0] >> 1) & 0x07) as usize;
+                    data.extend(&segment[1..8 - unused]);
+                    if segment[0] & 0x01 != 0 {
+                        return Ok(data);
+                    }
+                    toggle ^= 0x10;
+                }
+            }
+            command => panic!("unexpected SDO response 0x{command:02X}"),
+        }
+    }
+
+    /// Read an object of 4 bytes or less as a signed value.
+    pub async fn read_i32(&self, node_id: u8, index: u16, sub_index: u8) -> i32 {
+        let data = self.read(node_id, index, sub_index).await.unwrap();
+        let mut bytes = [0; 4];
+        bytes[..data.len()].copy_from_slice(&data);
+        // Sign extend the shorter objects
+        let shift = 32 - 8 * data.len() as u32;
+        (i32::from_le_bytes(bytes) << shift) >> shift
+    }
+
+    /// The frames the nodes sent since the last call, once the node processed the frames put on the bus before.
+    pub async fn sent(&self, node_id: u8) -> Vec<CanFrame> {
+
+        // The node handles its frames in order, so the response of an SDO upload follows the earlier frames
+        self.read(node_id, 0x1000, 0).await.unwrap();
+
+        let mut frames: Vec<_> = self.frames.lock().unwrap().drain(..).collect();
+        while let Some(frame) = self.bus.try_next_sent() {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    /// The data of the frames with the COB-ID the nodes sent since the last call, see [`TestTwin::sent`].
+    pub async fn sent_with_id(&self, node_id: u8, cob_id: u16) -> Vec<Vec<u8>> {
+        self.sent(node_id).await.into_iter()
+            .filter(|frame| frame.id() == CanId::new_base(cob_id).unwrap())
+            .map(|frame| frame.data().to_vec())
+            .collect()
+    }
+}
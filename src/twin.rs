@@ -0,0 +1,530 @@
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::{self, JoinHandle};
+
+use canopen_tokio::nmt::NmtCommand;
+
+use crate::bus::{self, BusSocket, CanTransport, Trace};
+use crate::bus_load::{BusLoad, MAX_BUS_LOAD};
+use crate::clock::{Clock, RealClock};
+use crate::cia301::{self, Node, NodeRegistry, NodeRequest, NodeStats, SocketMessage, WriteCallbacks};
+use crate::conformance::{Conformance, Violation};
+use crate::coupling::AxisMotions;
+use crate::config::{self, BusConfig, Buses, Config, ConfigError, GeneralConfig, RandomizeConfig};
+use crate::eds::{self, DataValue, EDSData, ObjectType};
+use crate::rng::SplitMix64;
+
+/// Simulated CANopen nodes on one or more CAN buses.
+///
+/// ```no_run
+/// # async fn example() {
+/// let mut twin = fake_motorcontroller::DigitalTwin::builder()
+///     .interface("vcan0")
+///     .add_node(1, "CPB3-1-2.eds")
+///     .build()
+///     .expect("invalid configuration");
+/// twin.start().await.expect("failed to start the nodes");
+/// let eds_data = twin.eds_data(1).await;
+/// twin.stop();
+/// # }
+/// ```
+pub struct DigitalTwin {
+    config: Config,
+    bus_options: BusOptions,
+    buses: BTreeMap<String, Bus>,
+    nodes: BTreeMap<(String, u8), RunningNode>,
+    registry: NodeRegistry,
+    clock: Arc<dyn Clock>,
+    write_callbacks: Arc<WriteCallbacks>,
+    conformance: Arc<Conformance>,
+}
+
+/// Builder of a [`DigitalTwin`], from a configuration or node by node.
+pub struct DigitalTwinBuilder {
+    config: Config,
+    detached: bool,
+    transports: BTreeMap<String, Arc<dyn CanTransport>>,
+    clock: Arc<dyn Clock>,
+    write_callbacks: WriteCallbacks,
+}
+
+impl Default for DigitalTwinBuilder {
+    fn default() -> Self {
+        Self {
+            config: Config {
+                general: GeneralConfig::default(),
+                bus: Buses::Single(BusConfig::new("vcan0")),
+                node: Vec::new(),
+                randomize: None,
+                conformance: Vec::new(),
+            },
+            detached: false,
+            transports: BTreeMap::new(),
+            clock: Arc::new(RealClock),
+            write_callbacks: WriteCallbacks::default(),
+        }
+    }
+}
+
+impl DigitalTwinBuilder {
+    /// Use a complete configuration, replacing the buses and nodes added before.
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Put the nodes on a single bus on the CAN interface, `vcan0` when not set.
+    pub fn interface(mut self, interface: &str) -> Self {
+        self.config.bus = Buses::Single(BusConfig::new(interface));
+        self
+    }
+
+    /// Add a node with the EDS file and the default node configuration.
+    pub fn add_node(self, node_id: u8, eds_file: impl Into<PathBuf>) -> Self {
+        self.add_node_config(config::Node::new(node_id, eds_file))
+    }
+
+    /// Add a node with its complete configuration.
+    pub fn add_node_config(mut self, node: config::Node) -> Self {
+        self.config.node.push(node);
+        self
+    }
+
+    /// Period of the motor controller update, motion advances by this fixed timestep.
+    pub fn cycle_time(mut self, cycle_time: Duration) -> Self {
+        self.config.general.cycle_time_us = cycle_time.as_micros() as u64;
+        self
+    }
+
+    /// Time between the boot-up of consecutive nodes.
+    pub fn boot_stagger(mut self, boot_stagger: Duration) -> Self {
+        self.config.general.boot_stagger_ms = boot_stagger.as_millis() as u64;
+        self
+    }
+
+    /// Append all sent and received frames to the file in the candump log format.
+    pub fn trace_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.general.trace_file = Some(path.into());
+        self
+    }
+
+    /// Start the objects with reproducible random values instead of their EDS defaults.
+    pub fn randomize(mut self, seed: u64, objects: Vec<u16>) -> Self {
+        self.config.randomize = Some(RandomizeConfig { seed, objects });
+        self
+    }
+
+    /// Do not bind the CAN sockets, frames are only given to the nodes by [`DigitalTwin::replay`].
+    pub fn detached(mut self, detached: bool) -> Self {
+        self.detached = detached;
+        self
+    }
+
+    /// Use the transport for the CAN interface instead of binding a socket, for example a [`MockBus`](crate::MockBus) in tests.
+    pub fn transport(mut self, interface: &str, transport: impl CanTransport + 'static) -> Self {
+        self.transports.insert(interface.to_string(), Arc::new(transport));
+        self
+    }
+
+    /// Clock of the update cycles, a [`ManualClock`](crate::ManualClock) steps the motion in tests instead of waiting in real time.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Call the callback with the new value whenever the object of the node is written over SDO or by an RPDO,
+    /// for example to add manufacturer specific behaviour. The callback runs in the task of the node and should not block.
+    pub fn on_write(mut self, node_id: u8, index: u16, sub_index: u8, callback: impl FnMut(&DataValue) + Send + 'static) -> Self {
+        self.write_callbacks.insert(node_id, index, sub_index, Box::new(callback));
+        self
+    }
+
+    /// Check the configuration and create the digital twin, the nodes are started by [`DigitalTwin::start`].
+    pub fn build(self) -> Result<DigitalTwin, ConfigError> {
+
+        self.config.validate()?;
+
+        Ok(DigitalTwin {
+            config: self.config,
+            bus_options: BusOptions { trace: None, detached: self.detached, transports: self.transports },
+            buses: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            registry: NodeRegistry::default(),
+            clock: self.clock,
+            write_callbacks: Arc::new(self.write_callbacks),
+            conformance: Arc::default(),
+        })
+    }
+}
+
+impl DigitalTwin {
+    pub fn builder() -> DigitalTwinBuilder {
+        DigitalTwinBuilder::default()
+    }
+
+    /// Open the buses and start the nodes. When a node fails to start the other nodes are still started and an error is returned.
+    pub async fn start(&mut self) -> Result<(), ()> {
+
+        // Trace of the frames on all buses
+        if self.bus_options.trace.is_none() {
+            if let Some(path) = &self.config.general.trace_file {
+                self.bus_options.trace = Some(Arc::new(Trace::create(path)?));
+            }
+        }
+
+        apply_config(self).await
+    }
+
+    /// Start and stop nodes and buses to match the new configuration. Nodes with a changed configuration are restarted.
+    pub async fn reload(&mut self, config: Config) -> Result<(), ()> {
+
+        config.validate().map_err(|e| log::error!("{e}"))?;
+        self.config = config;
+
+        apply_config(self).await
+    }
+
+    /// Stop the nodes and close the buses, they are opened again by [`DigitalTwin::start`].
+    pub fn stop(&mut self) {
+
+        for ((interface, node_id), running) in std::mem::take(&mut self.nodes) {
+            running.task.abort();
+            log::info!("Node {} on interface {} stopped", node_id, interface);
+        }
+        self.registry.write().unwrap().clear();
+
+        for (interface, bus) in std::mem::take(&mut self.buses) {
+            bus.task.abort();
+            log::info!("CAN bus on interface {} closed", interface);
+        }
+    }
+
+    /// Move the nodes to the NMT stopped state, which they announce on the bus, then stop the nodes and flush the trace.
+    pub async fn shutdown(&mut self) {
+
+        let senders: Vec<_> = self.registry.read().unwrap().values().cloned().collect();
+        for sender in senders {
+            let (reply, response) = oneshot::channel();
+            if sender.send(NodeRequest::Nmt { command: NmtCommand::Stop, reply }).await.is_ok() {
+                response.await.ok();
+            }
+        }
+
+        self.stop();
+
+        if let Some(trace) = &self.bus_options.trace {
+            trace.flush();
+        }
+    }
+
+    /// The node ids of the running nodes by CAN interface.
+    pub fn nodes(&self) -> impl Iterator<Item = (&str, u8)> {
+        self.nodes.keys().map(|(interface, node_id)| (interface.as_str(), *node_id))
+    }
+
+    /// A copy of the EDS data of the first running node with the node id, with the current values of the object dictionary.
+    pub async fn eds_data(&self, node_id: u8) -> Option<EDSData> {
+
+        let sender = self.registry.read().unwrap().iter()
+            .find(|((_, id), _)| *id == node_id)
+            .map(|(_, sender)| sender.clone())?;
+
+        let (reply, response) = oneshot::channel();
+        sender.send(NodeRequest::EdsData { reply }).await.ok()?;
+        response.await.ok()
+    }
+
+    /// The writes of the master that broke a conformance rule of the configuration, of all nodes since the twin was built.
+    pub fn violations(&self) -> Vec<Violation> {
+        self.conformance.violations()
+    }
+
+    /// The statistics of the first running node with the node id.
+    pub async fn stats(&self, node_id: u8) -> Option<NodeStats> {
+
+        let sender = self.registry.read().unwrap().iter()
+            .find(|((_, id), _)| *id == node_id)
+            .map(|(_, sender)| sender.clone())?;
+
+        let (reply, response) = oneshot::channel();
+        sender.send(NodeRequest::Stats { reply }).await.ok()?;
+        response.await.ok()
+    }
+
+    /// Serve the object dictionaries and metrics of the running nodes over HTTP.
+    #[cfg(feature = "http")]
+    pub fn serve_http(&self, listener: tokio::net::TcpListener) -> impl std::future::Future<Output = ()> + Send + 'static {
+        crate::http::serve(listener, Arc::clone(&self.registry))
+    }
+
+    /// Send the frames of a candump log addressed to the nodes to their buses, with the recorded time between the frames.
+    pub async fn replay(&self, path: &Path) -> Result<(), ()> {
+
+        let trace = std::fs::read_to_string(path)
+            .map_err(|e| log::error!("Failed to read {}: {e}", path.display()))?;
+        log::info!("Replaying {}", path.display());
+
+        let start = Instant::now();
+        let mut first_timestamp = None;
+        let mut count = 0;
+
+        for (line_number, line) in trace.lines().enumerate() {
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let Some((timestamp, interface, frame)) = bus::parse_trace_line(line) else {
+                log::warn!("Skipping line {} of {}: {}", line_number + 1, path.display(), line);
+                continue;
+            };
+
+            // Only frames addressed to the nodes: NMT and SYNC, RPDOs and SDO requests
+            let cob_id = frame.id().as_u32();
+            let node_id = (cob_id & 0x7F) as u8;
+            let addressed = match cob_id & !0x7F {
+                0x000 | 0x080 => node_id == 0,
+                0x200 | 0x300 | 0x400 | 0x500 | 0x600 => self.nodes.contains_key(&(interface.clone(), node_id)),
+                _ => false,
+            };
+            let Some(bus) = self.buses.get(&interface).filter(|_| addressed) else {
+                continue;
+            };
+
+            // Keep the recorded time between the frames
+            let offset = timestamp.saturating_sub(*first_timestamp.get_or_insert(timestamp));
+            tokio::time::sleep_until((start + offset).into()).await;
+
+            bus.sender.send(SocketMessage::Frame(frame, self.clock.now())).ok();
+            count += 1;
+        }
+
+        // Let the nodes handle the last frames
+        tokio::time::sleep(10 * Duration::from_micros(self.config.general.cycle_time_us)).await;
+        log::info!("Replayed {} frames from {}", count, path.display());
+
+        Ok(())
+    }
+}
+
+impl Drop for DigitalTwin {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// How the buses are opened.
+struct BusOptions {
+    /// Trace of the frames on all buses.
+    trace: Option<Arc<Trace>>,
+    /// Do not bind the sockets, the received frames are replayed from a trace.
+    detached: bool,
+    /// Transports used instead of sockets by CAN interface.
+    transports: BTreeMap<String, Arc<dyn CanTransport>>,
+}
+
+/// An opened CAN bus and the task forwarding its frames to the nodes.
+struct Bus {
+    socket: Arc<BusSocket>,
+    sender: broadcast::Sender<SocketMessage>,
+    /// Motion of the axes on the bus, shared by its nodes to follow an axis
+    axis_motions: AxisMotions,
+    task: JoinHandle<()>,
+}
+
+impl Bus {
+    /// Bind a socket on the interface and start forwarding its frames, with the faults of the bus configuration.
+    fn open(interface: &str, bus_config: Option<&BusConfig>, options: &BusOptions, clock: &Arc<dyn Clock>) -> Result<Self, ()> {
+
+        let socket = if let Some(transport) = options.transports.get(interface) {
+            BusSocket::new(interface, Arc::clone(transport), options.trace.clone())
+        } else if options.detached {
+            BusSocket::detached(interface, options.trace.clone())
+        } else {
+            BusSocket::bind(interface, options.trace.clone()).map_err(|e| {
+                log::error!("Failed to create CAN socket for interface {}: {e}", interface)
+            })?
+        };
+        let socket = match bus_config {
+            Some(bus_config) if bus_config.has_faults() => {
+                log::info!("CAN bus on interface {} delays up to {} ms, drops with probability {} and reorders: {}",
+                    interface, bus_config.tx_latency_ms, bus_config.drop_probability, bus_config.reorder);
+                socket.with_faults(bus_config)
+            }
+            _ => socket,
+        };
+        let socket = Arc::new(socket);
+        log::info!("CAN bus on interface {} opened", interface);
+
+        let (sender, _) = broadcast::channel(cia301::FRAME_QUEUE_SIZE);
+        let task = task::spawn(cia301::receive_frames(Arc::clone(&socket), sender.clone(), Arc::clone(clock)));
+
+        Ok(Self { socket, sender, axis_motions: AxisMotions::default(), task })
+    }
+}
+
+/// A running node task and the configuration it was started with.
+struct RunningNode {
+    config: config::Node,
+    cycle_time: Duration,
+    randomize: Option<RandomizeConfig>,
+    /// Estimated bus load of the periodic frames of the node at the start
+    bus_load: BusLoad,
+    task: JoinHandle<()>,
+}
+
+/// Start and stop nodes and buses to match the configuration. Nodes with a changed configuration are restarted.
+/// A node that fails to start is logged and skipped, the other nodes are still started.
+async fn apply_config(twin: &mut DigitalTwin) -> Result<(), ()> {
+
+    let DigitalTwin { config, bus_options, buses, nodes, registry, clock, write_callbacks, conformance } = twin;
+    conformance.set_rules(config.conformance.clone());
+
+    let cycle_time = Duration::from_micros(config.general.cycle_time_us);
+    let boot_stagger = Duration::from_millis(config.general.boot_stagger_ms);
+
+    // Nodes by CAN interface and node id, the nodes of an interface share one socket
+    let mut configured = BTreeMap::new();
+    for node in config.node.iter() {
+        let interface = config.node_interface(node).map_err(|e| log::error!("{e}"))?;
+        configured.insert((interface.to_string(), node.node_id), node);
+    }
+
+    // Stop removed and changed nodes
+    nodes.retain(|(interface, node_id), running| {
+        let keep = configured.get(&(interface.clone(), *node_id))
+            .is_some_and(|&node| *node == running.config && cycle_time == running.cycle_time && config.randomize == running.randomize);
+        if !keep {
+            running.task.abort();
+            registry.write().unwrap().remove(&(interface.clone(), *node_id));
+            log::info!("Node {} on interface {} stopped", node_id, interface);
+        }
+        keep
+    });
+
+    // Start added and changed nodes, each started node boots up the stagger after the previous one
+    let mut failed = false;
+    let mut started = 0;
+    for ((interface, node_id), node) in configured {
+
+        if nodes.contains_key(&(interface.clone(), node_id)) {
+            continue;
+        }
+
+        let bus = match buses.entry(interface.clone()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => match Bus::open(&interface, config.bus_config(&interface), bus_options, clock) {
+                Ok(bus) => entry.insert(bus),
+                Err(()) => {
+                    log::error!("Node {} on interface {} not started", node_id, interface);
+                    failed = true;
+                    continue;
+                }
+            },
+        };
+
+        // Parse eds data
+        let mut node_data = match eds::parse_eds(&node.node_id, &node.eds_file) {
+            Ok(node_data) => node_data,
+            Err(e) => {
+                log::error!("Failed to parse {} for node {}, node not started: {e}", node.eds_file.display(), node.node_id);
+                failed = true;
+                continue;
+            }
+        };
+
+        if let Some(randomize) = &config.randomize {
+            randomize_objects(&mut node_data, node.node_id, randomize);
+        }
+
+        // Initialize controller
+        let mut running = Node::initialize(Arc::clone(&bus.socket), node, node_data, cycle_time, Arc::clone(clock), Arc::clone(write_callbacks)).await;
+        let bus_load = running.bus_load();
+        running.startup_delay = boot_stagger * started;
+        running.conformance = Arc::clone(conformance);
+        running.axis_motions = Arc::clone(&bus.axis_motions);
+        started += 1;
+
+        // Start node
+        let receiver = bus.sender.subscribe();
+        let (request_sender, requests) = mpsc::channel(cia301::REQUEST_QUEUE_SIZE);
+        let task = task::spawn(async move {
+            running.start_socket(receiver, requests).await;
+        });
+        registry.write().unwrap().insert((interface.clone(), node_id), request_sender);
+        log::info!("Node {} on interface {} started", node_id, interface);
+
+        nodes.insert((interface, node_id), RunningNode { config: node.clone(), cycle_time, randomize: config.randomize.clone(), bus_load, task });
+    }
+
+    // Close buses without nodes
+    buses.retain(|interface, bus| {
+        let used = nodes.keys().any(|(node_interface, _)| node_interface == interface);
+        if !used {
+            bus.task.abort();
+            log::info!("CAN bus on interface {} closed", interface);
+        }
+        used
+    });
+
+    warn_bus_load(config, nodes);
+
+    if failed {
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Warn for the buses where the periodic frames of the nodes would use more than [`MAX_BUS_LOAD`] of the baud rate.
+fn warn_bus_load(config: &Config, nodes: &BTreeMap<(String, u8), RunningNode>) {
+
+    let mut loads: BTreeMap<&str, BusLoad> = BTreeMap::new();
+    for ((interface, _), running) in nodes {
+        *loads.entry(interface).or_default() += running.bus_load;
+    }
+
+    for (interface, load) in loads {
+
+        // A node can be on an interface without a bus configuration, it gets the default bus
+        let baud_rate = config.bus_config(interface).map_or(BusConfig::new(interface).baud_rate, |bus| bus.baud_rate);
+        let bus_load = load.bits_per_second() / baud_rate as f64;
+
+        if bus_load > MAX_BUS_LOAD {
+            log::warn!("Periodic frames on interface {} use an estimated {:.0}% of {} bit/s, frames may be lost on a real bus", interface, bus_load * 100., baud_rate);
+        } else {
+            log::debug!("Periodic frames on interface {} use an estimated {:.0}% of {} bit/s", interface, bus_load * 100., baud_rate);
+        }
+    }
+
+}
+
+/// Set the numeric variables of the objects to random values, the seed is combined with the node id so every node
+/// gets its own values. Sub index 0 of an array or record keeps the number of entries.
+fn randomize_objects(eds_data: &mut EDSData, node_id: u8, randomize: &RandomizeConfig) {
+
+    let mut rng = SplitMix64(randomize.seed ^ node_id as u64);
+
+    for &index in &randomize.objects {
+
+        let object_type = eds_data.object_type(index);
+        let Some(vars) = eds_data.od.get_mut(&index) else {
+            log::warn!("Object 0x{:04X} to randomize not in the object dictionary of node {}", index, node_id);
+            continue;
+        };
+
+        for (&sub_index, var) in vars.iter_mut() {
+            if sub_index == 0 && object_type != ObjectType::Var {
+                continue;
+            }
+            if let Some(value) = var.value.random(&mut rng) {
+                log::debug!("Node {} starts with 0x{:04X} sub {} = {:?}", node_id, index, sub_index, value);
+                var.value = value;
+            }
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::time::SystemTime;
+
+use crate::eds::{DataType, EDSData, ObjectType, Var};
+
+impl EDSData {
+
+    /// Write the object dictionary to an XDD file (CiA 311, ISO 15745), with the current values as the default values.
+    /// The device profile holds the device identity, the communication network profile the objects.
+    /// Variables of an unknown data type are left out.
+    pub fn export_xdd(&self, path: &Path) -> std::io::Result<()> {
+
+        let file_name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let file_attributes = format!(
+            r#"fileName="{}" fileCreator="{}" fileCreationDate="{}" fileVersion="{}""#,
+            escape(&file_name), escape(&self.file_info.created_by), creation_date(), self.file_info.file_version,
+        );
+
+        let mut xdd = String::new();
+        xdd.push_str("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+        xdd.push_str("<ISO15745ProfileContainer xmlns=\"http://www.canopen.org/xml/1.0\" xmlns:xsi=\"http://www.w3.org/2001/XMLSchema-instance\">\n");
+
+        // Device profile
+        xdd.push_str("  <ISO15745Profile>\n");
+        push_profile_header(&mut xdd, &self.device_info.product_name, "Device");
+        writeln!(xdd, "    <ProfileBody xsi:type=\"ProfileBody_Device_CANopen\" {}>", file_attributes).unwrap();
+        xdd.push_str("      <DeviceIdentity>\n");
+        writeln!(xdd, "        <vendorName>{}</vendorName>", escape(&self.device_info.vendor_name)).unwrap();
+        writeln!(xdd, "        <vendorID>0x{:08X}</vendorID>", self.device_info.vendor_number).unwrap();
+        writeln!(xdd, "        <productName>{}</productName>", escape(&self.device_info.product_name)).unwrap();
+        writeln!(xdd, "        <productID>0x{:08X}</productID>", self.device_info.product_number).unwrap();
+        xdd.push_str("      </DeviceIdentity>\n");
+        xdd.push_str("      <DeviceFunction>\n");
+        xdd.push_str("        <capabilities>\n");
+        xdd.push_str("          <characteristicsList>\n");
+        xdd.push_str("            <characteristic>\n");
+        xdd.push_str("              <characteristicName><label lang=\"en\">Description</label></characteristicName>\n");
+        writeln!(xdd, "              <characteristicContent><label lang=\"en\">{}</label></characteristicContent>", escape(&self.file_info.description)).unwrap();
+        xdd.push_str("            </characteristic>\n");
+        xdd.push_str("          </characteristicsList>\n");
+        xdd.push_str("        </capabilities>\n");
+        xdd.push_str("      </DeviceFunction>\n");
+        xdd.push_str("    </ProfileBody>\n");
+        xdd.push_str("  </ISO15745Profile>\n");
+
+        // Communication network profile
+        xdd.push_str("  <ISO15745Profile>\n");
+        push_profile_header(&mut xdd, &self.device_info.product_name, "CommunicationNetwork");
+        writeln!(xdd, "    <ProfileBody xsi:type=\"ProfileBody_CommunicationNetwork_CANopen\" {}>", file_attributes).unwrap();
+        xdd.push_str("      <ApplicationLayers>\n");
+        xdd.push_str("        <CANopenObjectList>\n");
+        for (&index, vars) in self.od.iter() {
+            self.push_object(&mut xdd, index, vars);
+        }
+        xdd.push_str("        </CANopenObjectList>\n");
+        xdd.push_str("      </ApplicationLayers>\n");
+        // The baud rate is a setting of the bus, not of the object dictionary
+        xdd.push_str("      <TransportLayers>\n");
+        xdd.push_str("        <PhysicalLayer>\n");
+        xdd.push_str("          <baudRate>\n");
+        xdd.push_str("            <supportedBaudRate value=\"1000 Kbps\"/>\n");
+        xdd.push_str("          </baudRate>\n");
+        xdd.push_str("        </PhysicalLayer>\n");
+        xdd.push_str("      </TransportLayers>\n");
+        xdd.push_str("    </ProfileBody>\n");
+        xdd.push_str("  </ISO15745Profile>\n");
+
+        xdd.push_str("</ISO15745ProfileContainer>\n");
+
+        std::fs::write(path, xdd)
+    }
+
+    /// A `CANopenObject` element, with a `CANopenSubObject` per sub index for an array or a record.
+    fn push_object(&self, xdd: &mut String, index: u16, vars: &std::collections::BTreeMap<u8, Var>) {
+
+        // Objects added by the twin with sub indices have no object type in the EDS, they are records
+        let object_type = match self.object_type(index) {
+            ObjectType::Var if vars.keys().all(|&sub_index| sub_index == 0) => ObjectType::Var,
+            ObjectType::Var => ObjectType::Record,
+            object_type => object_type,
+        };
+
+        if object_type == ObjectType::Var {
+            if let Some(attributes) = vars.get(&0).and_then(var_attributes) {
+                writeln!(xdd, "          <CANopenObject index=\"{:04X}\" objectType=\"7\" {}/>", index, attributes).unwrap();
+            }
+            return;
+        }
+
+        let sub_objects: Vec<_> = vars.iter()
+            .filter_map(|(&sub_index, var)| Some((sub_index, var_attributes(var)?)))
+            .collect();
+        let name = self.object_names.get(&index).map_or(String::new(), |name| escape(name));
+        let object_type_code = if object_type == ObjectType::Array { 8 } else { 9 };
+
+        writeln!(xdd, "          <CANopenObject index=\"{:04X}\" name=\"{}\" objectType=\"{}\" subNumber=\"{}\">",
+            index, name, object_type_code, sub_objects.len()).unwrap();
+        for (sub_index, attributes) in sub_objects {
+            writeln!(xdd, "            <CANopenSubObject subIndex=\"{:02X}\" objectType=\"7\" {}/>", sub_index, attributes).unwrap();
+        }
+        xdd.push_str("          </CANopenObject>\n");
+    }
+
+}
+
+/// `ProfileHeader` of a profile of the container, the profile class is `Device` or `CommunicationNetwork`.
+fn push_profile_header(xdd: &mut String, product_name: &str, profile_class: &str) {
+    xdd.push_str("    <ProfileHeader>\n");
+    writeln!(xdd, "      <ProfileIdentification>{}</ProfileIdentification>", escape(product_name)).unwrap();
+    xdd.push_str("      <ProfileRevision>1</ProfileRevision>\n");
+    writeln!(xdd, "      <ProfileName>{}</ProfileName>", escape(product_name)).unwrap();
+    xdd.push_str("      <ProfileSource/>\n");
+    writeln!(xdd, "      <ProfileClassID>{}</ProfileClassID>", profile_class).unwrap();
+    xdd.push_str("      <ISO15745Reference>\n");
+    xdd.push_str("        <ISO15745Part>1</ISO15745Part>\n");
+    xdd.push_str("        <ISO15745Edition>1</ISO15745Edition>\n");
+    xdd.push_str("        <ProfileTechnology>CANopen</ProfileTechnology>\n");
+    xdd.push_str("      </ISO15745Reference>\n");
+    xdd.push_str("    </ProfileHeader>\n");
+}
+
+/// Name, data type, access type, default value and PDO mapping attributes of a variable, None for an unknown data type.
+fn var_attributes(var: &Var) -> Option<String> {
+
+    let data_type = data_type_code(&var.value.data_type())?;
+    let default_value = var.value.to_eds_string()?;
+
+    // The read and write access of the EDS ("rww", "rwr") is not distinguished in an XDD
+    let access_type = match var.access_type.as_str() {
+        access_type @ ("ro" | "wo" | "const") => access_type,
+        _ => "rw",
+    };
+    let pdo_mapping = if var.pdo_mapping { "optional" } else { "no" };
+
+    Some(format!(r#"name="{}" dataType="{:04X}" accessType="{}" defaultValue="{}" PDOmapping="{}""#,
+        escape(&var.parameter_name), data_type, access_type, escape(&default_value), pdo_mapping))
+}
+
+/// Index of the data type in the object dictionary, the inverse of the `DataType` of an EDS.
+fn data_type_code(data_type: &DataType) -> Option<u16> {
+    match data_type {
+        DataType::Unknown => None,
+        DataType::Boolean => Some(0x0001),
+        DataType::Integer8 => Some(0x0002),
+        DataType::Integer16 => Some(0x0003),
+        DataType::Integer32 => Some(0x0004),
+        DataType::Unsigned8 => Some(0x0005),
+        DataType::Unsigned16 => Some(0x0006),
+        DataType::Unsigned32 => Some(0x0007),
+        DataType::Real32 => Some(0x0008),
+        DataType::VisibleString => Some(0x0009),
+        DataType::OctetString => Some(0x000A),
+        DataType::Domain => Some(0x000F),
+        DataType::Integer24 => Some(0x0010),
+        DataType::Unsigned24 => Some(0x0016),
+    }
+}
+
+/// Escape the characters with a meaning in XML text and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Today's date in UTC as `YYYY-MM-DD`.
+fn creation_date() -> String {
+
+    let seconds = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |time| time.as_secs());
+
+    // Civil date from the days since 1970-01-01, in eras of 400 years starting on March 1st
+    let days = (seconds / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
@@ -0,0 +1,70 @@
+//! SDO transfers with a node of the twin on a [`MockBus`], through the public API only.
+
+use std::time::Duration;
+
+use can_socket::{CanFrame, CanId};
+use fake_motorcontroller::config::Node;
+use fake_motorcontroller::{DigitalTwin, ManualClock, MockBus};
+
+const NODE_ID: u16 = 1;
+
+/// Send an SDO request to the node and wait for its response.
+async fn sdo(bus: &MockBus, request: [u8; 8]) -> [u8; 8] {
+
+    bus.push(CanFrame::new(CanId::new_base(0x600 + NODE_ID).unwrap(), &request, None).unwrap());
+
+    let response = async {
+        loop {
+            let frame = bus.next_sent().await;
+            if frame.id() == CanId::new_base(0x580 + NODE_ID).unwrap() {
+                return frame.data().try_into().unwrap();
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), response).await.expect("no SDO response")
+}
+
+async fn start_twin(bus: &MockBus) -> DigitalTwin {
+    let mut twin = DigitalTwin::builder()
+        .transport("vcan0", bus.clone())
+        .clock(ManualClock::new())
+        .add_node_config(Node::new(NODE_ID as u8, "CPB3-1-2.eds"))
+        .build()
+        .unwrap();
+    twin.start().await.unwrap();
+    twin
+}
+
+#[tokio::test]
+async fn expedited_download_is_read_back() {
+    let bus = MockBus::new();
+    let _twin = start_twin(&bus).await;
+
+    // Profile velocity (0x6081) of 1234
+    let response = sdo(&bus, [0x23, 0x81, 0x60, 0x00, 0xD2, 0x04, 0x00, 0x00]).await;
+    assert_eq!(response, [0x60, 0x81, 0x60, 0x00, 0, 0, 0, 0]);
+
+    let response = sdo(&bus, [0x40, 0x81, 0x60, 0x00, 0, 0, 0, 0]).await;
+    assert_eq!(response, [0x43, 0x81, 0x60, 0x00, 0xD2, 0x04, 0x00, 0x00]);
+}
+
+#[tokio::test]
+async fn segmented_upload_of_the_device_name() {
+    let bus = MockBus::new();
+    let _twin = start_twin(&bus).await;
+
+    // Manufacturer device name (0x1008) of 8 bytes
+    let response = sdo(&bus, [0x40, 0x08, 0x10, 0x00, 0, 0, 0, 0]).await;
+    assert_eq!(response, [0x41, 0x08, 0x10, 0x00, 8, 0, 0, 0]);
+
+    let mut name: Vec<u8> = Vec::new();
+    let first = sdo(&bus, [0x60, 0, 0, 0, 0, 0, 0, 0]).await;
+    assert_eq!(first[0], 0x00);
+    name.extend(&first[1..8]);
+    let last = sdo(&bus, [0x70, 0, 0, 0, 0, 0, 0, 0]).await;
+    // Toggled, 6 bytes without data and the last segment
+    assert_eq!(last[0], 0x1D);
+    name.extend(&last[1..2]);
+
+    assert_eq!(name, b"CPB3-1-2");
+}